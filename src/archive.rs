@@ -0,0 +1,396 @@
+use crate::download::METADATA_FILE;
+use crate::models::{TeachingTool, TocEntry};
+use crate::prepare::{civil_date, ImageArchive};
+use crate::EdukaError;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use zip::write::SimpleFileOptions;
+
+/// Metadata file comic readers look for at the root of a CBZ.
+const COMIC_INFO: &str = "ComicInfo.xml";
+
+/// Escape text for an XML element or attribute value.
+pub(crate) fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Name of a page in an archive, zero padded so readers sorting by name keep
+/// the pages in order.
+fn page_entry_name(n: usize, page_count: usize, extension: &str) -> String {
+    let width = page_count.to_string().len().max(4);
+    format!("{:0width$}.{}", n, extension, width = width)
+}
+
+/// `ComicInfo.xml` of a teaching tool, with a bookmark on the first page of
+/// every top-level chapter of `toc`.
+fn comic_info(teaching_tool: &TeachingTool, page_count: usize, toc: &[TocEntry]) -> String {
+    let book = &teaching_tool.book;
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<ComicInfo>\n");
+    xml += &format!("  <Title>{}</Title>\n", xml_escape(&book.title));
+    xml += &format!(
+        "  <Series>{}</Series>\n",
+        xml_escape(&book.collection_title)
+    );
+    if !teaching_tool.authors.is_empty() {
+        xml += &format!(
+            "  <Writer>{}</Writer>\n",
+            xml_escape(&teaching_tool.authors)
+        );
+    }
+    if !teaching_tool.publishing_house.is_empty() {
+        xml += &format!(
+            "  <Publisher>{}</Publisher>\n",
+            xml_escape(&teaching_tool.publishing_house)
+        );
+    }
+    xml += &format!("  <PageCount>{}</PageCount>\n", page_count);
+    xml += "  <Pages>\n";
+    let mut marked = vec![];
+    for entry in toc {
+        // pages are 1-based in the toc and 0-based here
+        let Some(image) = (entry.page as usize).checked_sub(1) else {
+            continue;
+        };
+        if image >= page_count || marked.contains(&image) {
+            continue;
+        }
+        marked.push(image);
+        xml += &format!(
+            "    <Page Image=\"{}\" Bookmark=\"{}\"/>\n",
+            image,
+            xml_escape(&entry.title)
+        );
+    }
+    xml += "  </Pages>\n</ComicInfo>\n";
+    xml
+}
+
+/// Package `pages` into a comic book archive at `path`. The images are stored
+/// as they are, they don't get any smaller by compressing them again.
+pub(crate) fn write_cbz(
+    path: &Path,
+    teaching_tool: &TeachingTool,
+    pages: &[PathBuf],
+    toc: &[TocEntry],
+) -> Result<(), EdukaError> {
+    let options = SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+    let mut archive = zip::ZipWriter::new(io::BufWriter::new(fs::File::create(path)?));
+    for (n, page) in pages.iter().enumerate() {
+        let extension = page.extension().and_then(|e| e.to_str()).unwrap_or("png");
+        archive.start_file(page_entry_name(n, pages.len(), extension), options)?;
+        io::copy(&mut fs::File::open(page)?, &mut archive)?;
+    }
+    archive.start_file(COMIC_INFO, options)?;
+    io::Write::write_all(
+        &mut archive,
+        comic_info(teaching_tool, pages.len(), toc).as_bytes(),
+    )?;
+    archive.finish()?;
+    Ok(())
+}
+
+/// Number of pages in an archive written by `write_cbz`.
+pub(crate) fn cbz_page_count(path: &Path) -> Result<usize, EdukaError> {
+    let archive = zip::ZipArchive::new(fs::File::open(path)?)?;
+    Ok(archive
+        .file_names()
+        .filter(|name| *name != COMIC_INFO)
+        .count())
+}
+
+/// Language of the EPUB, which readers pick fonts and hyphenation by. eduka's
+/// books are Lithuanian schoolbooks.
+const EPUB_LANGUAGE: &str = "lt";
+
+const CONTAINER_XML: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+  <rootfiles>
+    <rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/>
+  </rootfiles>
+</container>
+"#;
+
+/// A page image as it goes into an EPUB.
+struct EpubPage {
+    name: String,
+    media_type: &'static str,
+    width: u32,
+    height: u32,
+}
+
+/// `YYYY-MM-DDThh:mm:ssZ` of now, for the required modification date.
+fn epub_modified() -> String {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (year, month, day) = civil_date(now);
+    let seconds = now % 86400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+fn epub_page_href(n: usize, page_count: usize) -> String {
+    format!("pages/{}", page_entry_name(n, page_count, "xhtml"))
+}
+
+fn content_opf(teaching_tool: &TeachingTool, pages: &[EpubPage]) -> String {
+    let book = &teaching_tool.book;
+    let mut opf = String::from(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+         <package xmlns=\"http://www.idpf.org/2007/opf\" version=\"3.0\" \
+         unique-identifier=\"book-id\" prefix=\"rendition: http://www.idpf.org/vocab/rendition/#\">\n\
+         <metadata xmlns:dc=\"http://purl.org/dc/elements/1.1/\">\n",
+    );
+    opf += &format!(
+        "<dc:identifier id=\"book-id\">urn:eduka:{}</dc:identifier>\n",
+        book.id
+    );
+    opf += &format!("<dc:title>{}</dc:title>\n", xml_escape(&book.title));
+    opf += &format!("<dc:language>{}</dc:language>\n", EPUB_LANGUAGE);
+    if !teaching_tool.authors.is_empty() {
+        opf += &format!(
+            "<dc:creator>{}</dc:creator>\n",
+            xml_escape(&teaching_tool.authors)
+        );
+    }
+    if !teaching_tool.publishing_house.is_empty() {
+        opf += &format!(
+            "<dc:publisher>{}</dc:publisher>\n",
+            xml_escape(&teaching_tool.publishing_house)
+        );
+    }
+    opf += &format!(
+        "<meta property=\"dcterms:modified\">{}</meta>\n",
+        epub_modified()
+    );
+    opf += "<meta property=\"rendition:layout\">pre-paginated</meta>\n\
+            <meta property=\"rendition:spread\">none</meta>\n\
+            </metadata>\n<manifest>\n\
+            <item id=\"nav\" href=\"nav.xhtml\" media-type=\"application/xhtml+xml\" properties=\"nav\"/>\n";
+    for (n, page) in pages.iter().enumerate() {
+        opf += &format!(
+            "<item id=\"page-{}\" href=\"{}\" media-type=\"application/xhtml+xml\"/>\n",
+            n,
+            epub_page_href(n, pages.len())
+        );
+        opf += &format!(
+            "<item id=\"image-{}\" href=\"images/{}\" media-type=\"{}\"{}/>\n",
+            n,
+            &page.name,
+            page.media_type,
+            if n == 0 {
+                " properties=\"cover-image\""
+            } else {
+                ""
+            }
+        );
+    }
+    opf += "</manifest>\n<spine>\n";
+    for n in 0..pages.len() {
+        opf += &format!("<itemref idref=\"page-{}\"/>\n", n);
+    }
+    opf += "</spine>\n</package>\n";
+    opf
+}
+
+/// A page showing just its image, sized to it.
+fn page_xhtml(n: usize, page: &EpubPage) -> String {
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+         <!DOCTYPE html>\n\
+         <html xmlns=\"http://www.w3.org/1999/xhtml\">\n\
+         <head><title>{}</title>\
+         <meta name=\"viewport\" content=\"width={}, height={}\"/>\
+         <style>body {{ margin: 0; }} img {{ width: 100%; height: 100%; }}</style></head>\n\
+         <body><img src=\"../images/{}\" alt=\"\"/></body>\n</html>\n",
+        n + 1,
+        page.width,
+        page.height,
+        &page.name
+    )
+}
+
+/// Nested `<ol>` of the chapters of `toc`, pointing at their pages.
+fn nav_list(toc: &[TocEntry], page_count: usize) -> String {
+    let mut list = String::from("<ol>\n");
+    for entry in toc {
+        // pages are 1-based in the toc, ones past the book go to its last page
+        let n = (entry.page as usize).clamp(1, page_count) - 1;
+        list += &format!(
+            "<li><a href=\"{}\">{}</a>",
+            epub_page_href(n, page_count),
+            xml_escape(&entry.title)
+        );
+        if !entry.children.is_empty() {
+            list += &nav_list(&entry.children, page_count);
+        }
+        list += "</li>\n";
+    }
+    list += "</ol>\n";
+    list
+}
+
+/// Navigation document with the chapters, or only the first page for a book
+/// without any, as it needs at least one entry.
+fn nav_xhtml(teaching_tool: &TeachingTool, page_count: usize, toc: &[TocEntry]) -> String {
+    let title = xml_escape(&teaching_tool.book.title);
+    let list = match toc.is_empty() {
+        true => format!(
+            "<ol>\n<li><a href=\"{}\">{}</a></li>\n</ol>\n",
+            epub_page_href(0, page_count),
+            &title
+        ),
+        false => nav_list(toc, page_count),
+    };
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+         <!DOCTYPE html>\n\
+         <html xmlns=\"http://www.w3.org/1999/xhtml\" xmlns:epub=\"http://www.idpf.org/2007/ops\">\n\
+         <head><title>{}</title></head>\n\
+         <body>\n<nav epub:type=\"toc\" id=\"toc\">\n<h1>{}</h1>\n{}</nav>\n</body>\n</html>\n",
+        &title, &title, list
+    )
+}
+
+/// Package `pages` into a fixed-layout EPUB at `path`, a page of the book on
+/// every page of the EPUB, with the chapters of `toc` as its navigation.
+pub(crate) fn write_epub(
+    path: &Path,
+    teaching_tool: &TeachingTool,
+    pages: &[PathBuf],
+    toc: &[TocEntry],
+) -> Result<(), EdukaError> {
+    if pages.is_empty() {
+        return Err(EdukaError::NoPages);
+    }
+    let stored = SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+    let deflated =
+        SimpleFileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+    let mut archive = zip::ZipWriter::new(io::BufWriter::new(fs::File::create(path)?));
+    // has to come first and uncompressed, for readers that sniff the file
+    archive.start_file("mimetype", stored)?;
+    io::Write::write_all(&mut archive, b"application/epub+zip")?;
+    archive.start_file("META-INF/container.xml", deflated)?;
+    io::Write::write_all(&mut archive, CONTAINER_XML.as_bytes())?;
+    let mut epub_pages = vec![];
+    for (n, page) in pages.iter().enumerate() {
+        // pages are named .png whatever eduka served them as
+        let reader = image::ImageReader::open(page)?.with_guessed_format()?;
+        let format = reader.format().unwrap_or(image::ImageFormat::Png);
+        let (width, height) = reader.into_dimensions()?;
+        let epub_page = EpubPage {
+            name: page_entry_name(n, pages.len(), format.extensions_str()[0]),
+            media_type: format.to_mime_type(),
+            width,
+            height,
+        };
+        archive.start_file(format!("OEBPS/images/{}", &epub_page.name), stored)?;
+        io::copy(&mut fs::File::open(page)?, &mut archive)?;
+        archive.start_file(
+            format!("OEBPS/{}", epub_page_href(n, pages.len())),
+            deflated,
+        )?;
+        io::Write::write_all(&mut archive, page_xhtml(n, &epub_page).as_bytes())?;
+        epub_pages.push(epub_page);
+    }
+    archive.start_file("OEBPS/nav.xhtml", deflated)?;
+    io::Write::write_all(
+        &mut archive,
+        nav_xhtml(teaching_tool, pages.len(), toc).as_bytes(),
+    )?;
+    archive.start_file("OEBPS/content.opf", deflated)?;
+    io::Write::write_all(
+        &mut archive,
+        content_opf(teaching_tool, &epub_pages).as_bytes(),
+    )?;
+    archive.finish()?;
+    Ok(())
+}
+
+/// Number of pages in an EPUB written by `write_epub`.
+pub(crate) fn epub_page_count(path: &Path) -> Result<usize, EdukaError> {
+    let archive = zip::ZipArchive::new(fs::File::open(path)?)?;
+    Ok(archive
+        .file_names()
+        .filter(|name| name.starts_with("OEBPS/images/"))
+        .count())
+}
+
+/// Archive `pages` in order at `path`, with the metadata of the teaching tool
+/// next to them, for processing the pages with other tools.
+pub(crate) fn write_images(
+    path: &Path,
+    teaching_tool: &TeachingTool,
+    pages: &[PathBuf],
+    archive: ImageArchive,
+) -> Result<(), EdukaError> {
+    let metadata = serde_json::to_vec_pretty(teaching_tool)?;
+    let file = io::BufWriter::new(fs::File::create(path)?);
+    let entry_name = |n: usize, page: &Path| {
+        let extension = page.extension().and_then(|e| e.to_str()).unwrap_or("png");
+        page_entry_name(n, pages.len(), extension)
+    };
+    match archive {
+        ImageArchive::Zip => {
+            // page images hardly get any smaller, the metadata does
+            let stored =
+                SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+            let deflated =
+                SimpleFileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+            let mut zip = zip::ZipWriter::new(file);
+            for (n, page) in pages.iter().enumerate() {
+                zip.start_file(entry_name(n, page), stored)?;
+                io::copy(&mut fs::File::open(page)?, &mut zip)?;
+            }
+            zip.start_file(METADATA_FILE, deflated)?;
+            io::Write::write_all(&mut zip, &metadata)?;
+            zip.finish()?;
+        }
+        ImageArchive::TarZst => {
+            let mut tar = tar::Builder::new(zstd::Encoder::new(file, 0)?.auto_finish());
+            for (n, page) in pages.iter().enumerate() {
+                tar.append_path_with_name(page, entry_name(n, page))?;
+            }
+            let mut header = tar::Header::new_gnu();
+            header.set_size(metadata.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            tar.append_data(&mut header, METADATA_FILE, metadata.as_slice())?;
+            tar.into_inner()?;
+        }
+    }
+    Ok(())
+}
+
+/// Number of pages in an archive written by `write_images`.
+pub(crate) fn images_page_count(path: &Path, archive: ImageArchive) -> Result<usize, EdukaError> {
+    let file = fs::File::open(path)?;
+    match archive {
+        ImageArchive::Zip => Ok(zip::ZipArchive::new(file)?
+            .file_names()
+            .filter(|name| *name != METADATA_FILE)
+            .count()),
+        ImageArchive::TarZst => {
+            let mut tar = tar::Archive::new(zstd::Decoder::new(file)?);
+            let mut count = 0;
+            for entry in tar.entries()? {
+                if entry?.path()?.as_ref() != Path::new(METADATA_FILE) {
+                    count += 1;
+                }
+            }
+            Ok(count)
+        }
+    }
+}
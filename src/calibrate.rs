@@ -0,0 +1,93 @@
+//! Interactive correction of the page shift of a downloaded book, for books
+//! whose bookmarks eduka places on the wrong pages.
+
+use eduka::{read_metadata, save_page_shift, Bookmark, EdukaError};
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// Show the page image in the system's image viewer.
+fn open_page(path: &Path) -> io::Result<()> {
+    #[cfg(target_os = "windows")]
+    let mut command = {
+        let mut command = process::Command::new("cmd");
+        command.args(["/C", "start", ""]);
+        command
+    };
+    #[cfg(target_os = "macos")]
+    let mut command = process::Command::new("open");
+    #[cfg(not(any(target_os = "windows", target_os = "macos")))]
+    let mut command = process::Command::new("xdg-open");
+    command
+        .arg(path)
+        .stdout(process::Stdio::null())
+        .stderr(process::Stdio::null())
+        .spawn()?;
+    Ok(())
+}
+
+/// Page image of a 1-based PDF page.
+fn page_path(book_dir: &Path, page: u32) -> PathBuf {
+    book_dir.join(format!("{}.png", page.saturating_sub(1)))
+}
+
+/// Walk the top-level chapters of the book in `book_dir`, showing the page each
+/// lands on, and let the user nudge the page shift until they line up. The
+/// shift is saved to the book's `metadata.json` when accepted.
+pub fn calibrate(book_dir: &Path, open: bool) -> Result<(), EdukaError> {
+    if !io::stdin().is_terminal() {
+        return Err(EdukaError::NonInteractive);
+    }
+    let book = read_metadata(book_dir)?.book;
+    let chapters: Vec<&Bookmark> = book.bookmarks.iter().collect();
+    if chapters.is_empty() {
+        println!("{} has no chapters to calibrate against", &book.title);
+        return Ok(());
+    }
+    let mut page_shift = book.page_shift;
+    let mut chapter = 0;
+    loop {
+        let page = chapters[chapter].pdf_page(page_shift);
+        let path = page_path(book_dir, page);
+        println!(
+            "[{}/{}] {:?} with shift {} is on page {}: {}",
+            chapter + 1,
+            chapters.len(),
+            &chapters[chapter].title,
+            page_shift,
+            page as i32,
+            if path.is_file() {
+                path.to_string_lossy().into_owned()
+            } else {
+                String::from("no such page")
+            }
+        );
+        if open && path.is_file() {
+            open_page(&path)?;
+        }
+        print!("+N/-N to move the chapter N pages on/back, n/p next/previous chapter, s save, q quit: ");
+        io::stdout().flush()?;
+        let mut input = String::new();
+        if io::stdin().read_line(&mut input)? == 0 {
+            return Ok(());
+        }
+        match input.trim() {
+            "n" => chapter = (chapter + 1).min(chapters.len() - 1),
+            "p" => chapter = chapter.saturating_sub(1),
+            "s" => {
+                save_page_shift(book_dir, page_shift)?;
+                println!(
+                    "saved page shift {}, run `prepare --bookmarks-only` to apply it",
+                    page_shift
+                );
+                return Ok(());
+            }
+            "q" => return Ok(()),
+            nudge => match nudge.parse::<i64>() {
+                // moving the chapter on is a smaller shift
+                Ok(pages) if nudge.starts_with(['+', '-']) => page_shift -= pages,
+                _ => println!("{:?} isn't a command", nudge),
+            },
+        }
+    }
+}
@@ -0,0 +1,231 @@
+use crate::download::progress_bar;
+use crate::{EdukaClient, EdukaError, TeachingTool};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::task::JoinSet;
+
+/// A teaching tool as recorded by a catalog scan, to pick books from offline.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CatalogEntry {
+    pub id: u64,
+    pub title: String,
+    /// Only known for teaching tools of a package, empty otherwise
+    pub publisher: String,
+    /// Pages of all parts together
+    pub pages: usize,
+    pub native_downloadable: bool,
+    /// Why the metadata of the id couldn't be fetched, in which case nothing
+    /// else is known of it, not even whether it has a teaching tool
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+impl CatalogEntry {
+    pub fn new(teaching_tool: &TeachingTool) -> CatalogEntry {
+        CatalogEntry {
+            id: teaching_tool.id,
+            title: teaching_tool.book.title.clone(),
+            publisher: teaching_tool.publishing_house.clone(),
+            pages: teaching_tool.books().map(|book| book.page_urls.len()).sum(),
+            native_downloadable: teaching_tool.book.native_downloadable,
+            error: None,
+        }
+    }
+
+    pub fn failed(id: u64, e: &EdukaError) -> CatalogEntry {
+        CatalogEntry {
+            id,
+            title: String::new(),
+            publisher: String::new(),
+            pages: 0,
+            native_downloadable: false,
+            error: Some(e.to_string()),
+        }
+    }
+}
+
+/// What changed between two catalog scans.
+#[derive(Debug, Default)]
+pub struct CatalogDiff {
+    pub added: Vec<CatalogEntry>,
+    pub removed: Vec<CatalogEntry>,
+    /// Teaching tools in both with something different, old and new
+    pub changed: Vec<(CatalogEntry, CatalogEntry)>,
+    /// Ids either scan failed to fetch, which may or may not have changed
+    pub unknown: Vec<u64>,
+}
+
+pub fn diff_catalogs(old: &[CatalogEntry], new: &[CatalogEntry]) -> CatalogDiff {
+    let old: BTreeMap<u64, &CatalogEntry> = old.iter().map(|entry| (entry.id, entry)).collect();
+    let new: BTreeMap<u64, &CatalogEntry> = new.iter().map(|entry| (entry.id, entry)).collect();
+    let unknown: BTreeSet<u64> = old
+        .values()
+        .chain(new.values())
+        .filter(|entry| entry.error.is_some())
+        .map(|entry| entry.id)
+        .collect();
+    let mut diff = CatalogDiff {
+        unknown: unknown.iter().copied().collect(),
+        ..CatalogDiff::default()
+    };
+    for (id, entry) in &new {
+        if unknown.contains(id) {
+            continue;
+        }
+        match old.get(id) {
+            None => diff.added.push((*entry).clone()),
+            Some(old_entry) if old_entry != entry => {
+                diff.changed.push(((*old_entry).clone(), (*entry).clone()))
+            }
+            Some(_) => {}
+        }
+    }
+    for (id, entry) in &old {
+        if !new.contains_key(id) && !unknown.contains(id) {
+            diff.removed.push((*entry).clone());
+        }
+    }
+    diff
+}
+
+fn is_json(path: &Path) -> bool {
+    path.extension().map(|e| e == "json").unwrap_or(false)
+}
+
+/// Write a catalog as JSON if `path` ends in `.json`, and as CSV otherwise.
+pub fn write_catalog(path: &Path, catalog: &[CatalogEntry]) -> Result<(), EdukaError> {
+    if is_json(path) {
+        fs::write(path, serde_json::to_string_pretty(catalog)?)?;
+    } else {
+        let mut writer = csv::Writer::from_path(path)?;
+        for entry in catalog {
+            writer.serialize(entry)?;
+        }
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+/// Read a catalog written by `write_catalog`.
+pub fn read_catalog(path: &Path) -> Result<Vec<CatalogEntry>, EdukaError> {
+    if is_json(path) {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    } else {
+        Ok(csv::Reader::from_path(path)?
+            .deserialize()
+            .collect::<Result<_, _>>()?)
+    }
+}
+
+impl EdukaClient {
+    /// Fetch the metadata of every id in `ids`, `concurrency` at a time, and
+    /// record the teaching tools there are. Ids eduka has none for are left out;
+    /// ids whose metadata couldn't be fetched are recorded with why, as whether
+    /// they have a teaching tool isn't known. When cancelled, what was found
+    /// until then is returned.
+    pub async fn catalog(
+        self: &Arc<Self>,
+        ids: Range<u64>,
+        concurrency: usize,
+    ) -> Result<Vec<CatalogEntry>, EdukaError> {
+        let progress = self.progress.add(progress_bar(
+            (ids.end - ids.start) as usize,
+            0,
+            String::from("catalog"),
+        ));
+        let mut catalog = vec![];
+        let mut probes = JoinSet::new();
+        let mut ids = ids.into_iter();
+        loop {
+            while probes.len() < concurrency && !self.is_cancelled() {
+                let Some(id) = ids.next() else {
+                    break;
+                };
+                let client = self.clone();
+                probes.spawn(async move {
+                    let mut teaching_tool = TeachingTool::new(id);
+                    let result = client
+                        .fill_teaching_tool_metadata(&mut teaching_tool)
+                        .await
+                        .map(|()| teaching_tool);
+                    (id, result)
+                });
+            }
+            let Some(probe) = probes.join_next().await else {
+                break;
+            };
+            progress.inc(1);
+            match probe.unwrap() {
+                (_, Ok(teaching_tool)) => catalog.push(CatalogEntry::new(&teaching_tool)),
+                (_, Err(EdukaError::NoTeachingTool)) => {}
+                (id, Err(e)) => {
+                    self.warn(&format!("fetching the metadata of {} failed {}", id, &e));
+                    catalog.push(CatalogEntry::failed(id, &e));
+                }
+            }
+        }
+        if self.is_cancelled() {
+            progress.abandon();
+        } else {
+            progress.finish();
+        }
+        catalog.sort_by_key(|entry| entry.id);
+        Ok(catalog)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(id: u64, title: &str) -> CatalogEntry {
+        CatalogEntry {
+            id,
+            title: String::from(title),
+            publisher: String::new(),
+            pages: 100,
+            native_downloadable: false,
+            error: None,
+        }
+    }
+
+    #[test]
+    fn ids_that_failed_in_either_scan_are_not_added_or_removed() {
+        let old = [
+            entry(1, "Fizika 7"),
+            entry(2, "Chemija 8"),
+            CatalogEntry::failed(3, &EdukaError::UnexpectedResponse),
+        ];
+        let new = [
+            CatalogEntry::failed(2, &EdukaError::UnexpectedResponse),
+            entry(3, "Biologija 9"),
+            entry(4, "Istorija 10"),
+        ];
+        let diff = diff_catalogs(&old, &new);
+        assert_eq!(diff.added, [entry(4, "Istorija 10")]);
+        assert_eq!(diff.removed, [entry(1, "Fizika 7")]);
+        assert_eq!(diff.unknown, [2, 3]);
+    }
+
+    #[test]
+    fn catalogs_without_errors_recorded_still_read() {
+        let path = std::env::temp_dir().join("eduka-catalog-test.csv");
+        fs::write(
+            &path,
+            "id,title,publisher,pages,native_downloadable\n1,Fizika 7,,100,false\n",
+        )
+        .unwrap();
+        assert_eq!(read_catalog(&path).unwrap(), [entry(1, "Fizika 7")]);
+        let catalog = [
+            entry(1, "Fizika 7"),
+            CatalogEntry::failed(2, &EdukaError::UnexpectedResponse),
+        ];
+        write_catalog(&path, &catalog).unwrap();
+        assert_eq!(read_catalog(&path).unwrap(), catalog);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,109 @@
+use crate::download::{count_missing_pages, LOCK_FILE};
+use crate::prepare::{delete_page_images, read_metadata};
+use crate::{local_books, EdukaError};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Something left behind in the output directory that `clean` removes.
+#[derive(Debug, Clone)]
+pub enum Leftover {
+    /// Directory of a download that stopped before every page was there
+    PartialDir(PathBuf),
+    /// Book directory missing pages, with no PDF made of them
+    IncompleteBook { path: PathBuf, missing_pages: usize },
+    /// Page images of a book whose PDF has been assembled already
+    PageImages { path: PathBuf, pages: Vec<PathBuf> },
+    /// Page that was being fetched when a download stopped
+    PartialPage(PathBuf),
+}
+
+impl Leftover {
+    pub fn remove(&self) -> Result<(), EdukaError> {
+        match self {
+            Leftover::PartialDir(path) | Leftover::IncompleteBook { path, .. } => {
+                fs::remove_dir_all(path)?
+            }
+            Leftover::PageImages { path, .. } => delete_page_images(path)?,
+            Leftover::PartialPage(path) => fs::remove_file(path)?,
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Leftover {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Leftover::PartialDir(path) => write!(f, "unfinished download {:?}", path),
+            Leftover::IncompleteBook {
+                path,
+                missing_pages,
+            } => write!(f, "{:?}, {} pages missing", path, missing_pages),
+            Leftover::PageImages { path, pages } => {
+                write!(f, "{} page images of prepared {:?}", pages.len(), path)
+            }
+            Leftover::PartialPage(path) => write!(f, "partly fetched page {:?}", path),
+        }
+    }
+}
+
+/// Whether a download is going on in `book_dir` right now.
+fn is_locked(book_dir: &Path) -> bool {
+    book_dir.join(LOCK_FILE).exists()
+}
+
+fn has_extension(path: &Path, extension: &str) -> bool {
+    path.extension().map(|e| e == extension).unwrap_or(false)
+}
+
+/// Everything under `root` that `clean` would remove. Directories a download is
+/// going on in are left alone.
+pub fn find_leftovers(root: &Path) -> Result<Vec<Leftover>, EdukaError> {
+    let mut leftovers = vec![];
+    for entry in fs::read_dir(root)? {
+        let path = entry?.path();
+        if path.is_dir() && has_extension(&path, "partial") && !is_locked(&path) {
+            leftovers.push(Leftover::PartialDir(path));
+        }
+    }
+    for book in local_books(root)? {
+        if is_locked(&book.path) {
+            continue;
+        }
+        let files: Vec<PathBuf> = fs::read_dir(&book.path)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .collect();
+        if !book.prepared {
+            let Ok(teaching_tool) = read_metadata(&book.path) else {
+                continue;
+            };
+            let missing_pages = count_missing_pages(&book.path, teaching_tool.book.page_urls.len());
+            if missing_pages > 0 {
+                leftovers.push(Leftover::IncompleteBook {
+                    path: book.path,
+                    missing_pages,
+                });
+                continue;
+            }
+        } else {
+            let pages: Vec<PathBuf> = files
+                .iter()
+                .filter(|file| has_extension(file, "png"))
+                .cloned()
+                .collect();
+            if !pages.is_empty() {
+                leftovers.push(Leftover::PageImages {
+                    path: book.path.clone(),
+                    pages,
+                });
+            }
+        }
+        for file in files {
+            if has_extension(&file, "part") {
+                leftovers.push(Leftover::PartialPage(file));
+            }
+        }
+    }
+    Ok(leftovers)
+}
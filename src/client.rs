@@ -0,0 +1,907 @@
+use crate::download::response_etag;
+use crate::models::{
+    Book, IsDownloadableResponse, PageRanges, PageSource, PagesResponse, SearchHit, TeachingTool,
+};
+use crate::prepare::DEFAULT_NAME_TEMPLATE;
+use crate::state::StateDb;
+use crate::{EdukaError, Event};
+use reqwest::cookie::CookieStore;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tracing::{info, warn};
+
+const LOGIN_URL: &str = "https://klase.eduka.lt/api/anonymously/login";
+
+const ACCEPT_TERMS_URL: &str =
+    "https://klase.eduka.lt/api/authenticated/teaching-tool/accept-terms/";
+
+const SEARCH_URL: &str = "https://klase.eduka.lt/api/authenticated/teaching-tool/search";
+
+/// Every authenticated endpoint is below this url, so the session cookies are
+/// saved and restored for it.
+const SESSION_URL: &str = "https://klase.eduka.lt/api/authenticated/";
+
+/// Answers 401 once the session has expired.
+const SESSION_CHECK_URL: &str =
+    "https://klase.eduka.lt/api/authenticated/teaching-tool/is-downloadable/0";
+
+/// Session cookies saved between runs, so not every run has to log in again.
+#[derive(Serialize, Deserialize)]
+struct Session {
+    username: String,
+    cookies: String,
+}
+
+/// How many times a metadata request is made before a truncated body is an error.
+const METADATA_ATTEMPTS: u32 = 3;
+
+/// eduka answers 402 or 403 for books the account has no license for, which
+/// should skip the book instead of failing like any other request.
+fn check_licensed(response: reqwest::Response) -> Result<reqwest::Response, EdukaError> {
+    match response.status() {
+        reqwest::StatusCode::PAYMENT_REQUIRED | reqwest::StatusCode::FORBIDDEN => {
+            Err(EdukaError::NotLicensed)
+        }
+        _ => Ok(response),
+    }
+}
+
+/// Which of the sizes eduka has of every page image is downloaded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Resolution {
+    /// The widest there is of each page
+    #[default]
+    AutoMax,
+    /// This width, or the widest there is of a page that doesn't have it
+    Width(u32),
+}
+
+impl Resolution {
+    /// Url of the page image of this resolution out of a page's `img` object,
+    /// which has an url for every width, keyed by the width.
+    fn pick<'a>(&self, images: &'a HashMap<String, String>) -> Option<&'a str> {
+        if let Resolution::Width(width) = self {
+            if let Some(url) = images.get(&width.to_string()) {
+                return Some(url);
+            }
+        }
+        images
+            .iter()
+            .filter_map(|(width, url)| Some((width.parse::<u32>().ok()?, url.as_str())))
+            .max_by_key(|(width, _)| *width)
+            .map(|(_, url)| url)
+    }
+}
+
+impl std::str::FromStr for Resolution {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto-max" => Ok(Resolution::AutoMax),
+            width => width
+                .parse()
+                .map(Resolution::Width)
+                .map_err(|_| format!("{} is neither auto-max nor a width in pixels", width)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ClientOptions {
+    /// Maximum number of concurrent requests to the eduka host across all books
+    pub max_connections: usize,
+    /// Accept the terms of use of books that require it
+    pub accept_terms: bool,
+    /// How many times a failed page request is repeated before the page fails
+    pub max_retries: u32,
+    /// Wait before the first retry, doubled for every retry after it
+    pub retry_delay: Duration,
+    /// Directory the book directories are downloaded into
+    pub output_dir: PathBuf,
+    /// Maximum number of pages of a book downloaded at once
+    pub jobs: usize,
+    /// Maximum number of books of a package downloaded at once
+    pub book_jobs: usize,
+    /// Name of book directories, with `{title}` and `{id}` placeholders
+    pub name_template: String,
+    /// Report progress as JSON events on stdout instead of with progress bars
+    pub json_events: bool,
+    /// Size of the page images downloaded
+    pub resolution: Resolution,
+    /// Download only these pages of every book, as a book of its own
+    pub pages: Option<PageRanges>,
+    /// Bytes per second all downloads together are kept under
+    pub limit_rate: Option<u64>,
+    /// Requests per second to the eduka host on average, each spaced out by a
+    /// random delay of up to one interval
+    pub rps: f64,
+    /// Proxy for every request, `http://`, `https://` or `socks5://`. Without it
+    /// the `HTTPS_PROXY`, `HTTP_PROXY` and `ALL_PROXY` environment variables are
+    /// used
+    pub proxy: Option<String>,
+    /// Ask for the `ETag` of every page of books downloaded already, to find the
+    /// pages updated without moving to another url
+    pub check_updates: bool,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        ClientOptions {
+            max_connections: 10,
+            accept_terms: false,
+            max_retries: 5,
+            retry_delay: Duration::from_secs(1),
+            output_dir: PathBuf::from("."),
+            jobs: 10,
+            book_jobs: 1,
+            name_template: String::from(DEFAULT_NAME_TEMPLATE),
+            json_events: false,
+            resolution: Resolution::AutoMax,
+            pages: None,
+            limit_rate: None,
+            rps: 10.0,
+            proxy: None,
+            check_updates: false,
+        }
+    }
+}
+
+/// HTTP client shared by every task. Besides the cookie store it holds a single
+/// cap on requests in flight to the eduka host, so the load on the server has a
+/// hard ceiling no matter how many books are being worked on at once.
+pub struct EdukaClient {
+    pub(crate) http: reqwest::Client,
+    cookies: Arc<reqwest::cookie::Jar>,
+    pub(crate) connections: Semaphore,
+    accept_terms: bool,
+    pub(crate) max_retries: u32,
+    retry_delay: Duration,
+    pub(crate) output_dir: PathBuf,
+    pub(crate) jobs: usize,
+    pub(crate) book_jobs: usize,
+    pub(crate) name_template: String,
+    pub(crate) progress: indicatif::MultiProgress,
+    json_events: bool,
+    resolution: Resolution,
+    pages: Option<PageRanges>,
+    limit_rate: Option<u64>,
+    /// When the bytes received so far are through at `limit_rate`
+    rate_booked_until: Mutex<Instant>,
+    rps: f64,
+    /// When the next request may be sent under `rps`
+    next_request: Mutex<Instant>,
+    /// Until when every request waits after eduka asked to slow down
+    paused_until: Mutex<Instant>,
+    cancelled: AtomicBool,
+    /// Username and password of the last login, to log in again once the
+    /// session expires in the middle of a download.
+    credentials: Mutex<Option<(String, String)>>,
+    /// Counts logins, so pages that noticed the same expired session log in
+    /// again only once.
+    session_generation: AtomicU64,
+    relogin: tokio::sync::Mutex<()>,
+    /// What was downloaded and prepared in `output_dir`, `None` if its database
+    /// can't be opened
+    state: Option<StateDb>,
+    pub(crate) check_updates: bool,
+    /// Books downloaded before that eduka changed pages of since, by their
+    /// directory, as the parts of a collection share an id
+    updated: Mutex<BTreeSet<String>>,
+}
+
+impl EdukaClient {
+    pub fn new(options: ClientOptions) -> Result<EdukaClient, EdukaError> {
+        let cookies = Arc::new(reqwest::cookie::Jar::default());
+        let mut http = reqwest::Client::builder().cookie_provider(cookies.clone());
+        if let Some(proxy) = &options.proxy {
+            http = http.proxy(reqwest::Proxy::all(proxy)?);
+        }
+        Ok(EdukaClient {
+            http: http.build()?,
+            cookies,
+            connections: Semaphore::new(options.max_connections),
+            accept_terms: options.accept_terms,
+            max_retries: options.max_retries,
+            retry_delay: options.retry_delay,
+            output_dir: options.output_dir.clone(),
+            jobs: options.jobs,
+            book_jobs: options.book_jobs,
+            name_template: options.name_template,
+            progress: if options.json_events {
+                indicatif::MultiProgress::with_draw_target(indicatif::ProgressDrawTarget::hidden())
+            } else {
+                indicatif::MultiProgress::new()
+            },
+            json_events: options.json_events,
+            resolution: options.resolution,
+            pages: options.pages,
+            limit_rate: options.limit_rate,
+            rate_booked_until: Mutex::new(Instant::now()),
+            rps: options.rps,
+            next_request: Mutex::new(Instant::now()),
+            paused_until: Mutex::new(Instant::now()),
+            cancelled: AtomicBool::new(false),
+            credentials: Mutex::new(None),
+            session_generation: AtomicU64::new(0),
+            relogin: tokio::sync::Mutex::new(()),
+            check_updates: options.check_updates,
+            updated: Mutex::new(BTreeSet::new()),
+            state: match StateDb::open(&options.output_dir) {
+                Ok(state) => Some(state),
+                Err(e) => {
+                    warn!(
+                        "opening the state database failed {}, going by the files only",
+                        &e
+                    );
+                    None
+                }
+            },
+        })
+    }
+
+    /// Stop starting new pages and books. Pages already being downloaded are
+    /// finished and recorded, so the next run resumes where this one stopped.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// Wait until `bytes` more can be received within `--limit-rate`. Every
+    /// chunk books the time it takes at that rate after the chunks before it.
+    pub(crate) async fn throttle(&self, bytes: usize) {
+        let Some(limit_rate) = self.limit_rate else {
+            return;
+        };
+        let start = {
+            let mut booked_until = self.rate_booked_until.lock().unwrap();
+            let start = (*booked_until).max(Instant::now());
+            *booked_until = start + Duration::from_secs_f64(bytes as f64 / limit_rate as f64);
+            start
+        };
+        tokio::time::sleep_until(start.into()).await;
+    }
+
+    /// Read the body of a download chunk by chunk within `--limit-rate`.
+    pub(crate) async fn read_body(
+        &self,
+        mut response: reqwest::Response,
+    ) -> Result<Vec<u8>, reqwest::Error> {
+        let mut body = vec![];
+        while let Some(chunk) = response.chunk().await? {
+            self.throttle(chunk.len()).await;
+            body.extend_from_slice(&chunk);
+        }
+        Ok(body)
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Log a line above the progress bars instead of through them.
+    pub(crate) fn log(&self, line: &str) {
+        self.progress.suspend(|| info!("{}", line));
+    }
+
+    /// Print `event` if progress is reported as JSON events.
+    pub fn emit(&self, event: Event) {
+        if self.json_events {
+            event.print();
+        }
+    }
+
+    /// `log` for something that went wrong but is dealt with.
+    pub(crate) fn warn(&self, line: &str) {
+        self.progress.suspend(|| warn!("{}", line));
+    }
+
+    /// Whether pages of a part of `teaching_tool` downloaded before were fetched
+    /// again in this run, as eduka changed them since.
+    pub fn was_updated(&self, teaching_tool: &TeachingTool) -> bool {
+        let updated = self.updated.lock().unwrap();
+        teaching_tool
+            .parts()
+            .iter()
+            .any(|part| updated.contains(&part.book.dir_name(&self.name_template)))
+    }
+
+    pub(crate) fn mark_updated(&self, dir_name: &str) {
+        self.updated.lock().unwrap().insert(String::from(dir_name));
+    }
+
+    /// The `ETag` eduka has for `url` now, asked without fetching the page.
+    pub(crate) async fn head_etag(&self, url: &str) -> Result<Option<String>, EdukaError> {
+        let _connection = self.connection().await;
+        self.pace().await;
+        let paused_until = *self.paused_until.lock().unwrap();
+        tokio::time::sleep_until(paused_until.into()).await;
+        let response = self.http.head(url).send().await?.error_for_status()?;
+        Ok(response_etag(&response))
+    }
+
+    /// The state database of the output directory.
+    pub fn state(&self) -> Option<&StateDb> {
+        self.state.as_ref()
+    }
+
+    /// Record something in the state database, only warning if that fails, as
+    /// the book directories alone are enough to resume from.
+    pub(crate) fn record_state(&self, record: impl FnOnce(&StateDb) -> Result<(), EdukaError>) {
+        if let Some(state) = &self.state {
+            if let Err(e) = record(state) {
+                self.warn(&format!("recording the download state failed {}", &e));
+            }
+        }
+    }
+
+    /// Wait before retry number `retry`, counting from 0: exponential backoff with
+    /// jitter, so pages that failed together don't all retry at the same moment.
+    pub(crate) fn backoff(&self, retry: u32) -> Duration {
+        let delay = self.retry_delay.saturating_mul(2u32.saturating_pow(retry));
+        delay.mul_f64(rand::random::<f64>() / 2.0 + 0.5)
+    }
+
+    /// Wait for a free slot under `--max-connections`. Keep the permit until the
+    /// response body has been read, as that is what holds the connection. Every
+    /// request sent under it still waits for its own turn under `--rps`.
+    pub(crate) async fn connection(&self) -> SemaphorePermit<'_> {
+        self.connections.acquire().await.unwrap()
+    }
+
+    /// Wait for the turn of the next request under `--rps`. Requests are spaced
+    /// out randomly between half and one and a half intervals, so they don't
+    /// arrive like clockwork.
+    pub(crate) async fn pace(&self) {
+        let send_at = {
+            let mut next_request = self.next_request.lock().unwrap();
+            let send_at = (*next_request).max(Instant::now());
+            *next_request = send_at
+                + Duration::from_secs_f64(1.0 / self.rps).mul_f64(rand::random::<f64>() + 0.5);
+            send_at
+        };
+        tokio::time::sleep_until(send_at.into()).await;
+    }
+
+    /// Log in and keep the session cookie in the client. Besides a plain 200, some
+    /// auth flows answer with a redirect to the dashboard, which is only a success
+    /// if it doesn't lead back to the login page and a session cookie was set.
+    pub async fn login(&self, username: &str, password: &str) -> Result<(), EdukaError> {
+        let mut login_map = HashMap::new();
+        login_map.insert("username", username);
+        login_map.insert("password", password);
+        self.pace().await;
+        let login_response = self.http.post(LOGIN_URL).json(&login_map).send().await?;
+        if login_response.status() != reqwest::StatusCode::OK {
+            return Err(EdukaError::LoginFailed);
+        }
+        let final_url = login_response.url();
+        if final_url.as_str() != LOGIN_URL
+            && (final_url.path().contains("login") || self.cookies.cookies(final_url).is_none())
+        {
+            return Err(EdukaError::LoginFailed);
+        }
+        self.remember_credentials(username, password);
+        self.session_generation.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Keep credentials to log in again with once the session expires, for a
+    /// session that was resumed instead of logged in to.
+    pub fn remember_credentials(&self, username: &str, password: &str) {
+        *self.credentials.lock().unwrap() = Some((username.to_string(), password.to_string()));
+    }
+
+    pub(crate) fn session_generation(&self) -> u64 {
+        self.session_generation.load(Ordering::SeqCst)
+    }
+
+    /// Log in again after a request made during session `generation` found it
+    /// expired. Returns false when no credentials are known to do it with.
+    pub(crate) async fn relogin(&self, generation: u64) -> Result<bool, EdukaError> {
+        let _relogin = self.relogin.lock().await;
+        if self.session_generation() != generation {
+            // another request already logged in again
+            return Ok(true);
+        }
+        let Some((username, password)) = self.credentials.lock().unwrap().clone() else {
+            return Ok(false);
+        };
+        self.log("the session has expired, logging in again");
+        self.login(&username, &password).await?;
+        Ok(true)
+    }
+
+    /// Reuse the session saved at `session_path` by an earlier run, if it belongs
+    /// to `username` and hasn't expired. Without one a `login` is needed.
+    pub async fn resume_session(
+        &self,
+        username: &str,
+        session_path: &Path,
+    ) -> Result<bool, EdukaError> {
+        if !self.restore_session(username, session_path) {
+            return Ok(false);
+        }
+        if self.session_valid().await? {
+            return Ok(true);
+        }
+        info!("saved session expired, logging in again");
+        Ok(false)
+    }
+
+    /// Load the cookies saved for `username`, if there are any.
+    fn restore_session(&self, username: &str, session_path: &Path) -> bool {
+        let Ok(session) = fs::read_to_string(session_path) else {
+            return false;
+        };
+        let Ok(session) = serde_json::from_str::<Session>(&session) else {
+            return false;
+        };
+        if session.username != username {
+            return false;
+        }
+        let url = reqwest::Url::parse(SESSION_URL).unwrap();
+        for cookie in session.cookies.split("; ") {
+            self.cookies.add_cookie_str(cookie, &url);
+        }
+        true
+    }
+
+    async fn session_valid(&self) -> Result<bool, EdukaError> {
+        let _connection = self.connection().await;
+        self.check_session().await
+    }
+
+    /// `session_valid` for a caller already holding a connection.
+    async fn check_session(&self) -> Result<bool, EdukaError> {
+        self.pace().await;
+        let response = self.http.get(SESSION_CHECK_URL).send().await?;
+        Ok(response.status() != reqwest::StatusCode::UNAUTHORIZED
+            && !response.url().path().contains("login"))
+    }
+
+    /// GET `url`, logging in again and repeating the request once when the session
+    /// has expired in the meantime. eduka answers 403 both for an expired session
+    /// and for an unlicensed book, so a 403 only logs in again if the session
+    /// check fails too; one that stays is `NotLicensed`.
+    pub(crate) async fn get(&self, url: &str) -> Result<reqwest::Response, EdukaError> {
+        let generation = self.session_generation();
+        let response = self.send(url).await?;
+        let expired = match response.status() {
+            reqwest::StatusCode::UNAUTHORIZED => true,
+            reqwest::StatusCode::FORBIDDEN => !self.check_session().await?,
+            _ => false,
+        };
+        if expired && self.relogin(generation).await? {
+            return check_licensed(self.send(url).await?);
+        }
+        check_licensed(response)
+    }
+
+    /// Send a GET, repeating it while eduka is rate limiting. A 429 or 503 pauses
+    /// the requests of every task, for as long as its `Retry-After` seconds ask
+    /// or the backoff of the attempt if it has none.
+    async fn send(&self, url: &str) -> Result<reqwest::Response, EdukaError> {
+        let mut retry = 0;
+        loop {
+            self.pace().await;
+            let paused_until = *self.paused_until.lock().unwrap();
+            tokio::time::sleep_until(paused_until.into()).await;
+            let response = self.http.get(url).send().await?;
+            let status = response.status();
+            if !matches!(
+                status,
+                reqwest::StatusCode::TOO_MANY_REQUESTS | reqwest::StatusCode::SERVICE_UNAVAILABLE
+            ) || retry >= self.max_retries
+            {
+                return Ok(response);
+            }
+            let delay = response
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.trim().parse().ok())
+                .map(Duration::from_secs)
+                .unwrap_or_else(|| self.backoff(retry));
+            {
+                let mut paused_until = self.paused_until.lock().unwrap();
+                if Instant::now() + delay > *paused_until {
+                    *paused_until = Instant::now() + delay;
+                    self.warn(&format!(
+                        "eduka answered {}, pausing all requests for {:.1}s",
+                        status,
+                        delay.as_secs_f64()
+                    ));
+                }
+            }
+            retry += 1;
+        }
+    }
+
+    /// Save the cookies of the current session to `session_path`, readable only
+    /// by the user as they are as good as the password until they expire.
+    pub fn save_session(&self, username: &str, session_path: &Path) -> Result<(), EdukaError> {
+        let url = reqwest::Url::parse(SESSION_URL).unwrap();
+        let Some(cookies) = self.cookies.cookies(&url) else {
+            return Ok(());
+        };
+        let session = Session {
+            username: String::from(username),
+            cookies: String::from(
+                cookies
+                    .to_str()
+                    .map_err(|_| EdukaError::UnexpectedResponse)?,
+            ),
+        };
+        if let Some(dir) = session_path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        std::io::Write::write_all(
+            &mut options.open(session_path)?,
+            serde_json::to_string(&session)?.as_bytes(),
+        )?;
+        Ok(())
+    }
+
+    /// GET `url` and parse the body as JSON. A body that ends mid-document means the
+    /// connection dropped during the transfer, so the request is repeated after a
+    /// backoff, taking its own turn under `--rps` like any other. Any other
+    /// parse failure is a schema mismatch and surfaces as `MissingField` when a
+    /// field isn't there, and as `UnexpectedResponse` otherwise, logging what
+    /// didn't match. A 404 is `NoTeachingTool`, as that is how eduka
+    /// answers ids nothing has.
+    pub(crate) async fn get_json<T: serde::de::DeserializeOwned>(
+        &self,
+        url: &str,
+    ) -> Result<T, EdukaError> {
+        let mut attempt = 1;
+        loop {
+            let response = self.get(url).await?;
+            match response.status() {
+                reqwest::StatusCode::PRECONDITION_REQUIRED => {
+                    return Err(EdukaError::TermsNotAccepted)
+                }
+                reqwest::StatusCode::NOT_FOUND => return Err(EdukaError::NoTeachingTool),
+                _ => {}
+            }
+            let body = response.text().await?;
+            match serde_json::from_str(&body) {
+                Ok(value) => return Ok(value),
+                Err(e) if e.is_eof() && attempt < METADATA_ATTEMPTS => {
+                    let delay = self.backoff(attempt - 1);
+                    self.warn(&format!(
+                        "truncated response from {}, retrying in {:.1}s",
+                        url,
+                        delay.as_secs_f64()
+                    ));
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                Err(e) if e.is_eof() => return Err(EdukaError::JSONError(e)),
+                Err(e) => {
+                    warn!(
+                        "the response from {} does not match what was expected, {}",
+                        url, &e
+                    );
+                    return Err(match missing_field(&e) {
+                        Some(field) => EdukaError::MissingField(field),
+                        None => EdukaError::UnexpectedResponse,
+                    });
+                }
+            }
+        }
+    }
+
+    /// `get_json` for the endpoints of books whose terms of use have to be accepted
+    /// once per session first. With `--accept-terms` that is done on the user's
+    /// behalf before asking again.
+    async fn get_json_accepting_terms<T: serde::de::DeserializeOwned>(
+        &self,
+        url: &str,
+        teaching_tool_id: u64,
+    ) -> Result<T, EdukaError> {
+        match self.get_json(url).await {
+            Err(EdukaError::TermsNotAccepted) if self.accept_terms => {
+                info!(
+                    "accepting the terms of use of teaching tool {}",
+                    teaching_tool_id
+                );
+                self.pace().await;
+                check_licensed(
+                    self.http
+                        .post(String::from(ACCEPT_TERMS_URL) + &teaching_tool_id.to_string())
+                        .send()
+                        .await?,
+                )?
+                .error_for_status()?;
+                self.get_json(url).await
+            }
+            result => result,
+        }
+    }
+
+    /// Teaching tools whose title, subject or grade matches `query`, as eduka's
+    /// own search finds them.
+    pub async fn search(&self, query: &str) -> Result<Vec<SearchHit>, EdukaError> {
+        let url = reqwest::Url::parse_with_params(SEARCH_URL, [("query", query)]).unwrap();
+        let _connection = self.connection().await;
+        self.get_json(url.as_str()).await
+    }
+
+    pub async fn fill_teaching_tool_metadata(
+        &self,
+        teaching_tool: &mut TeachingTool,
+    ) -> Result<(), EdukaError> {
+        let _connection = self.connection().await;
+        let is_downloadable_response: IsDownloadableResponse = self
+            .get_json(&format!(
+                "https://klase.eduka.lt/api/authenticated/teaching-tool/is-downloadable/{}",
+                &teaching_tool.id
+            ))
+            .await?;
+        let mut book: Book = self
+            .get_json_accepting_terms(
+                &(String::from(
+                    "https://klase.eduka.lt/api/authenticated/part/show-by-teaching-tool/",
+                ) + &teaching_tool.id.to_string()),
+                teaching_tool.id,
+            )
+            .await?;
+        book.title = book.collection_title.clone()
+            + ": "
+            + &book
+                .parts
+                .first()
+                .ok_or_else(|| EdukaError::MissingField(String::from("parts")))?
+                .title;
+        book.id = teaching_tool.id;
+        book.native_downloadable = is_downloadable_response.is_downloadable;
+        teaching_tool.book = book.clone();
+        let mut books = vec![];
+        for part in &book.parts {
+            let mut part_book = book.clone();
+            part_book.title = book.collection_title.clone() + ": " + &part.title;
+            // with a single part the teaching tool is the part
+            let part_id = (book.parts.len() > 1).then_some(part.id);
+            if part_id.is_some() {
+                // the publisher's PDF is of the whole collection
+                part_book.native_downloadable = false;
+            }
+            self.fill_pages(&mut part_book, part_id).await?;
+            books.push(part_book);
+        }
+        if let Some(pages) = &self.pages {
+            books = books.iter().map(|book| book.select_pages(pages)).collect();
+        }
+        let mut books = books.into_iter();
+        teaching_tool.book = books.next().ok_or(EdukaError::UnexpectedResponse)?;
+        teaching_tool.other_parts = books.collect();
+        Ok(())
+    }
+
+    /// Page urls, page shift and bookmarks of a book, or of one part of it.
+    async fn fill_pages(&self, book: &mut Book, part_id: Option<u64>) -> Result<(), EdukaError> {
+        let mut url = String::from("https://klase.eduka.lt/api/authenticated/teaching-tool/pages/")
+            + &book.id.to_string();
+        if let Some(part_id) = part_id {
+            url += &format!("?part={}", part_id);
+        }
+        let pages: PagesResponse = self.get_json_accepting_terms(&url, book.id).await?;
+        for page in &pages.pages {
+            let img_url_frag = page
+                .img
+                .as_ref()
+                .and_then(|images| self.resolution.pick(images))
+                .or(page.svg.as_deref());
+            if let Some(img_url_frag) = img_url_frag {
+                book.page_urls.push(PageSource::Url(
+                    String::from("https://klase.eduka.lt") + img_url_frag,
+                ));
+            } else if let Some(rows) = &page.tiles {
+                let rows = rows
+                    .iter()
+                    .map(|row| {
+                        row.iter()
+                            .map(|tile| String::from("https://klase.eduka.lt") + tile)
+                            .collect()
+                    })
+                    .collect();
+                book.page_urls.push(PageSource::Tiles(rows));
+            } else if page.pdf.is_some() {
+                return Err(EdukaError::PdfPages);
+            } else {
+                warn!("Couldn't get page by {:?}", &page)
+            }
+        }
+        book.page_shift = pages.page_shift;
+        book.bookmarks = pages.chapters;
+        Ok(())
+    }
+
+    /// Issue two authenticated requests one after the other before the concurrent
+    /// page fetches, so the pool starts with a warm connection, and warn if the
+    /// second didn't reuse the connection of the first, as every page would then
+    /// pay for a fresh handshake.
+    pub(crate) async fn warm_up_connection(&self, teaching_tool_id: u64) {
+        let _connection = self.connection().await;
+        let url = format!(
+            "https://klase.eduka.lt/api/authenticated/teaching-tool/is-downloadable/{}",
+            &teaching_tool_id
+        );
+        let mut local_addrs = vec![];
+        let mut closes_connection = false;
+        for _ in 0..2 {
+            self.pace().await;
+            let response = match self.http.get(&url).send().await {
+                Ok(response) => response,
+                Err(e) => {
+                    self.warn(&format!("warm-up request failed {}", &e));
+                    return;
+                }
+            };
+            local_addrs.push(local_addr(&response));
+            closes_connection = response
+                .headers()
+                .get(reqwest::header::CONNECTION)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.eq_ignore_ascii_case("close"))
+                .unwrap_or(false);
+            // the connection only goes back to the pool once the body is read
+            if let Err(e) = response.bytes().await {
+                self.warn(&format!("warm-up request failed {}", &e));
+                return;
+            }
+        }
+        let reused = match (local_addrs[0], local_addrs[1]) {
+            (Some(first), Some(second)) => first == second,
+            // not known for every connection, the server saying so has to do
+            _ => !closes_connection,
+        };
+        if !reused {
+            self.warn("server closes the connection after every request, downloads will be slow");
+        }
+    }
+}
+
+/// Name of the field serde found missing, which it only tells in the message.
+fn missing_field(e: &serde_json::Error) -> Option<String> {
+    let message = e.to_string();
+    let (field, _) = message.strip_prefix("missing field `")?.split_once('`')?;
+    Some(String::from(field))
+}
+
+/// Local address of the connection a response came over, the same for every
+/// response over a reused connection.
+fn local_addr(response: &reqwest::Response) -> Option<SocketAddr> {
+    response
+        .extensions()
+        .get::<hyper::client::connect::HttpInfo>()
+        .map(|info| info.local_addr())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[derive(Debug, Deserialize)]
+    struct Title {
+        title: String,
+    }
+
+    /// Answer one request per connection with each of `bodies` in turn, the last
+    /// one over and over. Returns the url served and how many requests came.
+    async fn serve(bodies: &'static [&'static str]) -> (String, Arc<AtomicUsize>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counted = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let n = counted.fetch_add(1, Ordering::SeqCst);
+                let body = bodies[n.min(bodies.len() - 1)];
+                let mut request = vec![];
+                let mut buffer = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let read = stream.read(&mut buffer).await.unwrap();
+                    request.extend(&buffer[..read]);
+                }
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        (url, requests)
+    }
+
+    fn client() -> EdukaClient {
+        EdukaClient::new(ClientOptions {
+            output_dir: std::env::temp_dir().join("eduka-client-test"),
+            retry_delay: Duration::from_millis(100),
+            ..ClientOptions::default()
+        })
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn closed_connections_are_told_apart() {
+        // the test server closes the connection after every response
+        let (url, _) = serve(&["{}"]).await;
+        let client = client();
+        let mut local_addrs = vec![];
+        for _ in 0..2 {
+            let response = client.http.get(&url).send().await.unwrap();
+            local_addrs.push(local_addr(&response).unwrap());
+            response.bytes().await.unwrap();
+        }
+        assert_ne!(local_addrs[0], local_addrs[1]);
+    }
+
+    #[tokio::test]
+    async fn requests_under_one_connection_are_paced() {
+        let (url, requests) = serve(&["{}"]).await;
+        let client = EdukaClient::new(ClientOptions {
+            output_dir: std::env::temp_dir().join("eduka-client-test"),
+            rps: 10.0,
+            ..ClientOptions::default()
+        })
+        .unwrap();
+        let started = Instant::now();
+        let _connection = client.connection().await;
+        for _ in 0..3 {
+            client.send(&url).await.unwrap().bytes().await.unwrap();
+        }
+        // two gaps of at least half an interval each
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn get_json_retries_truncated_body() {
+        let (url, requests) = serve(&[r#"{"title": "Fizika 7"#, r#"{"title": "Fizika 7"}"#]).await;
+        let started = Instant::now();
+        let title: Title = client().get_json(&url).await.unwrap();
+        assert_eq!(title.title, "Fizika 7");
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        // the first backoff is at least half the retry delay
+        assert!(started.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn get_json_gives_up_on_body_truncated_every_time() {
+        let (url, requests) = serve(&[r#"{"title": "Fiz"#]).await;
+        let result = client().get_json::<Title>(&url).await;
+        assert!(matches!(result, Err(EdukaError::JSONError(_))));
+        assert_eq!(requests.load(Ordering::SeqCst), METADATA_ATTEMPTS as usize);
+    }
+
+    #[tokio::test]
+    async fn get_json_reports_wrong_shape_without_retrying() {
+        let (url, requests) = serve(&[r#"{"title": 7}"#]).await;
+        let result = client().get_json::<Title>(&url).await;
+        assert!(matches!(result, Err(EdukaError::UnexpectedResponse)));
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn get_json_names_the_missing_field() {
+        let (url, _) = serve(&[r#"{"name": "Fizika 7"}"#]).await;
+        let result = client().get_json::<Title>(&url).await;
+        assert!(matches!(result, Err(EdukaError::MissingField(field)) if field == "title"));
+    }
+}
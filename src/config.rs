@@ -0,0 +1,89 @@
+//! Defaults from `config.toml` in the eduka-downloader config directory. They
+//! take the place of the built-in defaults of the command line, so a flag that
+//! is given still wins over the config.
+
+use eduka::DEFAULT_NAME_TEMPLATE;
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// Keys are named like the flags they set the default of, e.g. `max-connections`.
+#[derive(Debug, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Config {
+    pub username: Option<String>,
+    pub output_dir: PathBuf,
+    pub max_connections: u64,
+    pub jobs: u64,
+    pub book_jobs: u64,
+    pub rps: f64,
+    pub max_retries: u32,
+    pub retry_delay: u64,
+    pub ocr_lang: String,
+    pub name_template: String,
+    /// Delete the page images once the PDF is assembled, unless `--keep-images`
+    pub delete_images: bool,
+    /// Page shift of single books by id, like `--page-shift-override` for that
+    /// book only
+    #[serde(deserialize_with = "book_ids")]
+    pub page_shift: HashMap<u64, i64>,
+}
+
+/// TOML keys are always strings, so the ids are parsed from them.
+fn book_ids<'de, D: Deserializer<'de>>(deserializer: D) -> Result<HashMap<u64, i64>, D::Error> {
+    HashMap::<String, i64>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(id, value)| {
+            id.parse()
+                .map(|id| (id, value))
+                .map_err(|_| serde::de::Error::custom(format!("{:?} is not a book id", id)))
+        })
+        .collect()
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            username: None,
+            output_dir: PathBuf::from("."),
+            max_connections: 10,
+            jobs: 10,
+            book_jobs: 1,
+            rps: 10.0,
+            max_retries: 5,
+            retry_delay: 1000,
+            ocr_lang: String::from("lit"),
+            name_template: String::from(DEFAULT_NAME_TEMPLATE),
+            delete_images: false,
+            page_shift: HashMap::new(),
+        }
+    }
+}
+
+fn config_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("eduka-downloader").join("config.toml"))
+}
+
+/// Read the config file, if there is one, before the command line is parsed.
+pub fn load() -> Result<(), String> {
+    let config = match config_path() {
+        Some(path) => match fs::read_to_string(&path) {
+            Ok(text) => toml::from_str(&text)
+                .map_err(|e| format!("reading config {:?} failed {}", &path, &e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Config::default(),
+            Err(e) => return Err(format!("reading config {:?} failed {}", &path, &e)),
+        },
+        None => Config::default(),
+    };
+    CONFIG.set(config).ok();
+    Ok(())
+}
+
+pub fn config() -> &'static Config {
+    CONFIG.get_or_init(Config::default)
+}
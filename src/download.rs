@@ -0,0 +1,1173 @@
+use crate::models::{Book, Package, PageSource, TeachingTool};
+use crate::prepare::images_deleted;
+use crate::{EdukaClient, EdukaError, Event};
+use indicatif::{ProgressBar, ProgressStyle};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::{fs, io};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Semaphore;
+use tracing::debug;
+
+const BENCHMARK_LEVELS: [usize; 5] = [1, 2, 4, 8, 16];
+const BENCHMARK_SAMPLE: usize = 16;
+/// Directory in the output directory the pages of `benchmark` are fetched into.
+const BENCHMARK_DIR: &str = ".benchmark";
+
+const PROGRESS_TEMPLATE: &str = "{msg} [{bar:40}] {pos}/{len} {per_sec}";
+
+/// Bar for `len` items, starting at `done`.
+pub(crate) fn progress_bar(len: usize, done: usize, message: String) -> ProgressBar {
+    let bar = ProgressBar::new(len as u64)
+        .with_style(
+            ProgressStyle::with_template(PROGRESS_TEMPLATE)
+                .unwrap()
+                .progress_chars("=> "),
+        )
+        .with_message(message);
+    bar.set_position(done as u64);
+    bar
+}
+
+/// Why a page response is not a page image, if it isn't: error bodies come back
+/// with a 200 too and must not be saved as pages.
+fn not_a_page(content_type: Option<&str>, bytes: &[u8]) -> Option<String> {
+    if let Some(content_type) = content_type {
+        if content_type.starts_with("text/") || content_type.contains("json") {
+            return Some(format!(
+                "answered with {} instead of an image",
+                content_type
+            ));
+        }
+    }
+    if image::guess_format(bytes).is_err() && !is_svg(bytes) {
+        return Some("answered with something that is not an image".to_string());
+    }
+    None
+}
+
+/// How much of a page response is looked at to tell whether it is an image.
+const SNIFF_LEN: usize = 1024;
+
+fn is_svg(bytes: &[u8]) -> bool {
+    let start = String::from_utf8_lossy(&bytes[..bytes.len().min(SNIFF_LEN)]);
+    let start = start.trim_start();
+    (start.starts_with("<?xml") || start.starts_with("<svg")) && start.contains("<svg")
+}
+
+/// Width SVG pages are rendered at, that of an A4 page at 300 DPI.
+const SVG_WIDTH: f32 = 2480.0;
+
+fn rasterize_svg(svg: &[u8]) -> Result<Vec<u8>, EdukaError> {
+    use resvg::{tiny_skia, usvg};
+    let tree =
+        usvg::Tree::from_data(svg, &usvg::Options::default()).map_err(|_| EdukaError::SvgError)?;
+    let scale = SVG_WIDTH / tree.size().width();
+    let size = tree
+        .size()
+        .to_int_size()
+        .scale_by(scale)
+        .ok_or(EdukaError::SvgError)?;
+    let mut pixmap =
+        tiny_skia::Pixmap::new(size.width(), size.height()).ok_or(EdukaError::SvgError)?;
+    // pages are opaque, transparency would turn black in some viewers
+    pixmap.fill(tiny_skia::Color::WHITE);
+    resvg::render(
+        &tree,
+        tiny_skia::Transform::from_scale(scale, scale),
+        &mut pixmap.as_mut(),
+    );
+    pixmap.encode_png().map_err(|_| EdukaError::SvgError)
+}
+
+/// Put the tiles of a page back together into one PNG. Tiles of a row are as
+/// high as each other and rows as wide.
+fn stitch_tiles(rows: &[Vec<Vec<u8>>]) -> Result<Vec<u8>, EdukaError> {
+    let rows = rows
+        .iter()
+        .map(|row| {
+            row.iter()
+                .map(|tile| Ok(image::load_from_memory(tile)?.to_rgba8()))
+                .collect::<Result<Vec<_>, EdukaError>>()
+        })
+        .collect::<Result<Vec<_>, EdukaError>>()?;
+    let width = rows
+        .iter()
+        .map(|row| row.iter().map(|tile| tile.width()).sum::<u32>())
+        .max()
+        .unwrap_or(0);
+    let height = rows
+        .iter()
+        .map(|row| row.iter().map(|tile| tile.height()).max().unwrap_or(0))
+        .sum();
+    let mut page = image::RgbaImage::new(width, height);
+    let mut y = 0;
+    for row in &rows {
+        let mut x = 0;
+        for tile in row {
+            image::imageops::replace(&mut page, tile, x, y);
+            x += tile.width() as i64;
+        }
+        y += row.iter().map(|tile| tile.height()).max().unwrap_or(0) as i64;
+    }
+    let mut png = vec![];
+    image::DynamicImage::ImageRgba8(page)
+        .write_to(&mut io::Cursor::new(&mut png), image::ImageFormat::Png)?;
+    Ok(png)
+}
+
+async fn save_page_to_file(
+    client: Arc<EdukaClient>,
+    book_dir: &Path,
+    page: &PageSource,
+    page_number: u64,
+) -> Result<Vec<Option<String>>, EdukaError> {
+    let path = book_dir.join(format!("{}.png", page_number));
+    let mut etags = vec![];
+    // written next to the page and renamed, so an interrupted write never leaves
+    // a half page behind under the page's name
+    let partial_path = path.with_extension("png.part");
+    let converted = match page {
+        PageSource::Url(url) => {
+            etags.push(fetch_page_image(&client, url, page_number, &partial_path).await?);
+            let mut head = vec![];
+            io::Read::read_to_end(
+                &mut io::Read::take(fs::File::open(&partial_path)?, SNIFF_LEN as u64),
+                &mut head,
+            )?;
+            if is_svg(&head) {
+                Some(rasterize_svg(&fs::read(&partial_path)?)?)
+            } else {
+                None
+            }
+        }
+        PageSource::Tiles(rows) => {
+            let mut tiles = vec![];
+            for row in rows {
+                let mut row_tiles = vec![];
+                for url in row {
+                    etags.push(fetch_page_image(&client, url, page_number, &partial_path).await?);
+                    row_tiles.push(fs::read(&partial_path)?);
+                }
+                tiles.push(row_tiles);
+            }
+            Some(stitch_tiles(&tiles)?)
+        }
+    };
+    if let Some(converted) = converted {
+        let mut file = tokio::fs::File::create(&partial_path).await?;
+        file.write_all(&converted).await?;
+        file.flush().await?;
+    }
+    tokio::fs::rename(&partial_path, &path).await?;
+    debug!("saved page {} to {:?}", page_number, &path);
+    Ok(etags)
+}
+
+/// Write a page response to `path` chunk by chunk as it arrives, so no more
+/// than a chunk of it is held in memory. The first `SNIFF_LEN` bytes tell
+/// whether it is an image at all, which is the problem returned if it isn't.
+async fn receive_page(
+    client: &EdukaClient,
+    mut response: reqwest::Response,
+    path: &Path,
+) -> Result<Option<String>, EdukaError> {
+    let content_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    let expected = response.content_length();
+    let mut file = tokio::fs::File::create(path).await?;
+    let mut head = vec![];
+    let mut received = 0;
+    while let Some(chunk) = response.chunk().await? {
+        client.throttle(chunk.len()).await;
+        received += chunk.len() as u64;
+        if head.len() < SNIFF_LEN {
+            head.extend_from_slice(&chunk);
+            if head.len() >= SNIFF_LEN {
+                if let Some(problem) = not_a_page(content_type.as_deref(), &head) {
+                    return Ok(Some(problem));
+                }
+                file.write_all(&head).await?;
+            }
+        } else {
+            file.write_all(&chunk).await?;
+        }
+    }
+    if head.len() < SNIFF_LEN {
+        if let Some(problem) = not_a_page(content_type.as_deref(), &head) {
+            return Ok(Some(problem));
+        }
+        file.write_all(&head).await?;
+    }
+    file.flush().await?;
+    match expected {
+        Some(expected) if expected != received => {
+            Err(EdukaError::TruncatedPage { received, expected })
+        }
+        _ => Ok(None),
+    }
+}
+
+/// The `ETag` eduka sent a page with, which changes when the page does.
+pub(crate) fn response_etag(response: &reqwest::Response) -> Option<String> {
+    response
+        .headers()
+        .get(reqwest::header::ETAG)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from)
+}
+
+/// Fetch an image of page `page_number` into `path`, retrying with backoff.
+/// Returns the page's `ETag`, if eduka sent one.
+async fn fetch_page_image(
+    client: &EdukaClient,
+    page_url: &str,
+    page_number: u64,
+    path: &Path,
+) -> Result<Option<String>, EdukaError> {
+    let mut retry = 0;
+    loop {
+        let generation = client.session_generation();
+        let mut etag = None;
+        let received = {
+            let _connection = client.connection().await;
+            match client.get(page_url).await {
+                Ok(r) => match r.error_for_status() {
+                    Ok(r) => {
+                        etag = response_etag(&r);
+                        receive_page(client, r, path).await
+                    }
+                    Err(e) => Err(EdukaError::InternetError(e)),
+                },
+                Err(e) => Err(e),
+            }
+        };
+        let e = match received {
+            Ok(None) => return Ok(etag),
+            Ok(Some(problem)) => {
+                // an expired session is answered with the login page or a
+                // JSON error instead of the image
+                client.relogin(generation).await?;
+                problem
+            }
+            Err(EdukaError::InternetError(e)) => e.to_string(),
+            Err(e @ EdukaError::TruncatedPage { .. }) => e.to_string(),
+            Err(e) => return Err(e),
+        };
+        if retry < client.max_retries {
+            let delay = client.backoff(retry);
+            client.warn(&format!(
+                "fetching page {} failed {}, retrying in {:.1}s",
+                &page_number,
+                &e,
+                delay.as_secs_f64()
+            ));
+            tokio::time::sleep(delay).await;
+            retry += 1;
+        } else {
+            client.warn(&format!("fetching page {} failed {}", &page_number, &e));
+            return Err(EdukaError::PageFailed {
+                page: page_number,
+                attempts: retry + 1,
+            });
+        }
+    }
+}
+
+/// Save a page into the partial directory of the book directory `dir_name` and
+/// mark it fetched in the book's manifest once it is complete.
+async fn save_page_and_record(
+    client: Arc<EdukaClient>,
+    dir_name: &str,
+    page: &PageSource,
+    page_number: usize,
+    book_id: u64,
+    manifest: &Mutex<Manifest>,
+    progress: &ProgressBar,
+) -> Result<(), EdukaError> {
+    let book_dir = &client.output_dir.join(format!("{}.partial", dir_name));
+    let etags = save_page_to_file(client.clone(), book_dir, page, page_number as u64).await?;
+    progress.inc(1);
+    let path = book_dir.join(format!("{}.png", page_number));
+    if !is_complete_page(&path) {
+        return Ok(());
+    }
+    let hash = page_hash(&fs::read(&path)?);
+    {
+        let mut manifest = manifest.lock().unwrap();
+        manifest.fetched.insert(page_number);
+        manifest.hashes.insert(page_number, hash.clone());
+        manifest.sources.insert(page_number, page.urls());
+        manifest.etags.insert(page_number, etags);
+        manifest.save(book_dir)?;
+    }
+    client.record_state(|state| state.record_page(dir_name, page_number, &hash));
+    client.emit(Event::PageDone {
+        id: book_id,
+        page: page_number,
+    });
+    Ok(())
+}
+
+/// Whether a page left by an earlier run is complete. A crash can leave a zero
+/// byte or cut off file behind, so existing alone doesn't make a page done.
+pub(crate) fn is_complete_page(path: &Path) -> bool {
+    let Ok(bytes) = fs::read(path) else {
+        return false;
+    };
+    match image::guess_format(&bytes) {
+        // IEND chunk
+        Ok(image::ImageFormat::Png) => {
+            bytes.ends_with(&[0x49, 0x45, 0x4E, 0x44, 0xAE, 0x42, 0x60, 0x82])
+        }
+        // end of image marker
+        Ok(image::ImageFormat::Jpeg) => bytes.ends_with(&[0xFF, 0xD9]),
+        Ok(_) => true,
+        Err(_) => false,
+    }
+}
+
+/// How many of the first `page_count` pages in `book_dir` are missing or
+/// incomplete.
+pub(crate) fn count_missing_pages(book_dir: &Path, page_count: usize) -> usize {
+    (0..page_count)
+        .filter(|i| !is_complete_page(&book_dir.join(format!("{}.png", i))))
+        .count()
+}
+
+pub(crate) const METADATA_FILE: &str = "metadata.json";
+
+/// Keep everything fetched about a book in its directory, so it can be prepared
+/// again, resumed or read by other tools without asking eduka.
+pub(crate) fn write_metadata(
+    book_dir: &Path,
+    teaching_tool: &TeachingTool,
+) -> Result<(), EdukaError> {
+    fs::write(
+        book_dir.join(METADATA_FILE),
+        serde_json::to_string_pretty(teaching_tool)?,
+    )?;
+    Ok(())
+}
+
+pub(crate) const MANIFEST_FILE: &str = "manifest.json";
+
+/// Which pages of a book have been fetched, rewritten after every page so an
+/// interrupted download only has to fetch the rest when run again.
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    page_count: usize,
+    fetched: BTreeSet<usize>,
+    /// SHA-256 of every fetched page, so a page changed on disk since is fetched
+    /// again. Manifests from before there were hashes have none
+    #[serde(default)]
+    hashes: BTreeMap<usize, String>,
+    /// SHA-256 of the PDFs and other files assembled of the pages, by file name
+    #[serde(default)]
+    outputs: BTreeMap<String, String>,
+    /// Urls every fetched page was fetched from, to tell pages eduka replaced
+    #[serde(default)]
+    sources: BTreeMap<usize, Vec<String>>,
+    /// `ETag`s the urls of every fetched page were sent with, if any
+    #[serde(default)]
+    etags: BTreeMap<usize, Vec<Option<String>>>,
+}
+
+/// Hex SHA-256 of a page.
+pub(crate) fn page_hash(bytes: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+/// Record the checksum of `output`, assembled in `book_dir`, in its manifest.
+/// Directories downloaded before manifests were written don't get one, nor
+/// outputs that were removed again, like the PDF of `--chapters-only`.
+pub(crate) fn record_output_hash(book_dir: &Path, output: &Path) -> Result<(), EdukaError> {
+    let (Some(mut manifest), Some(name)) = (Manifest::load(book_dir), output.file_name()) else {
+        return Ok(());
+    };
+    if !output.is_file() {
+        return Ok(());
+    }
+    manifest.outputs.insert(
+        name.to_string_lossy().into_owned(),
+        page_hash(&fs::read(output)?),
+    );
+    manifest.save(book_dir)
+}
+
+/// Pages and assembled files in `book_dir` that aren't what the manifest has a
+/// checksum of anymore. Files that are gone are left to other checks.
+pub(crate) fn checksum_mismatches(book_dir: &Path) -> (Vec<usize>, Vec<PathBuf>) {
+    let Some(manifest) = Manifest::load(book_dir) else {
+        return (vec![], vec![]);
+    };
+    let differs = |path: &Path, hash: &String| {
+        fs::read(path)
+            .map(|bytes| page_hash(&bytes) != *hash)
+            .unwrap_or(false)
+    };
+    let pages = manifest
+        .hashes
+        .iter()
+        .filter(|(i, hash)| differs(&book_dir.join(format!("{}.png", i)), hash))
+        .map(|(&i, _)| i)
+        .collect();
+    let outputs = manifest
+        .outputs
+        .iter()
+        .map(|(name, hash)| (book_dir.join(name), hash))
+        .filter(|(path, hash)| differs(path, hash))
+        .map(|(path, _)| path)
+        .collect();
+    (pages, outputs)
+}
+
+/// What `pages` records of the pages of a book that aren't in `missing_pages`.
+fn kept_pages<T: Clone>(
+    pages: Option<&BTreeMap<usize, T>>,
+    missing_pages: &[usize],
+) -> BTreeMap<usize, T> {
+    pages
+        .into_iter()
+        .flatten()
+        .filter(|(i, _)| !missing_pages.contains(i))
+        .map(|(&i, value)| (i, value.clone()))
+        .collect()
+}
+
+/// Whether the page at `path` is still what was fetched, as far as `manifest`
+/// knows.
+fn matches_manifest(manifest: &Manifest, page_number: usize, path: &Path) -> bool {
+    let Some(hash) = manifest.hashes.get(&page_number) else {
+        return true;
+    };
+    fs::read(path)
+        .map(|bytes| page_hash(&bytes) == *hash)
+        .unwrap_or(false)
+}
+
+impl Manifest {
+    /// `None` for directories downloaded before manifests were written.
+    fn load(book_dir: &Path) -> Option<Manifest> {
+        let manifest = fs::read_to_string(book_dir.join(MANIFEST_FILE)).ok()?;
+        serde_json::from_str(&manifest).ok()
+    }
+
+    fn save(&self, book_dir: &Path) -> Result<(), EdukaError> {
+        fs::write(book_dir.join(MANIFEST_FILE), serde_json::to_string(self)?)?;
+        Ok(())
+    }
+}
+
+pub(crate) const LOCK_FILE: &str = ".lock";
+
+/// The publisher's own PDF of a natively downloadable book, used as is instead
+/// of assembling one from the page images.
+pub(crate) const NATIVE_PDF: &str = "native.pdf";
+
+const NATIVE_DOWNLOAD_URL: &str =
+    "https://klase.eduka.lt/api/authenticated/teaching-tool/download/";
+
+/// Marks a book directory as being downloaded by this process, so a second
+/// instance doesn't interleave its writes into the same page files. Removed
+/// again when dropped.
+struct BookLock {
+    path: PathBuf,
+}
+
+impl BookLock {
+    /// `None` if another instance already holds the lock.
+    fn acquire(book_dir: &Path) -> Result<Option<BookLock>, EdukaError> {
+        let path = book_dir.join(LOCK_FILE);
+        match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+        {
+            Ok(mut file) => {
+                write!(file, "{}", std::process::id())?;
+                Ok(Some(BookLock { path }))
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl Drop for BookLock {
+    fn drop(&mut self) {
+        fs::remove_file(&self.path).ok();
+    }
+}
+
+/// Wait for every page task, returning the first error any of them hit.
+async fn join_pages(
+    handles: &mut Vec<tokio::task::JoinHandle<Result<(), EdukaError>>>,
+) -> Result<(), EdukaError> {
+    let mut result = Ok(());
+    for handle in handles.iter_mut() {
+        let page_result = handle.await.unwrap();
+        if result.is_ok() {
+            result = page_result;
+        }
+    }
+    handles.clear();
+    result
+}
+
+fn package_url(id: u64) -> reqwest::Url {
+    reqwest::Url::parse_with_params(
+        &(String::from("https://klase.eduka.lt/api/authenticated/teaching-package/")
+            + &id.to_string()),
+        [("withTeachingTools", "1")],
+    )
+    .unwrap()
+}
+
+/// Packages the account has a license for, as on its dashboard.
+const MY_PACKAGES_URL: &str = "https://klase.eduka.lt/api/authenticated/teaching-package/my";
+
+impl EdukaClient {
+    /// Every package the logged in account has a license for, with the ids of
+    /// their teaching tools but none of their metadata.
+    pub async fn my_packages(&self) -> Result<Vec<Package>, EdukaError> {
+        let url =
+            reqwest::Url::parse_with_params(MY_PACKAGES_URL, [("withTeachingTools", "1")]).unwrap();
+        let _connection = self.connection().await;
+        self.get_json(url.as_str()).await
+    }
+
+    /// Download every part of a teaching tool, each into a book directory of its
+    /// own.
+    pub async fn download_teaching_tool(
+        self: &Arc<Self>,
+        teaching_tool: &TeachingTool,
+    ) -> Result<(), EdukaError> {
+        for part in teaching_tool.parts() {
+            self.download_part(&part).await?;
+        }
+        Ok(())
+    }
+
+    /// Pages eduka changed since the book was downloaded as the manifest has it:
+    /// pages now at another url and, with `--check-updates`, pages sent with
+    /// another `ETag` now. `None` if the manifest doesn't record where the pages
+    /// came from.
+    async fn changed_pages(&self, manifest: &Manifest, book: &Book) -> Option<Vec<usize>> {
+        if manifest.sources.is_empty() {
+            return None;
+        }
+        let mut changed = vec![];
+        for (i, page) in book.page_urls.iter().enumerate() {
+            let urls = page.urls();
+            match manifest.sources.get(&i) {
+                Some(sources) if *sources != urls => {
+                    changed.push(i);
+                    continue;
+                }
+                // fetched by a version that didn't record it
+                None if i < manifest.page_count => continue,
+                None => {
+                    changed.push(i);
+                    continue;
+                }
+                Some(_) => {}
+            }
+            if !self.check_updates {
+                continue;
+            }
+            let Some(etags) = manifest.etags.get(&i) else {
+                continue;
+            };
+            for (url, etag) in urls.iter().zip(etags) {
+                let Some(etag) = etag else {
+                    continue;
+                };
+                match self.head_etag(url).await {
+                    Ok(Some(now)) if now != *etag => {
+                        changed.push(i);
+                        break;
+                    }
+                    Ok(_) => {}
+                    Err(e) => self.warn(&format!(
+                        "checking page {} of {} for updates failed {}",
+                        i, &book.title, &e
+                    )),
+                }
+            }
+        }
+        Some(changed)
+    }
+
+    /// Delete the pages of the book in `final_dir` that eduka changed since it was
+    /// downloaded, and those it doesn't have anymore, so the changed ones are
+    /// fetched again. Whether there were any.
+    async fn take_out_updated_pages(
+        &self,
+        book: &Book,
+        final_dir: &Path,
+    ) -> Result<bool, EdukaError> {
+        let Some(mut manifest) = Manifest::load(final_dir) else {
+            return Ok(false);
+        };
+        let Some(changed) = self.changed_pages(&manifest, book).await else {
+            return Ok(false);
+        };
+        let removed: Vec<usize> = (book.page_urls.len()..manifest.page_count).collect();
+        if changed.is_empty() && removed.is_empty() {
+            return Ok(false);
+        }
+        if images_deleted(final_dir) {
+            self.warn(&format!(
+                "{} was updated on eduka, but its page images were deleted, download it again to update it",
+                &book.title
+            ));
+            return Ok(false);
+        }
+        self.log(&format!(
+            "UPDATED {}, {} pages changed and {} removed",
+            &book.title,
+            changed.len(),
+            removed.len()
+        ));
+        for &i in changed.iter().chain(&removed) {
+            let path = final_dir.join(format!("{}.png", i));
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+            manifest.fetched.remove(&i);
+            manifest.hashes.remove(&i);
+            manifest.sources.remove(&i);
+            manifest.etags.remove(&i);
+        }
+        manifest.page_count = book.page_urls.len();
+        manifest.outputs.clear();
+        manifest.save(final_dir)?;
+        let dir_name = book.dir_name(&self.name_template);
+        self.mark_updated(&dir_name);
+        self.record_state(|state| state.reopen_book(&dir_name));
+        Ok(true)
+    }
+
+    pub(crate) async fn download_part(
+        self: &Arc<Self>,
+        teaching_tool: &TeachingTool,
+    ) -> Result<(), EdukaError> {
+        let book = &teaching_tool.book;
+        let dir_name = book.dir_name(&self.name_template);
+        let final_dir = self.output_dir.join(&dir_name);
+        // pages go into a partial directory that is only given the book's name once
+        // every page is there, so a book directory always holds a whole book
+        let book_dir = self.output_dir.join(format!("{}.partial", &dir_name));
+        if final_dir.is_dir()
+            && !book_dir.exists()
+            && self.take_out_updated_pages(book, &final_dir).await?
+        {
+            fs::rename(&final_dir, &book_dir)?;
+        }
+        let recorded_done = self
+            .state()
+            .map(|state| state.is_downloaded(book, &dir_name))
+            .transpose()
+            .unwrap_or_else(|e| {
+                self.warn(&format!("reading the download state failed {}", &e));
+                None
+            })
+            .unwrap_or(false);
+        // recorded as done, so every page needn't be read to tell
+        if recorded_done && final_dir.join(METADATA_FILE).is_file() {
+            self.log("SKIPPING");
+            return Ok(());
+        }
+        if Path::new(&book_dir).join(LOCK_FILE).exists() {
+            self.log(&format!(
+                "SKIPPING {}, another instance is downloading it (delete {} if none is)",
+                &book.title, LOCK_FILE
+            ));
+            return Ok(());
+        }
+        if final_dir.is_dir() {
+            if final_dir.join(NATIVE_PDF).is_file()
+                || images_deleted(&final_dir)
+                || count_missing_pages(&final_dir, book.page_urls.len()) == 0
+            {
+                if !final_dir.join(METADATA_FILE).is_file() {
+                    // downloaded by a version that didn't keep it
+                    write_metadata(&final_dir, teaching_tool)?;
+                }
+                // downloaded before there was a database
+                self.record_state(|state| {
+                    state.start_book(book, &dir_name)?;
+                    state.finish_book(&dir_name)
+                });
+                self.log("SKIPPING");
+                return Ok(());
+            }
+            // left incomplete by a version that downloaded in place
+            if !book_dir.exists() {
+                fs::rename(&final_dir, &book_dir)?;
+            }
+        }
+        if book.native_downloadable {
+            match self.download_native_pdf(teaching_tool, &book_dir).await {
+                Ok(false) => {
+                    self.log(&format!(
+                        "SKIPPING {}, another instance started downloading it",
+                        &book.title
+                    ));
+                    return Ok(());
+                }
+                Ok(true) => {
+                    fs::rename(&book_dir, &final_dir)?;
+                    self.record_state(|state| {
+                        state.start_book(book, &dir_name)?;
+                        state.finish_book(&dir_name)
+                    });
+                    self.log(&format!(
+                        "SUCCESSFULLY DOWNLOADED THE PUBLISHER'S PDF OF {}",
+                        &book.title
+                    ));
+                    self.emit(Event::BookDone {
+                        id: book.id,
+                        title: &book.title,
+                    });
+                    return Ok(());
+                }
+                Err(EdukaError::NotLicensed) => return Err(EdukaError::NotLicensed),
+                Err(e) => self.warn(&format!(
+                    "downloading the publisher's PDF of {} failed {}, downloading the pages instead",
+                    &book.title, &e
+                )),
+            }
+        }
+        let manifest = match Manifest::load(Path::new(&book_dir)) {
+            Some(manifest) if manifest.page_count != book.page_urls.len() => {
+                self.log(&format!(
+                    "{} now has {} pages instead of {}, checking every page again",
+                    &book.title,
+                    book.page_urls.len(),
+                    manifest.page_count
+                ));
+                None
+            }
+            manifest => manifest,
+        };
+        let missing_pages: Vec<usize> = (0..book.page_urls.len())
+            .filter(|&i| {
+                let path = Path::new(&book_dir).join(format!("{}.png", i));
+                manifest
+                    .as_ref()
+                    .map(|manifest| {
+                        !manifest.fetched.contains(&i) || !matches_manifest(manifest, i, &path)
+                    })
+                    .unwrap_or(false)
+                    || !is_complete_page(&path)
+            })
+            .collect();
+        if Path::new(&book_dir).is_dir() {
+            // skip already downloaded books
+            if missing_pages.is_empty() {
+                self.log("SKIPPING");
+                return Ok(());
+            }
+            self.log(&format!(
+                "RESUMING {}, {} pages missing",
+                &book.title,
+                missing_pages.len()
+            ));
+        }
+        fs::create_dir_all(&book_dir)?;
+        let Some(lock) = BookLock::acquire(Path::new(&book_dir))? else {
+            self.log(&format!(
+                "SKIPPING {}, another instance started downloading it",
+                &book.title
+            ));
+            return Ok(());
+        };
+        write_metadata(&book_dir, teaching_tool)?;
+        self.record_state(|state| {
+            state.start_book(book, &dir_name)?;
+            // pages fetched before there was a database
+            let Some(manifest) = &manifest else {
+                return Ok(());
+            };
+            for (&page, hash) in &manifest.hashes {
+                if !missing_pages.contains(&page) {
+                    state.record_page(&dir_name, page, hash)?;
+                }
+            }
+            Ok(())
+        });
+        let manifest = Manifest {
+            page_count: book.page_urls.len(),
+            fetched: (0..book.page_urls.len())
+                .filter(|i| !missing_pages.contains(i))
+                .collect(),
+            hashes: kept_pages(
+                manifest.as_ref().map(|manifest| &manifest.hashes),
+                &missing_pages,
+            ),
+            // the pages they were assembled of are fetched again
+            outputs: BTreeMap::new(),
+            sources: kept_pages(
+                manifest.as_ref().map(|manifest| &manifest.sources),
+                &missing_pages,
+            ),
+            etags: kept_pages(
+                manifest.as_ref().map(|manifest| &manifest.etags),
+                &missing_pages,
+            ),
+        };
+        manifest.save(Path::new(&book_dir))?;
+        let manifest = Arc::new(Mutex::new(manifest));
+
+        self.warm_up_connection(book.id).await;
+
+        let progress = self.progress.add(progress_bar(
+            book.page_urls.len(),
+            book.page_urls.len() - missing_pages.len(),
+            book.title.clone(),
+        ));
+
+        self.emit(Event::BookStarted {
+            id: book.id,
+            title: &book.title,
+            pages: book.page_urls.len(),
+            done: book.page_urls.len() - missing_pages.len(),
+        });
+        let book_id = book.id;
+        // a new page starts as soon as any page finishes, up to --jobs at once
+        let page_slots = Arc::new(Semaphore::new(self.jobs));
+        let mut handles: Vec<tokio::task::JoinHandle<Result<(), EdukaError>>> = Vec::new();
+        for &i in &missing_pages {
+            let cl_clone = self.clone();
+            let dir_name = dir_name.clone();
+            let p_clone = book.page_urls[i].clone();
+            let manifest = manifest.clone();
+            let page_slots = page_slots.clone();
+            let progress = progress.clone();
+
+            handles.push(tokio::spawn(async move {
+                // closed once a page failed, so the pages still waiting don't start
+                let Ok(_slot) = page_slots.acquire().await else {
+                    return Ok(());
+                };
+                if cl_clone.is_cancelled() {
+                    return Ok(());
+                }
+                let result = save_page_and_record(
+                    cl_clone, &dir_name, &p_clone, i, book_id, &manifest, &progress,
+                )
+                .await;
+                if result.is_err() {
+                    page_slots.close();
+                }
+                result
+            }));
+        }
+        let mut result = join_pages(&mut handles).await;
+        if result.is_ok() && self.is_cancelled() {
+            result = Err(EdukaError::Interrupted);
+        }
+        if result.is_ok() {
+            progress.finish();
+        } else {
+            progress.abandon();
+        }
+        // the manifest keeps what was fetched, so running again resumes the book
+        result?;
+        let pages = count_missing_pages(&book_dir, book.page_urls.len());
+        if pages > 0 {
+            return Err(EdukaError::IncompleteDownload { pages });
+        }
+        drop(lock);
+        fs::rename(&book_dir, &final_dir)?;
+        self.record_state(|state| state.finish_book(&dir_name));
+        self.log(&format!("SUCCESSFULLY DOWNLOADED BOOK {}", &book.title));
+        self.emit(Event::BookDone {
+            id: book.id,
+            title: &book.title,
+        });
+        Ok(())
+    }
+
+    /// Fetch the publisher's PDF of a natively downloadable book into its book
+    /// directory, `false` if another instance is already at it. Anything but a
+    /// PDF in the response is an error, so the caller can fall back to the page
+    /// images.
+    async fn download_native_pdf(
+        &self,
+        teaching_tool: &TeachingTool,
+        book_dir: &Path,
+    ) -> Result<bool, EdukaError> {
+        fs::create_dir_all(book_dir)?;
+        let Some(_lock) = BookLock::acquire(book_dir)? else {
+            return Ok(false);
+        };
+        write_metadata(book_dir, teaching_tool)?;
+        let pdf = {
+            let _connection = self.connection().await;
+            let response = self
+                .get(&(String::from(NATIVE_DOWNLOAD_URL) + &teaching_tool.id.to_string()))
+                .await?
+                .error_for_status()?;
+            self.read_body(response).await?
+        };
+        if !pdf.starts_with(b"%PDF") {
+            return Err(EdukaError::UnexpectedResponse);
+        }
+        fs::write(book_dir.join(NATIVE_PDF), &pdf)?;
+        Ok(true)
+    }
+
+    /// Whether `id` is the id of a teaching package rather than of a single
+    /// teaching tool, as both are just numbers.
+    pub async fn is_package(&self, id: u64) -> Result<bool, EdukaError> {
+        let _connection = self.connection().await;
+        let response = self.get(package_url(id).as_str()).await?;
+        if !response.status().is_success() {
+            return Ok(false);
+        }
+        Ok(serde_json::from_str::<Package>(&response.text().await?).is_ok())
+    }
+
+    /// Download every teaching tool of a package. A book that fails doesn't stop
+    /// the others; the package comes back with the books that were downloaded,
+    /// next to the ones that failed and why. Books the account isn't licensed for
+    /// are skipped, failing with `NotLicensed`.
+    pub async fn download_package(
+        self: &Arc<Self>,
+        id: u64,
+        metadata_concurrency: usize,
+    ) -> Result<(Package, Vec<(TeachingTool, EdukaError)>), EdukaError> {
+        let url = package_url(id);
+        let mut package: Package = {
+            let _connection = self.connection().await;
+            self.get_json(url.as_str()).await?
+        };
+        let mut failed = vec![];
+        let mut teaching_tools = vec![];
+        // metadata is latency bound, so it is fetched for all tools at once before
+        // any pages are, still within the global connection cap
+        let metadata_slots = Arc::new(Semaphore::new(metadata_concurrency));
+        let mut handles = vec![];
+        for mut teaching_tool in package.teaching_tools.drain(..) {
+            // kept in the metadata.json of every book of the package
+            teaching_tool.authors = package.authors.clone();
+            teaching_tool.publishing_house = package.publishing_house.clone();
+            let client = self.clone();
+            let metadata_slots = metadata_slots.clone();
+            handles.push(tokio::spawn(async move {
+                let _slot = metadata_slots.acquire().await.unwrap();
+                let result = client.fill_teaching_tool_metadata(&mut teaching_tool).await;
+                (teaching_tool, result)
+            }));
+        }
+        for handle in handles {
+            let (teaching_tool, result) = handle.await.unwrap();
+            match result {
+                Ok(()) => teaching_tools.push(teaching_tool),
+                Err(EdukaError::NotLicensed) => {
+                    self.log(&format!(
+                        "skipping teaching tool {}, not licensed for this account",
+                        &teaching_tool.id
+                    ));
+                    failed.push((teaching_tool, EdukaError::NotLicensed));
+                }
+                Err(e) => failed.push((teaching_tool, e)),
+            }
+        }
+        let progress = self.progress.add(progress_bar(
+            teaching_tools.len(),
+            0,
+            format!("package {}", id),
+        ));
+        // a new book starts as soon as any book finishes, up to --book-jobs at once
+        let book_slots = Arc::new(Semaphore::new(self.book_jobs));
+        let mut handles = vec![];
+        for teaching_tool in teaching_tools {
+            let client = self.clone();
+            let book_slots = book_slots.clone();
+            let progress = progress.clone();
+            handles.push(tokio::spawn(async move {
+                let _slot = book_slots.acquire().await.unwrap();
+                let result = if client.is_cancelled() {
+                    Err(EdukaError::Interrupted)
+                } else {
+                    client.download_teaching_tool(&teaching_tool).await
+                };
+                progress.inc(1);
+                (teaching_tool, result)
+            }));
+        }
+        let mut interrupted = false;
+        for handle in handles {
+            let (teaching_tool, result) = handle.await.unwrap();
+            match result {
+                Ok(()) => package.teaching_tools.push(teaching_tool),
+                Err(EdukaError::NotLicensed) => {
+                    self.log(&format!(
+                        "skipping {}, not licensed for this account",
+                        &teaching_tool.book.title
+                    ));
+                    failed.push((teaching_tool, EdukaError::NotLicensed));
+                }
+                Err(EdukaError::Interrupted) => interrupted = true,
+                Err(e) => {
+                    self.warn(&format!(
+                        "downloading {} failed {}",
+                        &teaching_tool.book.title, &e
+                    ));
+                    failed.push((teaching_tool, e));
+                }
+            }
+        }
+        if interrupted {
+            progress.abandon();
+            return Err(EdukaError::Interrupted);
+        }
+        if failed.is_empty() {
+            progress.finish();
+        } else {
+            progress.abandon();
+        }
+        Ok((package, failed))
+    }
+
+    /// Download the same sample of a book's pages at increasing concurrency, the
+    /// way a download fetches them, retries and logging in again included, and
+    /// measure the throughput and errors of each level.
+    pub async fn benchmark(
+        self: &Arc<Self>,
+        teaching_tool_id: u64,
+    ) -> Result<Vec<BenchmarkLevel>, EdukaError> {
+        let mut teaching_tool = TeachingTool::new(teaching_tool_id);
+        self.fill_teaching_tool_metadata(&mut teaching_tool).await?;
+        let sample: Vec<String> = teaching_tool
+            .book
+            .page_urls
+            .iter()
+            .flat_map(PageSource::urls)
+            .take(BENCHMARK_SAMPLE)
+            .collect();
+        if sample.is_empty() {
+            return Err(EdukaError::UnexpectedResponse);
+        }
+        let sample_dir = self.output_dir.join(BENCHMARK_DIR);
+        fs::create_dir_all(&sample_dir)?;
+        // levels above the global cap would only measure waiting for it
+        let max_connections = self.connections.available_permits();
+        let mut levels = vec![];
+        for concurrency in BENCHMARK_LEVELS
+            .into_iter()
+            .filter(|level| *level <= max_connections)
+        {
+            let slots = Arc::new(Semaphore::new(concurrency));
+            let started = std::time::Instant::now();
+            let mut handles = vec![];
+            for (page_number, page_url) in sample.iter().enumerate() {
+                let client = self.clone();
+                let slots = slots.clone();
+                let page_url = page_url.clone();
+                let path = sample_dir.join(format!("{}.png", page_number));
+                handles.push(tokio::spawn(async move {
+                    let _slot = slots.acquire().await.unwrap();
+                    fetch_page_image(&client, &page_url, page_number as u64, &path).await?;
+                    Ok::<u64, EdukaError>(fs::metadata(&path)?.len())
+                }));
+            }
+            let mut level = BenchmarkLevel {
+                concurrency,
+                bytes: 0,
+                seconds: 0.0,
+                errors: 0,
+            };
+            for handle in handles {
+                match handle.await.unwrap() {
+                    Ok(page_bytes) => level.bytes += page_bytes,
+                    Err(_) => level.errors += 1,
+                }
+            }
+            level.seconds = started.elapsed().as_secs_f64();
+            levels.push(level);
+        }
+        fs::remove_dir_all(&sample_dir)?;
+        Ok(levels)
+    }
+}
+
+/// How one concurrency level of `benchmark` did.
+#[derive(Debug, Clone)]
+pub struct BenchmarkLevel {
+    pub concurrency: usize,
+    pub bytes: u64,
+    pub seconds: f64,
+    /// Pages that failed after every retry
+    pub errors: usize,
+}
+
+impl BenchmarkLevel {
+    /// KiB per second.
+    pub fn throughput(&self) -> f64 {
+        self.bytes as f64 / 1024.0 / self.seconds
+    }
+}
+
+/// The fastest concurrency level that had no errors.
+pub fn recommended_concurrency(levels: &[BenchmarkLevel]) -> Option<usize> {
+    levels
+        .iter()
+        .filter(|level| level.errors == 0)
+        .max_by(|a, b| a.throughput().total_cmp(&b.throughput()))
+        .map(|level| level.concurrency)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn png() -> Vec<u8> {
+        let mut bytes = vec![];
+        image::RgbImage::new(4, 4)
+            .write_to(&mut io::Cursor::new(&mut bytes), image::ImageFormat::Png)
+            .unwrap();
+        bytes
+    }
+
+    #[test]
+    fn fastest_level_without_errors_is_recommended() {
+        let level = |concurrency, seconds, errors| BenchmarkLevel {
+            concurrency,
+            bytes: 1024 * 1024,
+            seconds,
+            errors,
+        };
+        let levels = [level(1, 4.0, 0), level(2, 2.0, 0), level(4, 1.0, 1)];
+        assert_eq!(recommended_concurrency(&levels), Some(2));
+        assert_eq!(recommended_concurrency(&levels[2..]), None);
+    }
+
+    #[test]
+    fn zero_byte_and_cut_off_pages_are_missing() {
+        let book_dir = std::env::temp_dir().join(format!("eduka-resume-{}", std::process::id()));
+        fs::create_dir_all(&book_dir).unwrap();
+        let png = png();
+        fs::write(book_dir.join("0.png"), &png).unwrap();
+        fs::write(book_dir.join("1.png"), []).unwrap();
+        fs::write(book_dir.join("2.png"), &png[..png.len() - 4]).unwrap();
+
+        assert!(is_complete_page(&book_dir.join("0.png")));
+        assert!(!is_complete_page(&book_dir.join("1.png")));
+        assert!(!is_complete_page(&book_dir.join("2.png")));
+        assert!(!is_complete_page(&book_dir.join("3.png")));
+        assert_eq!(count_missing_pages(&book_dir, 4), 3);
+        fs::remove_dir_all(&book_dir).unwrap();
+    }
+}
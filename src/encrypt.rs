@@ -0,0 +1,147 @@
+use crate::EdukaError;
+use lopdf::{dictionary, Object, StringFormat};
+use md5::{Digest, Md5};
+
+/// What every password is padded or cut to 32 bytes with.
+const PADDING: [u8; 32] = [
+    0x28, 0xBF, 0x4E, 0x5E, 0x4E, 0x75, 0x8A, 0x41, 0x64, 0x00, 0x4E, 0x56, 0xFF, 0xFA, 0x01, 0x08,
+    0x2E, 0x2E, 0x00, 0xB6, 0xD0, 0x68, 0x3E, 0x80, 0x2F, 0x0C, 0xA9, 0xFE, 0x64, 0x53, 0x69, 0x7A,
+];
+
+/// Permissions of the user password: printing and reading the text out for
+/// accessibility, but not changing or copying out of the PDF.
+const PERMISSIONS: i32 = 0xFFFF_FAC4_u32 as i32;
+
+/// Length of the key in bytes, 128 bits.
+const KEY_LENGTH: usize = 16;
+
+fn rc4(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut state: Vec<u8> = (0..=255).collect();
+    let mut j = 0u8;
+    for i in 0..256 {
+        j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+        state.swap(i, j as usize);
+    }
+    let (mut i, mut j) = (0u8, 0u8);
+    data.iter()
+        .map(|byte| {
+            i = i.wrapping_add(1);
+            j = j.wrapping_add(state[i as usize]);
+            state.swap(i as usize, j as usize);
+            byte ^ state[state[i as usize].wrapping_add(state[j as usize]) as usize]
+        })
+        .collect()
+}
+
+/// RC4 of `data` with `key` and then 19 more times with every byte of the key
+/// xored with the round, as the owner and user entries are made.
+fn rc4_rounds(key: &[u8], data: &[u8]) -> Vec<u8> {
+    (1..=19u8).fold(rc4(key, data), |data, round| {
+        let key: Vec<u8> = key.iter().map(|byte| byte ^ round).collect();
+        rc4(&key, &data)
+    })
+}
+
+/// A password in PDFDocEncoding, padded or cut to 32 bytes.
+fn pad(password: &str) -> Vec<u8> {
+    password
+        .chars()
+        .map(|c| u8::try_from(c).unwrap_or(b'?'))
+        .chain(PADDING)
+        .take(32)
+        .collect()
+}
+
+/// MD5 of `data` and then 50 more times of the key length of it.
+fn md5_rounds(data: &[u8]) -> Vec<u8> {
+    (0..50).fold(Md5::digest(data)[..KEY_LENGTH].to_vec(), |hash, _| {
+        Md5::digest(&hash)[..KEY_LENGTH].to_vec()
+    })
+}
+
+/// Encrypt every string and stream of `object` with `key`.
+fn encrypt_object(object: &mut Object, key: &[u8]) {
+    match object {
+        Object::String(bytes, _) => *bytes = rc4(key, bytes),
+        Object::Array(array) => {
+            for object in array {
+                encrypt_object(object, key);
+            }
+        }
+        Object::Dictionary(dictionary) => {
+            for (_, object) in dictionary.iter_mut() {
+                encrypt_object(object, key);
+            }
+        }
+        Object::Stream(stream) => {
+            for (_, object) in stream.dict.iter_mut() {
+                encrypt_object(object, key);
+            }
+            stream.set_content(rc4(key, &stream.content));
+        }
+        _ => {}
+    }
+}
+
+/// Encrypt `doc` with 128 bit RC4, so it only opens with `user_password`.
+/// Without an `owner_password` one is made up, leaving nobody able to lift the
+/// restrictions.
+pub(crate) fn encrypt_pdf(
+    doc: &mut lopdf::Document,
+    user_password: &str,
+    owner_password: Option<&str>,
+) -> Result<(), EdukaError> {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    let file_id = match doc.trailer.get(b"ID").and_then(Object::as_array) {
+        Ok(id) => id
+            .first()
+            .and_then(|id| id.as_str().ok())
+            .map(<[u8]>::to_vec)
+            .unwrap_or_default(),
+        Err(_) => {
+            let id = Md5::digest(format!("{:?} {}", now, user_password)).to_vec();
+            let id_object = Object::String(id.clone(), StringFormat::Hexadecimal);
+            doc.trailer.set("ID", vec![id_object.clone(), id_object]);
+            id
+        }
+    };
+    let owner_password = match owner_password {
+        Some(password) => pad(password),
+        None => pad(&format!("{:x}", Md5::digest(format!("{:?}", now)))),
+    };
+
+    let owner_key = md5_rounds(&owner_password);
+    let owner = rc4_rounds(&owner_key, &pad(user_password));
+    let key = md5_rounds(
+        &[
+            pad(user_password),
+            owner.clone(),
+            PERMISSIONS.to_le_bytes().to_vec(),
+            file_id.clone(),
+        ]
+        .concat(),
+    );
+    let mut user = rc4_rounds(&key, &Md5::digest([&PADDING[..], &file_id].concat()));
+    // only the first 16 bytes are checked
+    user.resize(32, 0);
+
+    for (&id, object) in doc.objects.iter_mut() {
+        let object_key =
+            Md5::digest([&key[..], &id.0.to_le_bytes()[..3], &id.1.to_le_bytes()[..2]].concat());
+        // n + 5 bytes of it, all of it for a 16 byte key
+        encrypt_object(object, &object_key);
+    }
+    let encrypt = doc.add_object(dictionary! {
+        "Filter" => "Standard",
+        "V" => 2,
+        "R" => 3,
+        "Length" => (KEY_LENGTH * 8) as i64,
+        "O" => Object::String(owner, StringFormat::Hexadecimal),
+        "U" => Object::String(user, StringFormat::Hexadecimal),
+        "P" => PERMISSIONS,
+    });
+    doc.trailer.set("Encrypt", encrypt);
+    Ok(())
+}
@@ -0,0 +1,69 @@
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum EdukaError {
+    #[error("an unknown error occured")]
+    Unknown,
+    #[error("JSON input was invalid")]
+    JSONError(#[from] serde_json::Error),
+    #[error("a Reqwest failed")]
+    InternetError(#[from] reqwest::Error),
+    #[error("the position number returned by eduka for a chapter does not match reality")]
+    PositionOffsetError,
+    #[error("the table of contents file is invalid, {0}")]
+    InvalidToc(String),
+    #[error("the data sent by eduka does not match any known technologies")]
+    UnexpectedResponse,
+    #[error("the data sent by eduka is missing the {0} field")]
+    MissingField(String),
+    #[error("an error occured when manipulating a pdf")]
+    PDFError(#[from] lopdf::Error),
+    #[error("an I/O error occured")]
+    IOError(#[from] std::io::Error),
+    #[error("reading or writing a zip archive failed")]
+    ZipError(#[from] zip::result::ZipError),
+    #[error("reading or writing a CSV file failed")]
+    CsvError(#[from] csv::Error),
+    #[error("reading or writing the state database failed, {0}")]
+    StateError(#[from] rusqlite::Error),
+    #[error("reading or writing the search index failed, {0}")]
+    IndexError(#[from] tantivy::TantivyError),
+    #[error("the search query is invalid, {0}")]
+    QueryError(#[from] tantivy::query::QueryParserError),
+    #[error("the library has no search index yet, run `index` first")]
+    NoIndex,
+    #[error("input is required but stdin is not a terminal")]
+    NonInteractive,
+    #[error("eduka did not accept the login")]
+    LoginFailed,
+    #[error("not licensed for this account")]
+    NotLicensed,
+    #[error("eduka has no teaching tool with this id")]
+    NoTeachingTool,
+    #[error("the book's terms of use have to be accepted first, pass --accept-terms")]
+    TermsNotAccepted,
+    #[error("an error occured when processing an image")]
+    ImageError(#[from] image::ImageError),
+    #[error("page {page} could not be fetched after {attempts} attempts")]
+    PageFailed { page: u64, attempts: u32 },
+    #[error("{pages} pages are still missing or incomplete")]
+    IncompleteDownload { pages: usize },
+    #[error("the page ended after {received} of {expected} bytes")]
+    TruncatedPage { received: u64, expected: u64 },
+    #[error("interrupted, run again to resume")]
+    Interrupted,
+    #[error("tesseract failed to recognize the text of a page")]
+    OCRFailed,
+    #[error("the book directory can't be repaired, {0}")]
+    NotRepairable(&'static str),
+    #[error("the pages of this book are PDFs, which can't be downloaded as page images yet")]
+    PdfPages,
+    #[error(
+        "the page images were deleted after preparing, download the book again to prepare it anew"
+    )]
+    ImagesDeleted,
+    #[error("the book has no pages to assemble")]
+    NoPages,
+    #[error("an SVG page could not be rendered")]
+    SvgError,
+}
@@ -0,0 +1,58 @@
+use serde::Serialize;
+
+/// Progress of a run as a line of JSON on stdout, for scripts and GUIs driving
+/// the downloader with `--json`.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    LoginOk {
+        username: &'a str,
+    },
+    /// Pages of a book started downloading, `done` of them already on disk
+    BookStarted {
+        id: u64,
+        title: &'a str,
+        pages: usize,
+        done: usize,
+    },
+    PageDone {
+        id: u64,
+        page: usize,
+    },
+    BookDone {
+        id: u64,
+        title: &'a str,
+    },
+    PrepareDone {
+        id: u64,
+        title: &'a str,
+    },
+    /// Book directory found by `list`
+    LocalBook {
+        id: u64,
+        title: &'a str,
+        pages: usize,
+        /// Bytes on disk
+        size: u64,
+        prepared: bool,
+        /// Unix time it was last downloaded
+        downloaded: Option<u64>,
+    },
+    /// Page image URLs of a book printed by `page-urls`
+    PageUrls {
+        id: u64,
+        title: &'a str,
+        page_urls: Vec<String>,
+    },
+    Error {
+        /// What failed, an id or url as it was given when there is one
+        subject: &'a str,
+        message: String,
+    },
+}
+
+impl Event<'_> {
+    pub fn print(&self) {
+        println!("{}", serde_json::to_string(self).unwrap());
+    }
+}
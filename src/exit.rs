@@ -0,0 +1,60 @@
+//! Exit codes of the commands, so scripts can tell why a run didn't succeed.
+
+use eduka::EdukaError;
+use std::process::ExitCode;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exit {
+    Success = 0,
+    /// Anything not covered by a more specific code
+    Failure = 1,
+    /// Invalid arguments, config or input files, also the same as clap's
+    InvalidArguments = 2,
+    /// Logging in failed or the account isn't allowed to get the book
+    AuthFailed = 3,
+    /// eduka couldn't be reached or didn't answer properly
+    NetworkFailed = 4,
+    /// Some books or pages were downloaded, but not all of them
+    PartialDownload = 5,
+    /// The pages were downloaded but turning them into a PDF failed
+    PrepareFailed = 6,
+    /// Stopped by Ctrl-C or SIGTERM, the same as a shell reports
+    Interrupted = 130,
+}
+
+impl Exit {
+    /// Exit code for a command that ended with `e`.
+    pub fn of(e: &EdukaError) -> Exit {
+        match e {
+            EdukaError::LoginFailed | EdukaError::NotLicensed | EdukaError::TermsNotAccepted => {
+                Exit::AuthFailed
+            }
+            EdukaError::InternetError(_)
+            | EdukaError::UnexpectedResponse
+            | EdukaError::MissingField(_)
+            | EdukaError::PageFailed { .. }
+            | EdukaError::TruncatedPage { .. } => Exit::NetworkFailed,
+            EdukaError::IncompleteDownload { .. } => Exit::PartialDownload,
+            EdukaError::PositionOffsetError
+            | EdukaError::InvalidToc(_)
+            | EdukaError::PDFError(_)
+            | EdukaError::ImageError(_)
+            | EdukaError::OCRFailed
+            | EdukaError::SvgError
+            | EdukaError::ImagesDeleted
+            | EdukaError::ZipError(_)
+            | EdukaError::NoPages => Exit::PrepareFailed,
+            EdukaError::NonInteractive | EdukaError::CsvError(_) | EdukaError::QueryError(_) => {
+                Exit::InvalidArguments
+            }
+            EdukaError::Interrupted => Exit::Interrupted,
+            _ => Exit::Failure,
+        }
+    }
+}
+
+impl From<Exit> for ExitCode {
+    fn from(exit: Exit) -> ExitCode {
+        ExitCode::from(exit as u8)
+    }
+}
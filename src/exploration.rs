@@ -0,0 +1,75 @@
+//! What exploration went through, kept between runs so it picks up where it
+//! stopped instead of asking about the same ids again.
+
+use eduka::{EdukaError, Explored, StateDb};
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::PathBuf;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ExplorationState {
+    /// Id exploration goes on from when no `--start` is given
+    pub next_id: u64,
+    /// Whether each teaching tool found was to be downloaded, by id
+    pub answers: BTreeMap<u64, bool>,
+    /// Ids tried that had no teaching tool
+    pub missing: BTreeSet<u64>,
+    /// Ids answered yes that haven't been downloaded yet
+    pub selected: BTreeSet<u64>,
+}
+
+/// Where versions before the state database kept the exploration state.
+fn legacy_state_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("eduka-downloader").join("exploration.json"))
+}
+
+impl ExplorationState {
+    /// The state recorded in `db`. Until anything is, the one an earlier version
+    /// saved, or else a fresh one.
+    pub fn load(db: &StateDb) -> Result<ExplorationState, EdukaError> {
+        let (next_id, explored) = db.exploration()?;
+        if next_id == 0 && explored.is_empty() {
+            return Ok(legacy_state_path()
+                .and_then(|path| fs::read_to_string(path).ok())
+                .and_then(|state| serde_json::from_str(&state).ok())
+                .unwrap_or_default());
+        }
+        let mut state = ExplorationState {
+            next_id,
+            ..Default::default()
+        };
+        for (id, explored) in explored {
+            match explored {
+                Explored::Missing => {
+                    state.missing.insert(id);
+                }
+                Explored::Answered { wanted, pending } => {
+                    state.answers.insert(id, wanted);
+                    if pending {
+                        state.selected.insert(id);
+                    }
+                }
+            }
+        }
+        Ok(state)
+    }
+
+    pub fn save(&self, db: &StateDb) -> Result<(), EdukaError> {
+        let explored = self
+            .missing
+            .iter()
+            .map(|&id| (id, Explored::Missing))
+            .chain(self.answers.iter().map(|(&id, &wanted)| {
+                let pending = self.selected.contains(&id);
+                (id, Explored::Answered { wanted, pending })
+            }))
+            .collect();
+        db.save_exploration(self.next_id, &explored)
+    }
+
+    /// Whether `id` was tried before, found or not.
+    pub fn tried(&self, id: u64) -> bool {
+        self.answers.contains_key(&id) || self.missing.contains(&id)
+    }
+}
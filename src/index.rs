@@ -0,0 +1,193 @@
+use crate::prepare::{chapter_pages, read_metadata};
+use crate::{local_books, EdukaError};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tantivy::collector::TopDocs;
+use tantivy::query::QueryParser;
+use tantivy::schema::{
+    Field, IndexRecordOption, Schema, TextFieldIndexing, TextOptions, Value, STORED,
+};
+use tantivy::tokenizer::{
+    AsciiFoldingFilter, LowerCaser, RemoveLongFilter, SimpleTokenizer, TextAnalyzer,
+};
+use tantivy::{doc, Index, SnippetGenerator, TantivyDocument};
+use tracing::{info, warn};
+
+/// Directory under the output directory the search index is kept in.
+const INDEX_DIR: &str = ".index";
+
+/// Tokenizer of the text, folding Lithuanian letters to ASCII so `fotosinteze`
+/// finds `fotosintezė` too.
+const TOKENIZER: &str = "folded";
+
+/// Memory the index writer may use.
+const WRITER_MEMORY: usize = 50_000_000;
+
+/// Page of a book the text search found.
+#[derive(Debug, Clone)]
+pub struct TextHit {
+    pub book_id: u64,
+    pub title: String,
+    /// Top-level chapter the page is in, empty for books without chapters
+    pub chapter: String,
+    /// Page of the PDF, counted from 1
+    pub page: u64,
+    /// Text around the words found
+    pub snippet: String,
+    pub path: PathBuf,
+}
+
+struct Fields {
+    book_id: Field,
+    title: Field,
+    chapter: Field,
+    page: Field,
+    text: Field,
+    path: Field,
+}
+
+fn schema() -> (Schema, Fields) {
+    let mut builder = Schema::builder();
+    let text_options = TextOptions::default()
+        .set_indexing_options(
+            TextFieldIndexing::default()
+                .set_tokenizer(TOKENIZER)
+                .set_index_option(IndexRecordOption::WithFreqsAndPositions),
+        )
+        .set_stored();
+    let fields = Fields {
+        book_id: builder.add_u64_field("book_id", STORED),
+        title: builder.add_text_field("title", STORED),
+        chapter: builder.add_text_field("chapter", STORED),
+        page: builder.add_u64_field("page", STORED),
+        text: builder.add_text_field("text", text_options),
+        path: builder.add_text_field("path", STORED),
+    };
+    (builder.build(), fields)
+}
+
+fn register_tokenizer(index: &Index) {
+    let analyzer = TextAnalyzer::builder(SimpleTokenizer::default())
+        .filter(RemoveLongFilter::limit(40))
+        .filter(LowerCaser)
+        .filter(AsciiFoldingFilter)
+        .build();
+    index.tokenizers().register(TOKENIZER, analyzer);
+}
+
+/// OCR text sidecars in `book_dir`, the `.txt` files named like a PDF next to
+/// them.
+fn text_sidecars(book_dir: &Path) -> Result<Vec<PathBuf>, EdukaError> {
+    let mut sidecars: Vec<PathBuf> = fs::read_dir(book_dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().map(|e| e == "txt").unwrap_or(false))
+        .filter(|path| path.with_extension("pdf").is_file())
+        .collect();
+    sidecars.sort();
+    Ok(sidecars)
+}
+
+/// Index the OCR text of every prepared book under `root` page by page,
+/// replacing the index made before. Returns how many books were indexed.
+pub fn build_index(root: &Path) -> Result<usize, EdukaError> {
+    let index_dir = root.join(INDEX_DIR);
+    if index_dir.is_dir() {
+        fs::remove_dir_all(&index_dir)?;
+    }
+    fs::create_dir_all(&index_dir)?;
+    let (schema, fields) = schema();
+    let index = Index::create_in_dir(&index_dir, schema)?;
+    register_tokenizer(&index);
+    let mut writer = index.writer::<TantivyDocument>(WRITER_MEMORY)?;
+    let mut indexed = 0;
+    for book in local_books(root)? {
+        let sidecars = text_sidecars(&book.path)?;
+        if sidecars.is_empty() {
+            if book.prepared {
+                warn!(
+                    "{} has no OCR text to index, prepare it with OCR",
+                    &book.title
+                );
+            }
+            continue;
+        }
+        let metadata = read_metadata(&book.path).ok();
+        for sidecar in sidecars {
+            let text = fs::read_to_string(&sidecar)?;
+            // pages are separated by form feeds
+            let pages: Vec<&str> = text.split('\x0c').collect();
+            let chapters = metadata
+                .as_ref()
+                .map(|teaching_tool| chapter_pages(&teaching_tool.book, pages.len()))
+                .unwrap_or_default();
+            for (n, page_text) in pages.iter().enumerate() {
+                let page = n + 1;
+                let chapter = chapters
+                    .iter()
+                    .rev()
+                    .find(|(_, pdf_pages)| pdf_pages.start <= page)
+                    .map(|(chapter, _)| chapter.title.as_str())
+                    .unwrap_or_default();
+                writer.add_document(doc!(
+                    fields.book_id => book.id,
+                    fields.title => book.title.as_str(),
+                    fields.chapter => chapter,
+                    fields.page => page as u64,
+                    fields.text => *page_text,
+                    fields.path => sidecar.with_extension("pdf").to_string_lossy().into_owned(),
+                ))?;
+            }
+        }
+        info!("indexed {}", &book.title);
+        indexed += 1;
+    }
+    writer.commit()?;
+    Ok(indexed)
+}
+
+/// Pages of the books under `root` whose OCR text matches `query`, best first,
+/// from the index `build_index` made.
+pub fn search_index(root: &Path, query: &str, limit: usize) -> Result<Vec<TextHit>, EdukaError> {
+    let index_dir = root.join(INDEX_DIR);
+    if !index_dir.is_dir() {
+        return Err(EdukaError::NoIndex);
+    }
+    let index = Index::open_in_dir(&index_dir)?;
+    register_tokenizer(&index);
+    let (_, fields) = schema();
+    let searcher = index.reader()?.searcher();
+    let query = QueryParser::for_index(&index, vec![fields.text]).parse_query(query)?;
+    let snippets = SnippetGenerator::create(&searcher, &*query, fields.text)?;
+    let mut hits = vec![];
+    for (_, address) in searcher.search(&query, &TopDocs::with_limit(limit))? {
+        let document: TantivyDocument = searcher.doc(address)?;
+        let text_of = |field| {
+            document
+                .get_first(field)
+                .and_then(|value| value.as_str())
+                .map(String::from)
+                .unwrap_or_default()
+        };
+        let number_of = |field| {
+            document
+                .get_first(field)
+                .and_then(|value| value.as_u64())
+                .unwrap_or_default()
+        };
+        hits.push(TextHit {
+            book_id: number_of(fields.book_id),
+            title: text_of(fields.title),
+            chapter: text_of(fields.chapter),
+            page: number_of(fields.page),
+            snippet: snippets
+                .snippet_from_doc(&document)
+                .fragment()
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" "),
+            path: PathBuf::from(text_of(fields.path)),
+        });
+    }
+    Ok(hits)
+}
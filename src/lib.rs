@@ -0,0 +1,39 @@
+//! Downloading teaching tools from klase.eduka.lt: logging in, fetching their
+//! metadata, downloading the page images and assembling them into PDFs.
+
+mod archive;
+mod catalog;
+mod clean;
+mod client;
+mod download;
+mod encrypt;
+mod error;
+mod events;
+mod index;
+mod library;
+mod models;
+mod ocr;
+mod pdfa;
+mod prepare;
+mod state;
+mod verify;
+
+pub use catalog::{diff_catalogs, read_catalog, write_catalog, CatalogDiff, CatalogEntry};
+pub use clean::{find_leftovers, Leftover};
+pub use client::{ClientOptions, EdukaClient, Resolution};
+pub use download::{recommended_concurrency, BenchmarkLevel};
+pub use error::EdukaError;
+pub use events::Event;
+pub use index::{build_index, search_index, TextHit};
+pub use library::{local_books, LocalBook};
+pub use models::{
+    resolve_toc, Book, Bookmark, Package, PageRanges, PageSource, Part, SearchHit, TeachingTool,
+    TocEntry,
+};
+pub use prepare::{
+    prepare_book_dir, prepare_package, prepare_teaching_tool, read_metadata, rebookmark,
+    save_page_shift, ColorMode, ImageArchive, OutputFormat, PageFormat, PdfName, PrepareOptions,
+    DEFAULT_NAME_TEMPLATE,
+};
+pub use state::{Explored, StateDb};
+pub use verify::Verification;
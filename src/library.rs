@@ -0,0 +1,122 @@
+use crate::download::{MANIFEST_FILE, METADATA_FILE};
+use crate::prepare::{civil_date, read_metadata};
+use crate::EdukaError;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// Extensions of the files books are assembled into, `zst` of `.tar.zst`.
+const OUTPUT_EXTENSIONS: [&str; 5] = ["pdf", "cbz", "epub", "zip", "zst"];
+
+/// A book directory found on disk.
+#[derive(Debug, Clone)]
+pub struct LocalBook {
+    pub id: u64,
+    pub title: String,
+    pub path: PathBuf,
+    /// Whether a PDF, CBZ, EPUB or page image archive has been assembled in the
+    /// directory
+    pub prepared: bool,
+    /// Pages of the book as eduka had them, or the page images in the directory
+    /// when it has no `metadata.json`
+    pub page_count: usize,
+}
+
+impl LocalBook {
+    /// Bytes of everything in the book directory.
+    pub fn size(&self) -> u64 {
+        dir_size(&self.path)
+    }
+
+    /// Unix time the book was last downloaded, when the manifest or metadata
+    /// was written.
+    pub fn downloaded(&self) -> Option<u64> {
+        [MANIFEST_FILE, METADATA_FILE]
+            .iter()
+            .find_map(|file| fs::metadata(self.path.join(file)).ok())
+            .and_then(|metadata| metadata.modified().ok())
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|since| since.as_secs())
+    }
+
+    /// `YYYY-MM-DD` of `downloaded`, in UTC.
+    pub fn downloaded_date(&self) -> Option<String> {
+        self.downloaded().map(|unix_time| {
+            let (year, month, day) = civil_date(unix_time);
+            format!("{:04}-{:02}-{:02}", year, month, day)
+        })
+    }
+}
+
+fn dir_size(dir: &Path) -> u64 {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return 0;
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| match entry.metadata() {
+            Ok(metadata) if metadata.is_dir() => dir_size(&entry.path()),
+            Ok(metadata) => metadata.len(),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+/// Book directories directly under `root`, ordered by id. They are recognized
+/// by their `metadata.json`, or else by the default `{title} ;;; {id}` name.
+/// Downloads that haven't finished yet are left out.
+pub fn local_books(root: &Path) -> Result<Vec<LocalBook>, EdukaError> {
+    let mut books = vec![];
+    for entry in fs::read_dir(root)? {
+        let path = entry?.path();
+        if !path.is_dir() {
+            continue;
+        }
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        if name.ends_with(".partial") {
+            continue;
+        }
+        let (id, title, page_count) = match read_metadata(&path) {
+            Ok(teaching_tool) => (
+                teaching_tool.book.id,
+                teaching_tool.book.title,
+                Some(teaching_tool.book.page_urls.len()),
+            ),
+            Err(_) => {
+                let Some((title, id)) = name.rsplit_once(" ;;; ") else {
+                    continue;
+                };
+                let Ok(id) = id.parse::<u64>() else {
+                    continue;
+                };
+                (id, String::from(title), None)
+            }
+        };
+        let extensions: Vec<String> = fs::read_dir(&path)?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                entry
+                    .path()
+                    .extension()
+                    .and_then(|e| e.to_str())
+                    .map(String::from)
+            })
+            .collect();
+        let prepared = extensions
+            .iter()
+            .any(|e| OUTPUT_EXTENSIONS.contains(&e.as_str()));
+        let page_count =
+            page_count.unwrap_or_else(|| extensions.iter().filter(|e| *e == "png").count());
+        books.push(LocalBook {
+            id,
+            title,
+            path,
+            prepared,
+            page_count,
+        });
+    }
+    books.sort_by_key(|book| book.id);
+    Ok(books)
+}
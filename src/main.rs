@@ -1,456 +1,1994 @@
-use clap::Parser;
-use lopdf;
-use reqwest::{self, Request};
-use serde::de::IntoDeserializer;
-use serde::{Deserialize, Serialize};
-use serde_json;
-use std::collections::HashMap;
-use std::io::Write;
-use std::path::Path;
-use std::process::Command;
+use clap::{Args, Parser, Subcommand};
+use config::config;
+use eduka::{
+    build_index, diff_catalogs, find_leftovers, local_books, prepare_book_dir,
+    prepare_teaching_tool, read_catalog, rebookmark, recommended_concurrency, search_index,
+    write_catalog, BenchmarkLevel, Bookmark, ClientOptions, ColorMode, EdukaClient, EdukaError,
+    Event, ImageArchive, LocalBook, OutputFormat, PageFormat, PageRanges, PageSource, PdfName,
+    PrepareOptions, Resolution, StateDb, TeachingTool,
+};
+use exit::Exit;
+use exploration::ExplorationState;
+use std::collections::VecDeque;
+use std::io::{IsTerminal, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 use std::sync::Arc;
+use std::time::Duration;
 use std::{fs, io};
-use thiserror::Error;
-use tokio::io::AsyncWriteExt;
-use unidecode::unidecode;
-use url;
+use tracing::{error, info, warn};
 
-#[derive(Deserialize)]
-struct IsDownloadableResponse {
-    isDownloadable: bool,
+mod calibrate;
+mod config;
+mod exit;
+mod exploration;
+mod tui;
+
+/// What a `download` argument refers to.
+enum Target {
+    /// A plain id, of either a package or a teaching tool
+    Id(u64),
+    Package(u64),
+    TeachingTool(u64),
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct Bookmark {
-    title: String,
-    #[serde(default)]
-    startPage: u32,
-    #[serde(default)]
-    lessons: Vec<Bookmark>,
+/// Target of either a plain id or a url. The id of a teaching tool url follows
+/// its `teaching-tool` path segment, of a package url it is the last segment.
+fn target(book: &str) -> Result<Target, String> {
+    if let Ok(id) = book.parse::<u64>() {
+        return Ok(Target::Id(id));
+    }
+    let url = url::Url::parse(book).map_err(|e| {
+        format!(
+            "skipping downloading book {} as url is invalid: {:?}",
+            book, &e
+        )
+    })?;
+    let segments: Vec<&str> = url
+        .path_segments()
+        .ok_or_else(|| format!("url {} doesn't have segments, is it a book url?", book))?
+        .collect();
+    if let Some(position) = segments.iter().position(|s| *s == "teaching-tool") {
+        return segments
+            .get(position + 1)
+            .and_then(|id| id.parse::<u64>().ok())
+            .map(Target::TeachingTool)
+            .ok_or_else(|| format!("url {} doesn't contain a teaching tool id", &url));
+    }
+    let id_str = segments
+        .iter()
+        .rev()
+        .find(|s| !s.is_empty())
+        .ok_or_else(|| format!("url {} doesn't have final segment, is it a book url?", book))?;
+    id_str
+        .parse::<u64>()
+        .map(Target::Package)
+        .map_err(|e| format!("url {} doesn't contain a book id: {:?}", &url, &e))
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct Part {
-    title: String,
+/// Entries of an `--ids-file`: an id or url per line, skipping blank lines and
+/// `#` comments. `-` reads them from stdin.
+fn read_ids_file(path: &Path) -> Result<Vec<String>, EdukaError> {
+    let text = if path == Path::new("-") {
+        io::read_to_string(io::stdin())?
+    } else {
+        fs::read_to_string(path)?
+    };
+    Ok(text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect())
 }
 
-#[derive(Deserialize, Serialize, Debug, Default, Clone)]
-struct Book {
-    #[serde(default)]
-    id: u64,
-    #[serde(default)]
-    page_shift: i64,
-    #[serde(default)]
-    native_downloadable: bool,
-    #[serde(default)]
-    #[serde(rename = "nothing")]
-    title: String,
-    #[serde(rename = "title")]
-    collection_title: String,
-    parts: Vec<Part>,
-    #[serde(default)]
-    page_urls: Vec<String>,
-    #[serde(default)]
-    bookmarks: Vec<Bookmark>,
-}
-
-#[derive(Debug, Error)]
-enum EdukaError {
-    #[error("an unknown error occured")]
-    Unknown,
-    #[error("JSON input was invalid")]
-    JSONError(#[from] serde_json::Error),
-    #[error("a Reqwest failed")]
-    InternetError(#[from] reqwest::Error),
-    #[error("the position number returned by eduka for a chapter does not match reality")]
-    PositionOffsetError,
-    #[error("the data sent by eduka does not match any known technologies")]
-    UnexpectedResponse,
-    #[error("an error occured when manipulating a pdf")]
-    PDFError(#[from] lopdf::Error),
-    #[error("an I/O error occured")]
-    IOError(#[from] std::io::Error),
-}
-
-#[derive(Serialize, Deserialize, Debug)]
-struct TeachingTool {
-    id: u64,
-    #[serde(default)]
-    book: Book,
+#[derive(Args)]
+struct Credentials {
+    /// Asked for when neither given, set in the environment nor in the config
+    #[arg(short, long, env = "EDUKA_USERNAME")]
+    username: Option<String>,
+    /// Prefer the environment variable or the prompt, as arguments end up in
+    /// shell history and are visible to other users in the process list
+    #[arg(short, long, env = "EDUKA_PASSWORD", hide_env_values = true)]
+    password: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct Package {
-    id: u64,
-    authors: String,
-    publishing_house: String,
-    teaching_tools: Vec<TeachingTool>,
-}
-
-async fn save_page_to_file(
-    client: Arc<reqwest::Client>,
-    book_dir: &str,
-    page_url: &str,
-    page_number: u64,
-) {
-    let path_str = String::from(book_dir)
-        + &String::from("/")
-        + &page_number.to_string()
-        + &String::from(".png");
-    let path = Path::new(&path_str);
-    match tokio::fs::File::create(path).await {
-        Ok(mut file) => loop {
-            if let Ok(r) = client.get(page_url).send().await {
-                let file_as_bytes = r.bytes().await.unwrap();
-                file.write_all(&file_as_bytes).await.unwrap();
-                file.flush().await.unwrap();
-                println!("SUCCESSFULLY DOWNLOADED PAGE {}", &page_number);
-                break;
+impl Credentials {
+    fn username(&self) -> Result<String, EdukaError> {
+        if let Some(username) = self.username.as_ref().or(config().username.as_ref()) {
+            return Ok(username.clone());
+        }
+        if !io::stdin().is_terminal() {
+            return Err(EdukaError::NonInteractive);
+        }
+        print!("Username: ");
+        io::stdout().flush()?;
+        let mut username = String::new();
+        io::stdin().read_line(&mut username)?;
+        Ok(String::from(username.trim()))
+    }
+
+    /// The password, read without echoing it when it has to be asked for.
+    fn password(&self) -> Result<String, EdukaError> {
+        if let Some(password) = &self.password {
+            return Ok(password.clone());
+        }
+        if !io::stdin().is_terminal() {
+            return Err(EdukaError::NonInteractive);
+        }
+        Ok(rpassword::prompt_password("Password: ")?)
+    }
+}
+
+#[derive(Args)]
+struct ConnectionArgs {
+    /// Maximum number of concurrent requests to the eduka host across all books
+    #[arg(long, default_value_t = config().max_connections, value_parser = clap::value_parser!(u64).range(1..))]
+    max_connections: u64,
+    /// Number of pages of a book downloaded in parallel
+    #[arg(short, long, default_value_t = config().jobs, value_parser = clap::value_parser!(u64).range(1..))]
+    jobs: u64,
+    /// Number of books of a package downloaded in parallel, each with up to
+    /// --jobs pages at once and all within --max-connections
+    #[arg(long, default_value_t = config().book_jobs, value_parser = clap::value_parser!(u64).range(1..))]
+    book_jobs: u64,
+    /// Accept the terms of use of books that can't be read before doing so
+    #[arg(long)]
+    accept_terms: bool,
+    /// How many times a failed page request is repeated before giving up on it
+    #[arg(long, default_value_t = config().max_retries)]
+    max_retries: u32,
+    /// Milliseconds to wait before the first retry of a page, doubling with every
+    /// further retry
+    #[arg(long, value_name = "MS", default_value_t = config().retry_delay)]
+    retry_delay: u64,
+    /// Width in pixels of the page images to download, e.g. 1140, or auto-max for
+    /// the largest there is of each page
+    #[arg(long, default_value = "auto-max")]
+    resolution: Resolution,
+    /// Download and assemble only these pages, e.g. `1-50,120-130`, as a book of
+    /// its own. Bookmarks of pages left out are dropped
+    #[arg(long, value_name = "RANGES")]
+    pages: Option<PageRanges>,
+    /// Keep all downloads together under this many bytes per second, with a K, M
+    /// or G suffix for KiB, MiB or GiB, e.g. `2M`
+    #[arg(long, value_name = "RATE", value_parser = byte_rate)]
+    limit_rate: Option<u64>,
+    /// Average number of requests per second to the eduka host, spaced out
+    /// randomly so they don't come at a fixed beat
+    #[arg(long, default_value_t = config().rps, value_parser = requests_per_second)]
+    rps: f64,
+    /// Send every request through this proxy, e.g. `http://proxy:3128` or
+    /// `socks5://localhost:1080` for an SSH tunnel. Without it HTTPS_PROXY,
+    /// HTTP_PROXY and ALL_PROXY are honored
+    #[arg(long, value_name = "URL")]
+    proxy: Option<String>,
+    /// Also ask eduka whether every page of the books downloaded already changed
+    /// since, to fetch pages the publisher replaced under the same url again.
+    /// Pages moved to another url are noticed without it
+    #[arg(long)]
+    check_updates: bool,
+}
+
+impl ConnectionArgs {
+    fn options(&self, output_dir: &Path, name_template: &str, json: bool) -> ClientOptions {
+        ClientOptions {
+            max_connections: self.max_connections as usize,
+            accept_terms: self.accept_terms,
+            max_retries: self.max_retries,
+            retry_delay: Duration::from_millis(self.retry_delay),
+            output_dir: output_dir.to_path_buf(),
+            jobs: self.jobs as usize,
+            book_jobs: self.book_jobs as usize,
+            name_template: String::from(name_template),
+            json_events: json,
+            resolution: self.resolution,
+            pages: self.pages.clone(),
+            limit_rate: self.limit_rate,
+            rps: self.rps,
+            proxy: self.proxy.clone(),
+            check_updates: self.check_updates,
+        }
+    }
+}
+
+#[derive(Args)]
+struct PrepareArgs {
+    /// Also save word-level hOCR for every page of each book, as tesseract
+    /// recognized it in the `--ocr-lang` languages
+    #[arg(long)]
+    hocr: bool,
+    /// Don't save the OCR text of each book as a `.txt` file next to its PDF
+    #[arg(long)]
+    no_text_sidecar: bool,
+    /// Also save the resolved bookmarks as a JSON sidecar next to each PDF
+    #[arg(long)]
+    toc_json: bool,
+    /// Leave the PDF without bookmarks, for books whose chapter data is garbage
+    #[arg(long)]
+    no_bookmarks: bool,
+    /// Also save the resolved bookmarks to a file to edit by hand, as JSON if it
+    /// ends in `.json` and as indented `title<TAB>page` lines otherwise. `{id}`
+    /// in the path stands for the book id
+    #[arg(long, value_name = "PATH")]
+    export_toc: Option<PathBuf>,
+    /// Build the bookmarks from a file in either format of `--export-toc`
+    /// instead of eduka's chapters, for books whose chapters are incomplete
+    #[arg(long, value_name = "PATH")]
+    toc: Option<PathBuf>,
+    /// Keep eduka's chapters next to the ones from `--toc`
+    #[arg(long, requires = "toc")]
+    toc_merge: bool,
+    /// Re-encode pages as JPEG of the given quality (1-100) before assembling the
+    /// PDF, for much smaller output. Pages are kept lossless by default
+    #[arg(long, value_name = "QUALITY", value_parser = clap::value_parser!(u8).range(1..=100))]
+    compress_images_before_pdf: Option<u8>,
+    /// Convert all pages to one format before assembly, whatever eduka served
+    #[arg(long, value_enum)]
+    image_format: Option<PageFormat>,
+    /// Quality (1-100) of pages converted to JPEG, 85 if not given. Without
+    /// `--image-format` it converts the pages to JPEG
+    #[arg(long, value_name = "QUALITY", value_parser = clap::value_parser!(u8).range(1..=100))]
+    jpeg_quality: Option<u8>,
+    /// Resolution of the page images used to compute the physical page size.
+    /// Defaults to the DPI stored in each image, or 96 DPI if there is none
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    dpi: Option<u32>,
+    /// Trim the uniform margins off the pages before assembling them, for
+    /// reading on small screens. Margins of more than half the page are kept
+    #[arg(long)]
+    autocrop: bool,
+    /// Straighten pages scanned at a slight angle before assembling them, which
+    /// also helps OCR
+    #[arg(long)]
+    deskew: bool,
+    /// Embed the image of identical pages, like repeated blank or divider
+    /// pages, only once in the PDF
+    #[arg(long)]
+    dedupe_pages: bool,
+    /// Store the pages of the PDF in grayscale, for black-and-white books
+    #[arg(long, conflicts_with = "bilevel")]
+    grayscale: bool,
+    /// Store the pages of the PDF in pure black and white at 1 bit per pixel,
+    /// the smallest for workbooks of only text and line drawings
+    #[arg(long)]
+    bilevel: bool,
+    /// Don't carry embedded ICC color profiles over to re-encoded pages and into
+    /// the PDF
+    #[arg(long)]
+    strip_icc_profiles: bool,
+    /// Also save the OCR text of each top-level chapter as its own text file
+    #[arg(long)]
+    chapter_text: bool,
+    /// Also save each top-level chapter as its own PDF, to share only the
+    /// chapter that is needed
+    #[arg(long)]
+    split_by_chapter: bool,
+    /// Save only the PDFs of the chapters, not the one of the whole book
+    #[arg(long, conflicts_with = "delete_images")]
+    chapters_only: bool,
+    /// Assemble the pages into a PDF, a CBZ comic book archive for tablet
+    /// reading apps, a fixed-layout EPUB for e-readers, or only archive the page
+    /// images with the book's metadata.json to process them yourself
+    #[arg(long, value_enum, default_value_t = OutputFormat::Pdf)]
+    format: OutputFormat,
+    /// Archive the page images of `--format images` go into
+    #[arg(long, value_enum, default_value_t = ImageArchive::Zip)]
+    images_archive: ImageArchive,
+    /// Name the PDF after the book id only, or also its title like the directory
+    #[arg(long, value_enum, default_value_t = PdfName::Id)]
+    pdf_name: PdfName,
+    /// Assemble the parts of a collection into a single PDF, with a bookmark
+    /// for every part holding its chapters
+    #[arg(long)]
+    merge_parts: bool,
+    /// Write PDF/A-2b conforming PDFs, with an embedded color profile and XMP
+    /// metadata, for libraries archiving them
+    #[arg(long, conflicts_with = "encrypt")]
+    pdfa: bool,
+    /// Encrypt the PDFs, so they only open with `--user-password`, and can be
+    /// printed but not changed or copied out of
+    #[arg(long, requires = "user_password")]
+    encrypt: bool,
+    /// Password the encrypted PDFs open with
+    #[arg(long, requires = "encrypt")]
+    user_password: Option<String>,
+    /// Password that lifts the restrictions of the encrypted PDFs, without it
+    /// nobody can
+    #[arg(long, requires = "encrypt")]
+    owner_password: Option<String>,
+    /// Skip OCR for a quick image-only PDF with bookmarks, without a text layer
+    #[arg(long)]
+    no_ocr: bool,
+    /// Languages of the books for OCR, as tesseract language codes separated by
+    /// commas, e.g. `lit,eng`
+    #[arg(long, value_name = "LANGS", default_value = config().ocr_lang.as_str())]
+    ocr_lang: String,
+    /// Page shift to place bookmarks with instead of the one eduka reports, for
+    /// books whose chapters all point at the wrong page. Set it for single books
+    /// under `[page-shift]` in the config
+    #[arg(long, value_name = "SHIFT", allow_negative_numbers = true)]
+    page_shift_override: Option<i64>,
+    /// Move every bookmark this many pages back, or forward if negative
+    #[arg(
+        long,
+        value_name = "PAGES",
+        default_value_t = 0,
+        allow_negative_numbers = true
+    )]
+    page_shift_adjust: i64,
+    /// Delete the page images once the PDF is assembled and reads back with
+    /// every page, the default with `delete-images = true` in the config
+    #[arg(long, conflicts_with = "keep_images")]
+    delete_images: bool,
+    /// Keep the page images next to the PDF, the default
+    #[arg(long)]
+    keep_images: bool,
+}
+
+impl PrepareArgs {
+    fn options(&self, output_dir: &Path, name_template: &str) -> PrepareOptions {
+        // either asks for JPEG pages
+        let jpeg_quality = self.compress_images_before_pdf.or(self.jpeg_quality);
+        PrepareOptions {
+            hocr: self.hocr,
+            text_sidecar: !self.no_text_sidecar,
+            toc_json: self.toc_json,
+            bookmarks: !self.no_bookmarks,
+            export_toc: self.export_toc.clone(),
+            toc: self.toc.clone(),
+            toc_merge: self.toc_merge,
+            image_format: self.image_format.or(jpeg_quality.map(|_| PageFormat::Jpeg)),
+            jpeg_quality: jpeg_quality.unwrap_or(PrepareOptions::default().jpeg_quality),
+            dpi: self.dpi,
+            keep_icc_profiles: !self.strip_icc_profiles,
+            chapter_text: self.chapter_text,
+            split_by_chapter: self.split_by_chapter,
+            chapters_only: self.chapters_only,
+            pdf_name: self.pdf_name,
+            name_template: String::from(name_template),
+            output_dir: output_dir.to_path_buf(),
+            merge_parts: self.merge_parts,
+            pdfa: self.pdfa,
+            user_password: self.user_password.clone().filter(|_| self.encrypt),
+            owner_password: self.owner_password.clone().filter(|_| self.encrypt),
+            ocr: !self.no_ocr,
+            ocr_lang: self.ocr_lang.clone(),
+            page_shift_override: self.page_shift_override,
+            page_shifts: config().page_shift.clone(),
+            page_shift_adjust: self.page_shift_adjust,
+            format: self.format,
+            images_archive: self.images_archive,
+            autocrop: self.autocrop,
+            deskew: self.deskew,
+            dedupe_pages: self.dedupe_pages,
+            color: match (self.grayscale, self.bilevel) {
+                (true, _) => ColorMode::Grayscale,
+                (_, true) => ColorMode::Bilevel,
+                _ => ColorMode::Color,
+            },
+            // the images are only deleted once the whole book's PDF reads back
+            delete_images: (self.delete_images || config().delete_images)
+                && !self.keep_images
+                && !self.chapters_only,
+        }
+    }
+}
+
+#[derive(Parser)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+    /// Directory the book directories are downloaded into, created if missing
+    #[arg(long, global = true, default_value_os_t = config().output_dir.clone())]
+    output_dir: PathBuf,
+    /// Name of book directories, and of PDFs with `--pdf-name title`, where
+    /// `{title}` and `{id}` stand for the book's, e.g. `{title} [{id}]`
+    #[arg(long, global = true, default_value = config().name_template.as_str(), value_parser = name_template)]
+    name_template: String,
+    /// Least severe messages shown: error, warn, info, debug or trace
+    #[arg(long, global = true, default_value_t = tracing::Level::INFO)]
+    log_level: tracing::Level,
+    /// Show more detail than `--log-level`, once for debug and twice for trace
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+    /// Also write the log of this run to `eduka-<unix time>.log` in the output
+    /// directory
+    #[arg(long, global = true)]
+    log_file: bool,
+    /// Report progress as newline-delimited JSON events on stdout instead of with
+    /// progress bars, logging to stderr
+    #[arg(long, global = true)]
+    json: bool,
+    /// Answer yes to every confirmation prompt, for unattended runs
+    #[arg(short = 'y', long, global = true)]
+    assume_yes: bool,
+}
+
+impl Cli {
+    fn log_level(&self) -> tracing::Level {
+        match (self.log_level, self.verbose) {
+            (level, 0) => level,
+            (tracing::Level::ERROR | tracing::Level::WARN | tracing::Level::INFO, 1) => {
+                tracing::Level::DEBUG
             }
-        },
-        Err(e) => {
-            println!("error {}", &e);
-        }
-    }
-}
-
-async fn fill_teaching_tool_metadata(
-    client: &reqwest::Client,
-    teaching_tool: &mut TeachingTool,
-) -> Result<(), EdukaError> {
-    let is_downloadable_response: IsDownloadableResponse = client
-        .get(&format!(
-            "https://klase.eduka.lt/api/authenticated/teaching-tool/is-downloadable/{}",
-            &teaching_tool.id
-        ))
-        .send()
-        .await?
-        .json()
-        .await?;
-    let mut book: Book = client
-        .get(
-            &(String::from("https://klase.eduka.lt/api/authenticated/part/show-by-teaching-tool/")
-                + &teaching_tool.id.to_string()),
+            _ => tracing::Level::TRACE,
+        }
+    }
+}
+
+/// Log to stdout, and to a file of the run in the output directory with
+/// `--log-file`.
+fn init_logging(cli: &Cli) -> Result<(), EdukaError> {
+    use tracing_subscriber::filter::LevelFilter;
+    use tracing_subscriber::prelude::*;
+    let level = LevelFilter::from_level(cli.log_level());
+    // stdout is kept for the events with --json
+    let writer = if cli.json {
+        tracing_subscriber::fmt::writer::BoxMakeWriter::new(io::stderr)
+    } else {
+        tracing_subscriber::fmt::writer::BoxMakeWriter::new(io::stdout)
+    };
+    let stdout = tracing_subscriber::fmt::layer()
+        .without_time()
+        .with_target(false)
+        .with_writer(writer)
+        .with_filter(level);
+    let log_file = if cli.log_file {
+        fs::create_dir_all(&cli.output_dir)?;
+        let started = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let file = fs::File::create(cli.output_dir.join(format!("eduka-{}.log", started)))?;
+        Some(
+            tracing_subscriber::fmt::layer()
+                .with_ansi(false)
+                .with_writer(std::sync::Mutex::new(file))
+                .with_filter(level),
         )
-        .send()
-        .await?
-        .json()
-        .await?;
-    book.title = book.collection_title.clone()
-        + ": "
-        + &book
-            .parts
-            .get(0)
-            .ok_or(EdukaError::UnexpectedResponse)?
-            .title;
-    book.id = teaching_tool.id;
-    book.native_downloadable = is_downloadable_response.isDownloadable;
-    teaching_tool.book = book.clone();
-    let pages_json: serde_json::Value = serde_json::from_str(
-        &client
-            .get(
-                &(String::from("https://klase.eduka.lt/api/authenticated/teaching-tool/pages/")
-                    + &book.id.to_string()),
-            )
-            .send()
-            .await
-            .unwrap()
-            .text()
-            .await
-            .unwrap(),
-    )
-    .unwrap();
-    let pages_objects_array = pages_json.get("pages").unwrap().as_array().unwrap();
-    for page in pages_objects_array {
-        let img_url_frag = page["img"]["1140"].as_str();
-        if let Some(img_url_frag) = img_url_frag {
-            book.page_urls
-                .push(String::from("https://klase.eduka.lt") + img_url_frag);
-        } else {
-            println!("Couldn't get page by {:?}", &page)
-        }
-    }
-    book.page_shift = pages_json.get("pageShift").unwrap().as_i64().unwrap();
-    let bookmarks_array: Vec<Bookmark> = serde_json::from_str(
-        &pages_json
-            .get("chapters")
-            .ok_or(EdukaError::UnexpectedResponse)?
-            .to_string(),
-    )
-    .map_err(|_| EdukaError::UnexpectedResponse)?;
-    book.bookmarks = bookmarks_array;
-    teaching_tool.book = book.clone();
+    } else {
+        None
+    };
+    tracing_subscriber::registry()
+        .with(stdout)
+        .with(log_file)
+        .init();
     Ok(())
 }
 
-async fn download_teaching_tool(
-    client: &Arc<reqwest::Client>,
-    teaching_tool: &TeachingTool,
-) -> Result<(), EdukaError> {
-    let book = &teaching_tool.book;
-    let book_dir = String::from("./") + &book.title + " ;;; " + &book.id.to_string();
-    // skip already started to dl books
-    if Path::new(&book_dir).is_dir() {
-        println!("SKIPPING");
-        return Ok(());
-    }
-    fs::create_dir_all(&book_dir).unwrap();
-
-    let mut handles: Vec<tokio::task::JoinHandle<()>> = Vec::new();
-    for (i, page) in book.page_urls.iter().enumerate() {
-        let cl_clone = client.clone();
-        let book_dir = book_dir.clone();
-        let p_clone = page.clone();
-
-        handles.push(tokio::spawn(async move {
-            save_page_to_file(cl_clone, &book_dir, &p_clone, i.try_into().unwrap()).await;
-        }));
-        if i % 10 == 0 {
-            for handle in &mut handles {
-                handle.await.unwrap();
-            }
-            handles.clear();
-        }
+/// Bytes per second of a `--limit-rate` like `2M`.
+fn byte_rate(rate: &str) -> Result<u64, String> {
+    let (number, unit) = match rate.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&rate[..i], 1 << 10),
+        Some((i, 'm' | 'M')) => (&rate[..i], 1 << 20),
+        Some((i, 'g' | 'G')) => (&rate[..i], 1 << 30),
+        _ => (rate, 1),
+    };
+    match number.parse::<f64>() {
+        Ok(number) if number * unit as f64 >= 1.0 => Ok((number * unit as f64) as u64),
+        _ => Err(String::from("expected a rate like 500K or 2M")),
+    }
+}
+
+fn requests_per_second(rps: &str) -> Result<f64, String> {
+    match rps.parse::<f64>() {
+        Ok(rps) if rps > 0.0 && rps.is_finite() => Ok(rps),
+        _ => Err(String::from(
+            "expected a positive number of requests per second",
+        )),
+    }
+}
+
+/// Which teaching tools exploration offers, and whether it asks about them.
+#[derive(Args)]
+struct ExploreFilters {
+    /// Explore only the ids from START up to END, e.g. `1000..2000`
+    #[arg(long, value_name = "START..END", value_parser = id_range, conflicts_with = "tui")]
+    explore_range: Option<Range<u64>>,
+    /// Select every teaching tool that passes the filters without asking, for
+    /// unattended exploration of an --explore-range
+    #[arg(long, requires = "explore_range")]
+    auto_yes: bool,
+    /// Only teaching tools whose title matches this regular expression, ignoring
+    /// case
+    #[arg(long, value_name = "REGEX", value_parser = title_regex, conflicts_with = "tui")]
+    title_filter: Option<regex::Regex>,
+    /// Only teaching tools with this word in their title or chapter titles,
+    /// ignoring case. Given several times any of them will do
+    #[arg(long = "keyword", value_name = "WORD", conflicts_with = "tui")]
+    keywords: Vec<String>,
+    /// Only teaching tools with at least this many pages
+    #[arg(long, value_name = "PAGES", conflicts_with = "tui")]
+    min_pages: Option<usize>,
+    /// Only teaching tools with at most this many pages
+    #[arg(long, value_name = "PAGES", conflicts_with = "tui")]
+    max_pages: Option<usize>,
+    /// Only teaching tools whose publisher's PDF can be downloaded
+    #[arg(long, conflicts_with = "tui")]
+    native_only: bool,
+}
+
+fn contains_keyword(bookmarks: &[Bookmark], keyword: &str) -> bool {
+    bookmarks.iter().any(|bookmark| {
+        bookmark.title.to_lowercase().contains(keyword)
+            || contains_keyword(&bookmark.lessons, keyword)
+    })
+}
+
+impl ExploreFilters {
+    fn matches(&self, teaching_tool: &TeachingTool) -> bool {
+        let book = &teaching_tool.book;
+        let pages: usize = teaching_tool.books().map(|book| book.page_urls.len()).sum();
+        self.title_filter
+            .as_ref()
+            .is_none_or(|regex| regex.is_match(&book.title))
+            && (self.keywords.is_empty()
+                || self.keywords.iter().any(|keyword| {
+                    let keyword = keyword.to_lowercase();
+                    teaching_tool.books().any(|book| {
+                        book.title.to_lowercase().contains(&keyword)
+                            || contains_keyword(&book.bookmarks, &keyword)
+                    })
+                }))
+            && self.min_pages.is_none_or(|min_pages| pages >= min_pages)
+            && self.max_pages.is_none_or(|max_pages| pages <= max_pages)
+            && (!self.native_only || book.native_downloadable)
+    }
+}
+
+fn id_range(range: &str) -> Result<Range<u64>, String> {
+    let (start, end) = range
+        .split_once("..")
+        .ok_or_else(|| String::from("expected a range like 1000..2000"))?;
+    let start = start
+        .parse()
+        .map_err(|_| format!("{:?} is not an id", start))?;
+    let end = end.parse().map_err(|_| format!("{:?} is not an id", end))?;
+    Ok(start..end)
+}
+
+fn title_regex(regex: &str) -> Result<regex::Regex, String> {
+    regex::RegexBuilder::new(regex)
+        .case_insensitive(true)
+        .build()
+        .map_err(|e| e.to_string())
+}
+
+/// A name template has to have the id in it, or books with the same title would
+/// share a directory.
+fn name_template(template: &str) -> Result<String, String> {
+    if template.contains("{id}") {
+        Ok(String::from(template))
+    } else {
+        Err(String::from("the template has to contain {id}"))
     }
-    for handle in &mut handles {
-        handle.await.unwrap();
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Log in and save the session for the other commands to reuse
+    Login {
+        #[command(flatten)]
+        credentials: Credentials,
+    },
+    /// Walk teaching tool ids one by one, pick the ones to download and prepare
+    Explore {
+        #[command(flatten)]
+        credentials: Credentials,
+        #[command(flatten)]
+        connection: ConnectionArgs,
+        #[command(flatten)]
+        prepare: PrepareArgs,
+        /// Teaching tool id to start exploring from. Without it exploration goes on
+        /// from where the last one stopped, skipping the ids already answered
+        #[arg(long)]
+        start: Option<u64>,
+        /// Explore in a terminal UI that lists teaching tools as they are found and
+        /// lets you pick several at once
+        #[arg(long)]
+        tui: bool,
+        #[command(flatten)]
+        filters: ExploreFilters,
+        /// Number of ids ahead whose metadata is fetched while a question waits
+        /// for an answer
+        #[arg(long, default_value_t = 8, value_parser = clap::value_parser!(u64).range(1..))]
+        prefetch: u64,
+    },
+    /// Download and prepare every book of the given packages
+    Download {
+        #[command(flatten)]
+        credentials: Credentials,
+        #[command(flatten)]
+        connection: ConnectionArgs,
+        #[command(flatten)]
+        prepare: PrepareArgs,
+        /// Package or teaching tool ids or urls
+        #[arg(required_unless_present = "ids_file")]
+        books: Vec<String>,
+        /// Download every package or teaching tool listed in a file, one id or url
+        /// per line, or in stdin with `-`
+        #[arg(long, visible_alias = "from-file", value_name = "PATH")]
+        ids_file: Option<PathBuf>,
+        /// Number of teaching tools of a package whose metadata is fetched at once
+        #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u64).range(1..))]
+        metadata_concurrency: u64,
+        /// Only download the pages, leaving `prepare` for later
+        #[arg(long)]
+        no_prepare: bool,
+    },
+    /// Assemble the PDFs of already downloaded book directories from the pages and
+    /// metadata.json on disk, without logging in or any network access
+    Prepare {
+        #[arg(value_name = "BOOK_DIR", required = true)]
+        book_dirs: Vec<PathBuf>,
+        #[command(flatten)]
+        prepare: PrepareArgs,
+        /// Rebuild only the bookmarks of the existing PDF, replacing its outline
+        #[arg(long)]
+        bookmarks_only: bool,
+    },
+    /// Line the bookmarks of a downloaded book up with its pages by nudging the
+    /// page shift chapter by chapter, saving it to the book's metadata.json
+    Calibrate {
+        #[arg(value_name = "BOOK_DIR")]
+        book_dir: PathBuf,
+        /// Open each page the chapter lands on in the image viewer
+        #[arg(long)]
+        open: bool,
+    },
+    /// Remove unfinished downloads, books missing pages and the page images of
+    /// prepared books from the output directory
+    Clean {
+        /// Only list what would be removed
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// List the books downloaded to the output directory, with their page count,
+    /// size, whether they are prepared and when they were downloaded
+    List {
+        /// List the packages and teaching tools the account has a license for
+        /// instead, with ids to pass to `download`
+        #[arg(long)]
+        mine: bool,
+        #[command(flatten)]
+        credentials: Credentials,
+        #[command(flatten)]
+        connection: ConnectionArgs,
+    },
+    /// Check every downloaded book against the page count eduka has for it now,
+    /// reporting missing, empty or corrupted pages and unreadable PDFs
+    Verify {
+        #[command(flatten)]
+        credentials: Credentials,
+        #[command(flatten)]
+        connection: ConnectionArgs,
+        #[command(flatten)]
+        prepare: PrepareArgs,
+        /// Download again only what is broken, and assemble the PDF again
+        #[arg(long)]
+        repair: bool,
+        /// Also compare every page and PDF with the SHA-256 recorded when it was
+        /// written, to find files that changed on disk since
+        #[arg(long)]
+        verify_checksums: bool,
+    },
+    /// Record the id, title, publisher, page count and whether the publisher's PDF
+    /// can be downloaded of every teaching tool in a range of ids, without
+    /// downloading any pages, to pick books from offline
+    #[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+    Catalog {
+        #[command(subcommand)]
+        command: Option<CatalogCommand>,
+        #[command(flatten)]
+        credentials: Credentials,
+        #[command(flatten)]
+        connection: ConnectionArgs,
+        /// Ids to scan, e.g. `0..50000`
+        #[arg(long, value_name = "START..END", value_parser = id_range, required = true)]
+        range: Option<Range<u64>>,
+        /// File to write, JSON if it ends in `.json` and CSV otherwise
+        #[arg(long, value_name = "PATH", required = true)]
+        out: Option<PathBuf>,
+        /// Number of ids whose metadata is fetched at once
+        #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u64).range(1..))]
+        metadata_concurrency: u64,
+    },
+    /// Find teaching tools by title, subject or grade, e.g. `biologija 9`, and print
+    /// their ids
+    Search {
+        #[command(flatten)]
+        credentials: Credentials,
+        #[command(flatten)]
+        connection: ConnectionArgs,
+        query: String,
+    },
+    /// Index the OCR text of every prepared book for `grep`, replacing the index
+    /// made before
+    Index,
+    /// Find the pages of the downloaded books whose OCR text has the words of a
+    /// query, e.g. `fotosintezė`, printing the book, chapter and page of each
+    Grep {
+        query: String,
+        /// Most pages to print
+        #[arg(long, default_value_t = 20)]
+        limit: usize,
+    },
+    /// Print the page image URLs of a teaching tool, one per line or as an event
+    /// per book with `--json`, without downloading
+    PageUrls {
+        #[command(flatten)]
+        credentials: Credentials,
+        #[command(flatten)]
+        connection: ConnectionArgs,
+        id: u64,
+    },
+    /// Measure download throughput at several concurrency levels using a sample
+    /// of the pages of a teaching tool, and recommend the best one
+    Benchmark {
+        #[command(flatten)]
+        credentials: Credentials,
+        #[command(flatten)]
+        connection: ConnectionArgs,
+        id: u64,
+    },
+}
+
+#[derive(Subcommand)]
+enum CatalogCommand {
+    /// Report the teaching tools added, removed or changed between two catalogs,
+    /// e.g. to find new editions since the last scan
+    Diff {
+        #[arg(value_name = "OLD")]
+        old: PathBuf,
+        #[arg(value_name = "NEW")]
+        new: PathBuf,
+    },
+}
+
+fn print_catalog_diff(old: &Path, new: &Path) -> Result<(), EdukaError> {
+    let diff = diff_catalogs(&read_catalog(old)?, &read_catalog(new)?);
+    for entry in &diff.added {
+        println!("+ {}\t{}\t{} pages", entry.id, &entry.title, entry.pages);
+    }
+    for entry in &diff.removed {
+        println!("- {}\t{}\t{} pages", entry.id, &entry.title, entry.pages);
+    }
+    for id in &diff.unknown {
+        println!(
+            "? {}\tnot known, fetching its metadata failed in a scan",
+            id
+        );
     }
-    handles.clear();
-    println!("SUCCESSFULLY DOWNLOADED BOOK {}", &book.title);
+    for (old, new) in &diff.changed {
+        let mut changes = vec![];
+        if old.title != new.title {
+            changes.push(format!("title {:?} -> {:?}", &old.title, &new.title));
+        }
+        if old.pages != new.pages {
+            changes.push(format!("{} -> {} pages", old.pages, new.pages));
+        }
+        if old.publisher != new.publisher {
+            changes.push(format!(
+                "publisher {:?} -> {:?}",
+                &old.publisher, &new.publisher
+            ));
+        }
+        if old.native_downloadable != new.native_downloadable {
+            changes.push(String::from(if new.native_downloadable {
+                "publisher's PDF now downloadable"
+            } else {
+                "publisher's PDF no longer downloadable"
+            }));
+        }
+        println!("~ {}\t{}\t{}", new.id, &new.title, changes.join(", "));
+    }
+    info!(
+        "{} added, {} removed, {} changed, {} not known",
+        diff.added.len(),
+        diff.removed.len(),
+        diff.changed.len(),
+        diff.unknown.len()
+    );
     Ok(())
 }
 
-async fn download_package(client: Arc<reqwest::Client>, id: u64) -> Result<Package, EdukaError> {
-    let url = reqwest::Url::parse_with_params(
-        &(String::from("https://klase.eduka.lt/api/authenticated/teaching-package/")
-            + &id.to_string()),
-        [("withTeachingTools", "1")],
-    )
-    .unwrap();
-    let mut package: Package = client.get(url).send().await?.json().await?;
-    for teaching_tool in &mut package.teaching_tools {
-        fill_teaching_tool_metadata(&client, teaching_tool).await?;
+/// Where the session cookies are kept between runs.
+fn session_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("eduka-downloader").join("session.json"))
+}
+
+async fn wait_for_signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+                .expect("installing the SIGTERM handler failed");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
     }
-    for teaching_tool in &package.teaching_tools {
-        download_teaching_tool(&client, teaching_tool).await?;
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await.ok();
+}
+
+/// On Ctrl-C or SIGTERM let the pages being downloaded finish and stop there, so
+/// the next run resumes cleanly. A second signal quits right away.
+fn cancel_on_signal(client: Arc<EdukaClient>) {
+    tokio::spawn(async move {
+        loop {
+            wait_for_signal().await;
+            if client.is_cancelled() {
+                std::process::exit(Exit::Interrupted as i32);
+            }
+            warn!("interrupted, finishing the pages being downloaded (again to quit now)");
+            client.cancel();
+        }
+    });
+}
+
+async fn logged_in_client(
+    credentials: &Credentials,
+    options: ClientOptions,
+) -> Result<Arc<EdukaClient>, EdukaError> {
+    let client = EdukaClient::new(options)?;
+    let username = credentials.username()?;
+    let session_path = session_path();
+    let resumed = match &session_path {
+        // the password is only needed, and asked for, without a usable session
+        Some(session_path) => client.resume_session(&username, session_path).await?,
+        None => false,
+    };
+    if !resumed {
+        client.login(&username, &credentials.password()?).await?;
+        if let Some(session_path) = &session_path {
+            client.save_session(&username, session_path)?;
+        }
+    } else if let Some(password) = &credentials.password {
+        // without asking for it, but so an expiring session can be logged in to again
+        client.remember_credentials(&username, password);
     }
-    Ok(package)
+    client.emit(Event::LoginOk {
+        username: &username,
+    });
+    let client = Arc::new(client);
+    cancel_on_signal(client.clone());
+    Ok(client)
 }
 
-fn prepare_teaching_tool(teaching_tool: &TeachingTool) -> Result<(), EdukaError> {
-    let book_dir = String::from("./")
-        + &teaching_tool.book.title
-        + " ;;; "
-        + &teaching_tool.book.id.to_string();
+/// Log in even if a saved session is still valid, and save the new session.
+async fn login(credentials: &Credentials, json: bool) -> Result<String, EdukaError> {
+    let client = EdukaClient::new(ClientOptions {
+        json_events: json,
+        ..Default::default()
+    })?;
+    let username = credentials.username()?;
+    client.login(&username, &credentials.password()?).await?;
+    if let Some(session_path) = session_path() {
+        client.save_session(&username, &session_path)?;
+    }
+    client.emit(Event::LoginOk {
+        username: &username,
+    });
+    Ok(username)
+}
 
-    assert!(Command::new("bash")
-        .arg("-c")
-        .arg(
-            String::from("img2pdf $(ls *.png | sort -n) | ocrmypdf -l lit - ")
-                + &teaching_tool.book.id.to_string()
-                + ".pdf"
-        )
-        .current_dir(fs::canonicalize(&book_dir).unwrap())
-        .status()
-        .expect("failed to execute process")
-        .success());
-
-    let pdf_path = std::path::Path::new(&book_dir).join(format!("{}.pdf", &teaching_tool.book.id));
-
-    let mut doc = lopdf::Document::load(&pdf_path)?;
-
-    fn add_bookmarks(
-        doc: &mut lopdf::Document,
-        page_shift: i64,
-        bookmarks: &Vec<Bookmark>,
-        parent_id: Option<u32>,
-    ) -> Result<(), EdukaError> {
-        for eduka_bookmark in bookmarks {
-            let page_num = ({
-                if eduka_bookmark.startPage == 0 {
-                    if let Some(child) = eduka_bookmark.lessons.get(0) {
-                        child.startPage
-                    } else {
-                        0
+/// Report an error that ends the command, as an event too with `--json`, and
+/// pass on the exit code for it.
+fn fail(json: bool, subject: &str, message: String, exit: Exit) -> Exit {
+    error!("{}", &message);
+    if json {
+        Event::Error { subject, message }.print();
+    }
+    exit
+}
+
+/// Keep the exploration state for the next run, warning if it can't be.
+fn save_exploration(state: &ExplorationState, db: Option<&StateDb>) {
+    if let Some(db) = db {
+        if let Err(e) = state.save(db) {
+            warn!("saving the exploration state failed {}", &e);
+        }
+    }
+}
+
+async fn explore(
+    client: Arc<EdukaClient>,
+    start: Option<u64>,
+    tui: bool,
+    assume_yes: bool,
+    filters: &ExploreFilters,
+    prefetch: usize,
+    prepare_options: &PrepareOptions,
+) -> Exit {
+    if assume_yes && !tui && filters.explore_range.is_none() {
+        error!("--assume-yes needs an --explore-range, exploring goes on without end otherwise");
+        return Exit::InvalidArguments;
+    }
+    let assume_yes = assume_yes || filters.auto_yes;
+    if !assume_yes && !io::stdin().is_terminal() {
+        error!("exploration is interactive but stdin is not a terminal, pass --assume-yes");
+        return Exit::InvalidArguments;
+    }
+    let db = client.state();
+    let mut state = match db.map(ExplorationState::load).transpose() {
+        Ok(state) => state.unwrap_or_default(),
+        Err(e) => {
+            warn!("reading the exploration state failed {}", &e);
+            ExplorationState::default()
+        }
+    };
+    let mut teaching_tools_to_download = vec![];
+    // answered yes in an earlier run that stopped before downloading them
+    for &id in &state.selected {
+        let mut teaching_tool = TeachingTool::new(id);
+        if let Ok(()) = client.fill_teaching_tool_metadata(&mut teaching_tool).await {
+            info!("still selected from before: {}", &teaching_tool.book.title);
+            teaching_tools_to_download.push(teaching_tool);
+        }
+    }
+    let mut i = match &filters.explore_range {
+        Some(range) => range.start,
+        None => start.unwrap_or(state.next_id),
+    };
+    if start.is_none() && filters.explore_range.is_none() && i > 0 {
+        info!("resuming exploration from teaching tool {}", &i);
+    }
+    if tui {
+        match tui::select_teaching_tools(client.clone(), i).await {
+            Ok(selected) => teaching_tools_to_download = selected,
+            Err(e) => {
+                error!("browsing teaching tools failed {}", &e);
+                return Exit::of(&e);
+            }
+        }
+    } else {
+        // metadata of the ids after the one asked about is fetched while the user
+        // answers, so the next question is usually there right away
+        let mut probes = VecDeque::new();
+        let mut next_probe = i;
+        loop {
+            while probes.len() < prefetch
+                && filters
+                    .explore_range
+                    .as_ref()
+                    .is_none_or(|range| next_probe < range.end)
+            {
+                if !state.tried(next_probe) {
+                    let client = client.clone();
+                    let id = next_probe;
+                    probes.push_back((
+                        id,
+                        tokio::spawn(async move {
+                            let mut teaching_tool = TeachingTool::new(id);
+                            client
+                                .fill_teaching_tool_metadata(&mut teaching_tool)
+                                .await
+                                .map(|()| teaching_tool)
+                        }),
+                    ));
+                }
+                next_probe += 1;
+            }
+            let Some((id, probe)) = probes.pop_front() else {
+                break;
+            };
+            i = id;
+            info!("trying teaching tool {}", &i);
+            if let Ok(teaching_tool) = probe.await.unwrap() {
+                let mut input_string = String::new();
+                if !filters.matches(&teaching_tool) {
+                    info!("{} doesn't pass the filters", &teaching_tool.book.title);
+                    // left unanswered, so other filters can still pick it
+                    input_string.push_str("skip");
+                } else if assume_yes {
+                    info!("selected {}", &teaching_tool.book.title);
+                    input_string.push('y');
+                }
+                while !(input_string.trim() == "y"
+                    || input_string.trim() == "n"
+                    || input_string.trim() == "skip"
+                    || input_string.trim() == "cancel")
+                {
+                    if teaching_tool.book.native_downloadable {
+                        print!("[NATIVE DOWNLOADABLE]");
                     }
-                } else {
-                    eduka_bookmark.startPage
+                    print!(
+                        "Should {} be downloaded (y/n/cancel): ",
+                        &teaching_tool.book.title
+                    );
+                    io::stdout().flush().expect("flushing the prompt failed");
+                    input_string.clear();
+                    io::stdin()
+                        .read_line(&mut input_string)
+                        .expect("reading user input failed");
                 }
-            } as i64
-                - page_shift) as u32;
-            let page_id = doc
-                .get_pages()
-                .get(&page_num)
-                .ok_or(EdukaError::PositionOffsetError)?
-                .to_owned();
-
-            // breaks in table of contents, surely its possible? LOPDF BUG?
-            let ascii_title = unidecode(&eduka_bookmark.title);
-            let lo_bookmark = lopdf::Bookmark::new(ascii_title, [1.0; 3], 0, page_id);
-            let bookmark_id = doc.add_bookmark(lo_bookmark, parent_id);
-            add_bookmarks(doc, page_shift, &eduka_bookmark.lessons, Some(bookmark_id))?;
-        }
-        Ok(())
-    }
-    add_bookmarks(
-        &mut doc,
-        teaching_tool.book.page_shift,
-        &teaching_tool.book.bookmarks,
-        None,
-    )?;
-    if let Some(n) = doc.build_outline() {
-        doc.catalog_mut()?
-            .set("Outlines", lopdf::Object::Reference(n));
-    }
-    doc.save(&pdf_path)?;
-    Ok(())
+                match input_string.trim() {
+                    "y" => {
+                        state.answers.insert(i, true);
+                        state.selected.insert(i);
+                        teaching_tools_to_download.push(teaching_tool);
+                    }
+                    "skip" => {}
+                    "cancel" => {
+                        if filters.explore_range.is_none() {
+                            state.next_id = i;
+                        }
+                        save_exploration(&state, db);
+                        break;
+                    }
+                    _ => {
+                        state.answers.insert(i, false);
+                    }
+                }
+            } else {
+                state.missing.insert(i);
+            }
+            i += 1;
+            // a range is explored apart from where exploring goes on from
+            if filters.explore_range.is_none() {
+                state.next_id = i;
+            }
+            save_exploration(&state, db);
+        }
+    }
+    let mut outcomes = vec![];
+    for teaching_tool in teaching_tools_to_download {
+        if client.is_cancelled() {
+            warn!("{}", EdukaError::Interrupted);
+            print_summary(&outcomes);
+            return Exit::Interrupted;
+        }
+        if let Err(e) = client.download_teaching_tool(&teaching_tool).await {
+            error!("failed to download {}", &teaching_tool.book.title);
+            outcomes.push(BookOutcome::failed(
+                teaching_tool_name(&teaching_tool),
+                e.to_string(),
+                Exit::of(&e),
+            ));
+            continue;
+        }
+        info!("downloaded {}", &teaching_tool.book.title);
+        state.selected.remove(&teaching_tool.id);
+        save_exploration(&state, db);
+        match prepare_teaching_tool(&teaching_tool, prepare_options) {
+            Ok(()) => {
+                info!("prepared {}", teaching_tool.book.title);
+                client.emit(Event::PrepareDone {
+                    id: teaching_tool.id,
+                    title: &teaching_tool.book.title,
+                });
+                outcomes.push(BookOutcome {
+                    title: teaching_tool_name(&teaching_tool),
+                    outcome: Outcome::Prepared,
+                    updated: client.was_updated(&teaching_tool),
+                });
+            }
+            Err(e) => {
+                error!("failed to prepare {} {}", teaching_tool.book.title, &e);
+                outcomes.push(BookOutcome::failed(
+                    teaching_tool_name(&teaching_tool),
+                    e.to_string(),
+                    Exit::of(&e),
+                ));
+            }
+        }
+    }
+    print_summary(&outcomes);
+    run_exit(&outcomes)
+}
+
+/// How far a book got in a run, for the summary at the end of it.
+enum Outcome {
+    Downloaded,
+    Prepared,
+    /// Left out of a package, as the account isn't licensed for it
+    Skipped,
+    Failed(String, Exit),
+}
+
+/// A book of an entry given to `download`.
+struct BookOutcome {
+    title: String,
+    outcome: Outcome,
+    /// Downloaded before, with pages eduka changed since fetched again
+    updated: bool,
+}
+
+impl BookOutcome {
+    fn failed(title: String, message: String, exit: Exit) -> BookOutcome {
+        BookOutcome {
+            title,
+            outcome: Outcome::Failed(message, exit),
+            updated: false,
+        }
+    }
+}
+
+/// Name of a teaching tool for messages, also when its metadata is missing.
+fn teaching_tool_name(teaching_tool: &TeachingTool) -> String {
+    if teaching_tool.book.title.is_empty() {
+        format!("teaching tool {}", teaching_tool.id)
+    } else {
+        teaching_tool.book.title.clone()
+    }
 }
 
-fn prepare_package(package: Package) -> Result<(), EdukaError> {
+/// Prepare a downloaded teaching tool if `prepare_options` ask for it.
+fn prepare_downloaded(
+    client: &EdukaClient,
+    teaching_tool: &TeachingTool,
+    prepare_options: Option<&PrepareOptions>,
+) -> BookOutcome {
+    let title = teaching_tool_name(teaching_tool);
+    let updated = client.was_updated(teaching_tool);
+    let Some(prepare_options) = prepare_options else {
+        return BookOutcome {
+            title,
+            outcome: Outcome::Downloaded,
+            updated,
+        };
+    };
+    match prepare_teaching_tool(teaching_tool, prepare_options) {
+        Ok(()) => {
+            client.emit(Event::PrepareDone {
+                id: teaching_tool.id,
+                title: &title,
+            });
+            BookOutcome {
+                title,
+                outcome: Outcome::Prepared,
+                updated,
+            }
+        }
+        Err(e) => {
+            let message = format!("preparing failed {}", &e);
+            report_failure(client, &title, format!("{}: {}", &title, &message));
+            BookOutcome::failed(title, message, Exit::PrepareFailed)
+        }
+    }
+}
+
+/// Download, and prepare, every book of a package that can be, going on past
+/// the ones that fail. `Err` if the package itself couldn't be.
+async fn download_package(
+    client: &Arc<EdukaClient>,
+    id: u64,
+    metadata_concurrency: usize,
+    prepare_options: Option<&PrepareOptions>,
+) -> Result<Vec<BookOutcome>, (String, Exit)> {
+    let (package, failed) = client
+        .download_package(id, metadata_concurrency)
+        .await
+        .map_err(|e| (format!("downloading package failed {}", &e), Exit::of(&e)))?;
+    let mut outcomes = vec![];
+    for (teaching_tool, e) in failed {
+        let title = teaching_tool_name(&teaching_tool);
+        if let EdukaError::NotLicensed = e {
+            outcomes.push(BookOutcome {
+                title,
+                outcome: Outcome::Skipped,
+                updated: false,
+            });
+            continue;
+        }
+        let message = format!("downloading failed {}", &e);
+        report_failure(client, &title, format!("{}: {}", &title, &message));
+        outcomes.push(BookOutcome::failed(title, message, Exit::of(&e)));
+    }
     for teaching_tool in &package.teaching_tools {
-        prepare_teaching_tool(teaching_tool)?;
+        outcomes.push(prepare_downloaded(client, teaching_tool, prepare_options));
     }
-    Ok(())
+    Ok(outcomes)
 }
 
-#[derive(Parser)]
-struct Cli {
-    #[arg(short, long)]
-    username: String,
-    #[arg(short, long)]
-    password: String,
-    books: Vec<String>,
-    #[arg(long)]
-    exploration_start: Option<u64>,
+async fn download_teaching_tool(
+    client: &Arc<EdukaClient>,
+    id: u64,
+    prepare_options: Option<&PrepareOptions>,
+) -> Result<BookOutcome, (String, Exit)> {
+    let mut teaching_tool = TeachingTool::new(id);
+    client
+        .fill_teaching_tool_metadata(&mut teaching_tool)
+        .await
+        .map_err(|e| {
+            (
+                format!("fetching teaching tool failed {}", &e),
+                Exit::of(&e),
+            )
+        })?;
+    client
+        .download_teaching_tool(&teaching_tool)
+        .await
+        .map_err(|e| {
+            (
+                format!("downloading teaching tool failed {}", &e),
+                Exit::of(&e),
+            )
+        })?;
+    Ok(prepare_downloaded(client, &teaching_tool, prepare_options))
+}
+
+/// Exit code of a run with `outcomes`. Some books downloaded and others not is a
+/// partial download, when none were it is why the first one failed. Books
+/// skipped as not licensed weren't downloaded either.
+fn run_exit(outcomes: &[BookOutcome]) -> Exit {
+    let failures: Vec<Exit> = outcomes
+        .iter()
+        .filter_map(|book| match book.outcome {
+            Outcome::Failed(_, exit) => Some(exit),
+            Outcome::Skipped => Some(Exit::of(&EdukaError::NotLicensed)),
+            _ => None,
+        })
+        .collect();
+    match failures.first() {
+        None => Exit::Success,
+        Some(_) if failures.iter().all(|&exit| exit == Exit::PrepareFailed) => Exit::PrepareFailed,
+        Some(_) if failures.len() < outcomes.len() => Exit::PartialDownload,
+        Some(_) if failures.contains(&Exit::PrepareFailed) => Exit::PartialDownload,
+        Some(&exit) => exit,
+    }
+}
+
+/// Table of how every book of a run ended, with why for the ones that failed.
+fn print_summary(outcomes: &[BookOutcome]) {
+    let count = |f: fn(&Outcome) -> bool| outcomes.iter().filter(|book| f(&book.outcome)).count();
+    info!(
+        "{} downloaded, {} prepared, {} skipped, {} failed, {} updated",
+        count(|outcome| matches!(outcome, Outcome::Downloaded)),
+        count(|outcome| matches!(outcome, Outcome::Prepared)),
+        count(|outcome| matches!(outcome, Outcome::Skipped)),
+        count(|outcome| matches!(outcome, Outcome::Failed(..))),
+        outcomes.iter().filter(|book| book.updated).count()
+    );
+    let width = outcomes
+        .iter()
+        .map(|book| book.title.chars().count())
+        .max()
+        .unwrap_or(0);
+    for book in outcomes {
+        let (status, reason) = match &book.outcome {
+            Outcome::Downloaded => ("downloaded", ""),
+            Outcome::Prepared => ("prepared", ""),
+            Outcome::Skipped => ("skipped", "not licensed for this account"),
+            Outcome::Failed(reason, _) => ("failed", reason.as_str()),
+        };
+        let reason = match book.updated {
+            true => "updated",
+            false => reason,
+        };
+        info!("    {:<10}  {:<width$}  {}", status, &book.title, reason);
+    }
+}
+
+/// Bytes as KiB, MiB or GiB.
+fn format_size(bytes: u64) -> String {
+    let mut size = bytes as f64;
+    for unit in ["B", "KiB", "MiB"] {
+        if size < 1024.0 {
+            return format!("{:.1} {}", size, unit);
+        }
+        size /= 1024.0;
+    }
+    format!("{:.1} GiB", size)
+}
+
+/// Throughput and errors of every concurrency level measured, and the level
+/// recommended.
+fn print_benchmark(levels: &[BenchmarkLevel]) {
+    println!(
+        "{:>11} {:>10} {:>8} {:>7}",
+        "concurrency", "KiB/s", "seconds", "errors"
+    );
+    for level in levels {
+        println!(
+            "{:>11} {:>10.0} {:>8.2} {:>7}",
+            level.concurrency,
+            level.throughput(),
+            level.seconds,
+            level.errors
+        );
+    }
+    match recommended_concurrency(levels) {
+        Some(concurrency) => println!("recommended --max-connections {}", concurrency),
+        None => println!("every concurrency level had errors, no recommendation"),
+    }
+}
+
+/// Print the downloaded `books` as a table, or as events with `--json`.
+fn print_library(books: &[LocalBook], json: bool) {
+    if json {
+        for book in books {
+            Event::LocalBook {
+                id: book.id,
+                title: &book.title,
+                pages: book.page_count,
+                size: book.size(),
+                prepared: book.prepared,
+                downloaded: book.downloaded(),
+            }
+            .print();
+        }
+        return;
+    }
+    let width = books
+        .iter()
+        .map(|book| book.title.chars().count())
+        .chain([5])
+        .max()
+        .unwrap_or(0);
+    println!(
+        "{:<width$}  {:>8}  {:>6}  {:>10}  {:<10}  DOWNLOADED",
+        "TITLE", "ID", "PAGES", "SIZE", "STATUS"
+    );
+    for book in books {
+        println!(
+            "{:<width$}  {:>8}  {:>6}  {:>10}  {:<10}  {}",
+            &book.title,
+            book.id,
+            book.page_count,
+            format_size(book.size()),
+            if book.prepared {
+                "prepared"
+            } else {
+                "unprepared"
+            },
+            book.downloaded_date().unwrap_or_default()
+        );
+    }
+}
+
+/// Log that `book` failed, and report it as an event with `--json`.
+fn report_failure(client: &EdukaClient, book: &str, message: String) {
+    error!("{}", &message);
+    client.emit(Event::Error {
+        subject: book,
+        message,
+    });
+}
+
+/// Download every entry of `books` and the ids file, with the exit code of how
+/// that went.
+async fn download(
+    client: Arc<EdukaClient>,
+    mut books: Vec<String>,
+    ids_file: Option<&Path>,
+    metadata_concurrency: usize,
+    prepare_options: Option<&PrepareOptions>,
+) -> Exit {
+    if let Some(ids_file) = ids_file {
+        match read_ids_file(ids_file) {
+            Ok(ids) => books.extend(ids),
+            Err(e) => {
+                error!("reading ids file {:?} failed {}", ids_file, &e);
+                return Exit::InvalidArguments;
+            }
+        }
+    }
+    let mut failed = vec![];
+    let mut outcomes = vec![];
+    for book in &books {
+        if client.is_cancelled() {
+            warn!("{}", EdukaError::Interrupted);
+            print_summary(&outcomes);
+            return Exit::Interrupted;
+        }
+        let (id, is_package) = match target(book) {
+            Ok(Target::Package(id)) => (id, true),
+            Ok(Target::TeachingTool(id)) => (id, false),
+            Ok(Target::Id(id)) => match client.is_package(id).await {
+                Ok(is_package) => (id, is_package),
+                Err(e) => {
+                    let message = format!("looking up {} failed {}", book, &e);
+                    report_failure(&client, book, message.clone());
+                    outcomes.push(BookOutcome::failed(book.clone(), message, Exit::of(&e)));
+                    failed.push(book.clone());
+                    continue;
+                }
+            },
+            Err(message) => {
+                report_failure(&client, book, message.clone());
+                outcomes.push(BookOutcome::failed(
+                    book.clone(),
+                    message,
+                    Exit::InvalidArguments,
+                ));
+                failed.push(book.clone());
+                continue;
+            }
+        };
+        let result = if is_package {
+            download_package(&client, id, metadata_concurrency, prepare_options).await
+        } else {
+            download_teaching_tool(&client, id, prepare_options)
+                .await
+                .map(|outcome| vec![outcome])
+        };
+        match result {
+            Ok(book_outcomes) => {
+                if book_outcomes
+                    .iter()
+                    .any(|book| matches!(book.outcome, Outcome::Failed(..) | Outcome::Skipped))
+                {
+                    failed.push(book.clone());
+                }
+                outcomes.extend(book_outcomes);
+            }
+            Err((message, exit)) => {
+                report_failure(&client, book, format!("{}: {}", book, &message));
+                outcomes.push(BookOutcome::failed(book.clone(), message, exit));
+                failed.push(book.clone());
+            }
+        }
+    }
+    if books.len() > 1 {
+        info!(
+            "{} of {} entries succeeded",
+            books.len() - failed.len(),
+            books.len()
+        );
+        for book in &books {
+            if failed.contains(book) {
+                info!("    failed: {}", book);
+            } else {
+                info!("    ok: {}", book);
+            }
+        }
+    }
+    if outcomes.len() > 1 || !failed.is_empty() {
+        print_summary(&outcomes);
+    }
+    if client.is_cancelled() {
+        return Exit::Interrupted;
+    }
+    run_exit(&outcomes)
+}
+
+/// Verify every book under `output_dir`, repairing and preparing the broken ones
+/// again with `repair_options`.
+async fn verify(
+    client: Arc<EdukaClient>,
+    output_dir: &Path,
+    repair_options: Option<&PrepareOptions>,
+    checksums: bool,
+) -> Exit {
+    let books = match local_books(output_dir) {
+        Ok(books) => books,
+        Err(e) => {
+            error!("listing books failed {}", &e);
+            return Exit::of(&e);
+        }
+    };
+    let mut broken = 0;
+    let mut exit = Exit::Success;
+    for book in &books {
+        if client.is_cancelled() {
+            warn!("{}", EdukaError::Interrupted);
+            return Exit::Interrupted;
+        }
+        let subject = book.path.to_string_lossy();
+        let verification = match client.verify(book, checksums).await {
+            Ok(verification) => verification,
+            Err(e) => {
+                report_failure(
+                    &client,
+                    &subject,
+                    format!("verifying {} failed {}", &book.title, &e),
+                );
+                exit = Exit::of(&e);
+                broken += 1;
+                continue;
+            }
+        };
+        if verification.is_ok() {
+            info!("{} is complete", &book.title);
+            continue;
+        }
+        broken += 1;
+        warn!(
+            "{}: {} missing, {} empty, {} corrupted and {} changed pages, {} unreadable and {} changed outputs",
+            &book.title,
+            verification.missing_pages.len(),
+            verification.empty_pages.len(),
+            verification.corrupted_pages.len(),
+            verification.changed_pages.len(),
+            verification.corrupted_pdfs.len(),
+            verification.changed_outputs.len()
+        );
+        let Some(prepare_options) = repair_options else {
+            exit = Exit::PartialDownload;
+            continue;
+        };
+        let repaired = match client.repair(&verification).await {
+            Ok(()) => match &verification.teaching_tool {
+                Some(teaching_tool) => prepare_teaching_tool(teaching_tool, prepare_options),
+                None => Ok(()),
+            },
+            Err(e) => Err(e),
+        };
+        match repaired {
+            Ok(()) => {
+                info!("repaired {}", &book.title);
+                broken -= 1;
+            }
+            Err(e) => {
+                report_failure(
+                    &client,
+                    &subject,
+                    format!("repairing {} failed {}", &book.title, &e),
+                );
+                exit = Exit::of(&e);
+            }
+        }
+    }
+    info!(
+        "{} of {} books are complete",
+        books.len() - broken,
+        books.len()
+    );
+    exit
+}
+
+/// Remove, or with `dry_run` list, the leftovers in `output_dir`.
+fn clean(output_dir: &Path, dry_run: bool, json: bool) -> Exit {
+    let leftovers = match find_leftovers(output_dir) {
+        Ok(leftovers) => leftovers,
+        Err(e) => {
+            return fail(
+                json,
+                "clean",
+                format!("looking for leftovers failed {}", &e),
+                Exit::of(&e),
+            )
+        }
+    };
+    let mut exit = Exit::Success;
+    for leftover in &leftovers {
+        if dry_run {
+            println!("{}", leftover);
+            continue;
+        }
+        match leftover.remove() {
+            Ok(()) => info!("removed {}", leftover),
+            Err(e) => {
+                exit = fail(
+                    json,
+                    "clean",
+                    format!("removing {} failed {}", leftover, &e),
+                    Exit::of(&e),
+                )
+            }
+        }
+    }
+    if leftovers.is_empty() {
+        info!("nothing to clean");
+    }
+    exit
 }
 
 #[tokio::main]
-async fn main() {
+async fn main() -> ExitCode {
+    if let Err(message) = config::load() {
+        println!("{}", message);
+        return Exit::InvalidArguments.into();
+    }
     let cli = Cli::parse();
-    let client = Arc::new(
-        reqwest::Client::builder()
-            .cookie_store(true)
-            .build()
-            .unwrap(),
-    );
-    let mut login_map = HashMap::new();
-    login_map.insert("username", &cli.username);
-    login_map.insert("password", &cli.password);
-    let login_response = client
-        .post("https://klase.eduka.lt/api/anonymously/login")
-        .json(&login_map)
-        .send()
-        .await;
-    if let Ok(login_response) = login_response {
-        match login_response.status() {
-            reqwest::StatusCode::OK => {
-                if cli.books.is_empty() {
-                    let mut teaching_tools_to_download = vec![];
-                    let mut i = match cli.exploration_start {
-                        Some(exploration_start) => exploration_start,
-                        None => 0,
-                    };
-                    loop {
-                        let mut teaching_tool = TeachingTool {
-                            id: i,
-                            book: Default::default(),
-                        };
-                        println!("trying teaching tool {}", &i);
-                        if let Ok(()) =
-                            fill_teaching_tool_metadata(&client, &mut teaching_tool).await
-                        {
-                            let mut input_string = String::new();
-                            while !(input_string.trim() == "y"
-                                || input_string.trim() == "n"
-                                || input_string.trim() == "cancel")
-                            {
-                                if teaching_tool.book.native_downloadable {
-                                    print!("[NATIVE DOWNLOADABLE]");
-                                }
-                                print!(
-                                    "Should {} be downloaded (y/n/cancel): ",
-                                    &teaching_tool.book.title
-                                );
-                                io::stdout().flush();
-                                input_string.clear();
-                                io::stdin()
-                                    .read_line(&mut input_string)
-                                    .expect("reading user input failed");
-                            }
-                            match input_string.trim().as_ref() {
-                                "y" => {
-                                    teaching_tools_to_download.push(teaching_tool);
-                                }
-                                "cancel" => {
-                                    break;
-                                }
-                                _ => {}
-                            }
-                        }
-                        i += 1;
-                    }
-                    for teaching_tool in teaching_tools_to_download {
-                        if let Ok(()) = download_teaching_tool(&client, &teaching_tool).await {
-                            println!("downloaded {}", &teaching_tool.book.title);
-                            if let Ok(()) = prepare_teaching_tool(&teaching_tool) {
-                                println!("prepared {}", teaching_tool.book.title);
-                            } else {
-                                println!("failed to prepare {}", teaching_tool.book.title);
-                            }
-                        } else {
-                            println!("failed to download {}", &teaching_tool.book.title);
+    if let Err(e) = init_logging(&cli) {
+        println!("opening the log file failed {}", &e);
+        return Exit::of(&e).into();
+    }
+    let json = cli.json;
+    let exit = match cli.command {
+        Command::Login { credentials } => match login(&credentials, json).await {
+            Ok(username) => {
+                info!("logged in as {}", &username);
+                Exit::Success
+            }
+            Err(e) => fail(
+                json,
+                "login",
+                format!("Failed to log in: {}", &e),
+                Exit::of(&e),
+            ),
+        },
+        Command::Explore {
+            credentials,
+            connection,
+            prepare,
+            start,
+            tui,
+            filters,
+            prefetch,
+        } => match logged_in_client(
+            &credentials,
+            connection.options(&cli.output_dir, &cli.name_template, cli.json),
+        )
+        .await
+        {
+            Ok(client) => {
+                explore(
+                    client,
+                    start,
+                    tui,
+                    cli.assume_yes,
+                    &filters,
+                    prefetch as usize,
+                    &prepare.options(&cli.output_dir, &cli.name_template),
+                )
+                .await
+            }
+            Err(e) => fail(
+                json,
+                "login",
+                format!("Failed to log in: {}", &e),
+                Exit::of(&e),
+            ),
+        },
+        Command::Download {
+            credentials,
+            connection,
+            prepare,
+            books,
+            ids_file,
+            metadata_concurrency,
+            no_prepare,
+        } => match logged_in_client(
+            &credentials,
+            connection.options(&cli.output_dir, &cli.name_template, cli.json),
+        )
+        .await
+        {
+            Ok(client) => {
+                let prepare_options = prepare.options(&cli.output_dir, &cli.name_template);
+                download(
+                    client,
+                    books,
+                    ids_file.as_deref(),
+                    metadata_concurrency as usize,
+                    (!no_prepare).then_some(&prepare_options),
+                )
+                .await
+            }
+            Err(e) => fail(
+                json,
+                "login",
+                format!("Failed to log in: {}", &e),
+                Exit::of(&e),
+            ),
+        },
+        Command::Prepare {
+            book_dirs,
+            prepare,
+            bookmarks_only,
+        } => {
+            let prepare_options = prepare.options(&cli.output_dir, &cli.name_template);
+            let mut exit = Exit::Success;
+            for book_dir in &book_dirs {
+                if bookmarks_only {
+                    match rebookmark(book_dir, &prepare_options) {
+                        Ok(()) => info!("rebuilt bookmarks of {:?}", book_dir),
+                        Err(e) => {
+                            exit = fail(
+                                json,
+                                &book_dir.to_string_lossy(),
+                                format!("rebuilding bookmarks of {:?} failed {}", book_dir, &e),
+                                Exit::PrepareFailed,
+                            )
                         }
                     }
                 } else {
-                    for book in cli.books {
-                        match url::Url::parse(&book) {
-                            Ok(url) => match url.path_segments() {
-                                Some(segments) => match segments.last() {
-                                    Some(id_str) => {
-                                        let id = match id_str.parse::<u64>() {
-                                            Ok(id) => id,
-                                            Err(e) => {
-                                                println!(
-                                                    "url {} doesn't contain a book id: {:?}",
-                                                    &url, &e
-                                                );
-                                                return;
-                                            }
-                                        };
-                                        let package = download_package(client.clone(), id).await;
-                                        match package {
-                                            Ok(package) => {
-                                                prepare_package(package).unwrap();
-                                            }
-                                            Err(e) => {
-                                                println!(
-                                                    "downloading package for url {} failed {}",
-                                                    &url, &e
-                                                );
-                                            }
-                                        }
-                                    }
-                                    None => println!(
-                                        "url {} doesn't have final segment, is it a book url?",
-                                        &book
-                                    ),
-                                },
-                                None => println!(
-                                    "url {} doesn't have segments, is it a book url?",
-                                    &book
-                                ),
-                            },
-                            Err(e) => println!(
-                                "skipping downloading book {} as url is invalid: {:?}",
-                                &book, &e
-                            ),
+                    match prepare_book_dir(book_dir, &prepare_options) {
+                        Ok(()) => info!("prepared {:?}", book_dir),
+                        Err(e) => {
+                            exit = fail(
+                                json,
+                                &book_dir.to_string_lossy(),
+                                format!("preparing {:?} failed {}", book_dir, &e),
+                                Exit::PrepareFailed,
+                            )
                         }
                     }
                 }
             }
-            _ => {
-                println!("Failed to log in")
+            exit
+        }
+        Command::Verify {
+            credentials,
+            connection,
+            prepare,
+            repair,
+            verify_checksums,
+        } => match logged_in_client(
+            &credentials,
+            connection.options(&cli.output_dir, &cli.name_template, cli.json),
+        )
+        .await
+        {
+            Ok(client) => {
+                verify(
+                    client,
+                    &cli.output_dir,
+                    repair.then_some(&prepare.options(&cli.output_dir, &cli.name_template)),
+                    verify_checksums,
+                )
+                .await
+            }
+            Err(e) => fail(
+                json,
+                "login",
+                format!("Failed to log in: {}", &e),
+                Exit::of(&e),
+            ),
+        },
+        Command::Calibrate { book_dir, open } => match calibrate::calibrate(&book_dir, open) {
+            Ok(()) => Exit::Success,
+            Err(e) => fail(
+                json,
+                &book_dir.to_string_lossy(),
+                format!("calibrating {:?} failed {}", &book_dir, &e),
+                Exit::of(&e),
+            ),
+        },
+        Command::Clean { dry_run } => clean(&cli.output_dir, dry_run, json),
+        Command::Index => match build_index(&cli.output_dir) {
+            Ok(books) => {
+                info!("indexed the text of {} books", books);
+                Exit::Success
+            }
+            Err(e) => fail(
+                json,
+                "index",
+                format!("indexing failed {}", &e),
+                Exit::of(&e),
+            ),
+        },
+        Command::Grep { query, limit } => match search_index(&cli.output_dir, &query, limit) {
+            Ok(hits) => {
+                for hit in hits {
+                    println!(
+                        "{}\t{}\t{}\tp. {}\t{}",
+                        hit.book_id, &hit.title, &hit.chapter, hit.page, &hit.snippet
+                    );
+                }
+                Exit::Success
+            }
+            Err(e) => fail(
+                json,
+                &query,
+                format!("searching the library failed {}", &e),
+                Exit::of(&e),
+            ),
+        },
+        Command::List {
+            mine: true,
+            credentials,
+            connection,
+        } => match logged_in_client(
+            &credentials,
+            connection.options(&cli.output_dir, &cli.name_template, cli.json),
+        )
+        .await
+        {
+            Ok(client) => match client.my_packages().await {
+                Ok(packages) => {
+                    for package in packages {
+                        println!(
+                            "package {}\t{}\t{}",
+                            package.id, &package.publishing_house, &package.authors
+                        );
+                        for teaching_tool in &package.teaching_tools {
+                            println!("    teaching tool {}", teaching_tool.id);
+                        }
+                    }
+                    Exit::Success
+                }
+                Err(e) => fail(
+                    json,
+                    "list",
+                    format!("listing the account's packages failed {}", &e),
+                    Exit::of(&e),
+                ),
+            },
+            Err(e) => fail(
+                json,
+                "login",
+                format!("Failed to log in: {}", &e),
+                Exit::of(&e),
+            ),
+        },
+        Command::List { mine: false, .. } => match local_books(&cli.output_dir) {
+            Ok(books) => {
+                print_library(&books, json);
+                Exit::Success
             }
+            Err(e) => fail(
+                json,
+                "list",
+                format!("listing books failed {}", &e),
+                Exit::of(&e),
+            ),
+        },
+        Command::Catalog {
+            command: Some(CatalogCommand::Diff { old, new }),
+            ..
+        } => match print_catalog_diff(&old, &new) {
+            Ok(()) => Exit::Success,
+            Err(e) => fail(
+                json,
+                "catalog diff",
+                format!("comparing {:?} and {:?} failed {}", &old, &new, &e),
+                Exit::of(&e),
+            ),
+        },
+        Command::Catalog {
+            command: None,
+            credentials,
+            connection,
+            range: Some(range),
+            out: Some(out),
+            metadata_concurrency,
+        } => match logged_in_client(
+            &credentials,
+            connection.options(&cli.output_dir, &cli.name_template, cli.json),
+        )
+        .await
+        {
+            Ok(client) => match client.catalog(range, metadata_concurrency as usize).await {
+                Ok(catalog) => match write_catalog(&out, &catalog) {
+                    Ok(()) => {
+                        let failed: Vec<String> = catalog
+                            .iter()
+                            .filter(|entry| entry.error.is_some())
+                            .map(|entry| entry.id.to_string())
+                            .collect();
+                        info!(
+                            "{} teaching tools written to {:?}",
+                            catalog.len() - failed.len(),
+                            &out
+                        );
+                        if failed.is_empty() {
+                            Exit::Success
+                        } else {
+                            error!(
+                                "the metadata of {} ids could not be fetched, they are recorded as failed: {}",
+                                failed.len(),
+                                failed.join(", ")
+                            );
+                            Exit::PartialDownload
+                        }
+                    }
+                    Err(e) => fail(
+                        json,
+                        &out.to_string_lossy(),
+                        format!("writing the catalog failed {}", &e),
+                        Exit::of(&e),
+                    ),
+                },
+                Err(e) => fail(
+                    json,
+                    "catalog",
+                    format!("scanning failed {}", &e),
+                    Exit::of(&e),
+                ),
+            },
+            Err(e) => fail(
+                json,
+                "login",
+                format!("Failed to log in: {}", &e),
+                Exit::of(&e),
+            ),
+        },
+        Command::Catalog { .. } => {
+            unreachable!("--range and --out are required without a subcommand")
         }
-    }
+        Command::Search {
+            credentials,
+            connection,
+            query,
+        } => match logged_in_client(
+            &credentials,
+            connection.options(&cli.output_dir, &cli.name_template, cli.json),
+        )
+        .await
+        {
+            Ok(client) => match client.search(&query).await {
+                Ok(hits) => {
+                    for hit in hits {
+                        println!("{}\t{}", hit.id, &hit.title);
+                    }
+                    Exit::Success
+                }
+                Err(e) => fail(
+                    json,
+                    &query,
+                    format!("searching failed {}", &e),
+                    Exit::of(&e),
+                ),
+            },
+            Err(e) => fail(
+                json,
+                "login",
+                format!("Failed to log in: {}", &e),
+                Exit::of(&e),
+            ),
+        },
+        Command::PageUrls {
+            credentials,
+            connection,
+            id,
+        } => match logged_in_client(
+            &credentials,
+            connection.options(&cli.output_dir, &cli.name_template, cli.json),
+        )
+        .await
+        {
+            Ok(client) => {
+                let mut teaching_tool = TeachingTool::new(id);
+                match client.fill_teaching_tool_metadata(&mut teaching_tool).await {
+                    Ok(()) => {
+                        for book in teaching_tool.books() {
+                            let page_urls = book.page_urls.iter().flat_map(PageSource::urls);
+                            if json {
+                                Event::PageUrls {
+                                    id: book.id,
+                                    title: &book.title,
+                                    page_urls: page_urls.collect(),
+                                }
+                                .print();
+                            } else {
+                                for page_url in page_urls {
+                                    println!("{}", page_url);
+                                }
+                            }
+                        }
+                        Exit::Success
+                    }
+                    Err(e) => fail(
+                        json,
+                        &id.to_string(),
+                        format!("resolving pages of teaching tool {} failed {}", &id, &e),
+                        Exit::of(&e),
+                    ),
+                }
+            }
+            Err(e) => fail(
+                json,
+                "login",
+                format!("Failed to log in: {}", &e),
+                Exit::of(&e),
+            ),
+        },
+        Command::Benchmark {
+            credentials,
+            connection,
+            id,
+        } => match logged_in_client(
+            &credentials,
+            connection.options(&cli.output_dir, &cli.name_template, cli.json),
+        )
+        .await
+        {
+            Ok(client) => match client.benchmark(id).await {
+                Ok(levels) => {
+                    print_benchmark(&levels);
+                    Exit::Success
+                }
+                Err(e) => fail(
+                    json,
+                    &id.to_string(),
+                    format!("benchmarking with teaching tool {} failed {}", &id, &e),
+                    Exit::of(&e),
+                ),
+            },
+            Err(e) => fail(
+                json,
+                "login",
+                format!("Failed to log in: {}", &e),
+                Exit::of(&e),
+            ),
+        },
+    };
+    exit.into()
 }
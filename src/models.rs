@@ -0,0 +1,299 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(Deserialize)]
+pub(crate) struct IsDownloadableResponse {
+    #[serde(rename = "isDownloadable")]
+    pub(crate) is_downloadable: bool,
+}
+
+/// Answer of the pages endpoint of a teaching tool, or of one part of it.
+#[derive(Deserialize, Debug)]
+pub(crate) struct PagesResponse {
+    pub(crate) pages: Vec<PageResponse>,
+    #[serde(rename = "pageShift")]
+    pub(crate) page_shift: i64,
+    pub(crate) chapters: Vec<Bookmark>,
+}
+
+/// A page of a `PagesResponse`, of which one kind of image is set.
+#[derive(Deserialize, Debug)]
+pub(crate) struct PageResponse {
+    /// Path of the page image of every width eduka has, keyed by the width
+    #[serde(default)]
+    pub(crate) img: Option<HashMap<String, String>>,
+    #[serde(default)]
+    pub(crate) svg: Option<String>,
+    /// Paths of the tiles of an image cut into them, as rows from the top left
+    #[serde(default)]
+    pub(crate) tiles: Option<Vec<Vec<String>>>,
+    #[serde(default)]
+    pub(crate) pdf: Option<serde_json::Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Bookmark {
+    pub title: String,
+    #[serde(default, rename = "startPage")]
+    pub start_page: u32,
+    #[serde(default)]
+    pub lessons: Vec<Bookmark>,
+}
+
+impl Bookmark {
+    /// PDF page the bookmark points at. Chapters without a start page of their
+    /// own start where their first lesson does.
+    pub fn pdf_page(&self, page_shift: i64) -> u32 {
+        let start_page = if self.start_page == 0 {
+            if let Some(child) = self.lessons.first() {
+                child.start_page
+            } else {
+                0
+            }
+        } else {
+            self.start_page
+        };
+        (start_page as i64 - page_shift) as u32
+    }
+}
+
+/// Bookmark as written to the `{id}.toc.json` sidecar, with the page already
+/// resolved against the page shift.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TocEntry {
+    pub title: String,
+    pub page: u32,
+    #[serde(default)]
+    pub children: Vec<TocEntry>,
+}
+
+pub fn resolve_toc(bookmarks: &[Bookmark], page_shift: i64) -> Vec<TocEntry> {
+    bookmarks
+        .iter()
+        .map(|bookmark| TocEntry {
+            title: bookmark.title.clone(),
+            page: bookmark.pdf_page(page_shift),
+            children: resolve_toc(&bookmark.lessons, page_shift),
+        })
+        .collect()
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Part {
+    #[serde(default)]
+    pub id: u64,
+    pub title: String,
+}
+
+/// Where a page image is downloaded from.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum PageSource {
+    /// A single PNG, JPEG or SVG image
+    Url(String),
+    /// An image cut into tiles, as rows of tile urls from the top left
+    Tiles(Vec<Vec<String>>),
+}
+
+impl PageSource {
+    pub fn urls(&self) -> Vec<String> {
+        match self {
+            PageSource::Url(url) => vec![url.clone()],
+            PageSource::Tiles(rows) => rows.concat(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Default, Clone)]
+pub struct Book {
+    #[serde(default)]
+    pub id: u64,
+    #[serde(default)]
+    pub page_shift: i64,
+    #[serde(default)]
+    pub native_downloadable: bool,
+    #[serde(default)]
+    #[serde(rename = "nothing")]
+    pub title: String,
+    #[serde(rename = "title")]
+    pub collection_title: String,
+    pub parts: Vec<Part>,
+    #[serde(default)]
+    pub page_urls: Vec<PageSource>,
+    #[serde(default)]
+    pub bookmarks: Vec<Bookmark>,
+    /// For a book of only some pages of another, the page of the whole book each
+    /// page is, empty otherwise
+    #[serde(default)]
+    pub original_pages: Vec<u32>,
+    /// Page shift of the whole book the pages were picked from, as `page_shift`
+    /// is 0 once the bookmarks point at the pages kept
+    #[serde(default)]
+    pub original_page_shift: i64,
+}
+
+/// Pages picked with `--pages`, as inclusive ranges of 1-based PDF page numbers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PageRanges(Vec<(u32, u32)>);
+
+impl PageRanges {
+    pub fn contains(&self, page: u32) -> bool {
+        self.0
+            .iter()
+            .any(|(first, last)| (*first..=*last).contains(&page))
+    }
+}
+
+impl std::str::FromStr for PageRanges {
+    type Err = String;
+
+    /// Ranges like `1-50,120-130`, where a range can also be a single page.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut ranges = vec![];
+        for range in s.split(',') {
+            let (first, last) = range.split_once('-').unwrap_or((range, range));
+            let page = |page: &str| {
+                page.trim()
+                    .parse::<u32>()
+                    .ok()
+                    .filter(|page| *page > 0)
+                    .ok_or_else(|| format!("{} is not a page range", range))
+            };
+            let (first, last) = (page(first)?, page(last)?);
+            if last < first {
+                return Err(format!("{} ends before it starts", range));
+            }
+            ranges.push((first, last));
+        }
+        Ok(PageRanges(ranges))
+    }
+}
+
+impl std::fmt::Display for PageRanges {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let ranges: Vec<String> = self
+            .0
+            .iter()
+            .map(|(first, last)| match first == last {
+                true => first.to_string(),
+                false => format!("{}-{}", first, last),
+            })
+            .collect();
+        write!(f, "{}", ranges.join(","))
+    }
+}
+
+/// Bookmarks of the pages kept, `new_page` telling where a page ends up. A
+/// bookmark of a page left out is dropped, or moved to its first lesson kept.
+fn select_bookmarks(
+    bookmarks: &[Bookmark],
+    page_shift: i64,
+    new_page: &impl Fn(u32) -> Option<u32>,
+) -> Vec<Bookmark> {
+    bookmarks
+        .iter()
+        .filter_map(|bookmark| {
+            let lessons = select_bookmarks(&bookmark.lessons, page_shift, new_page);
+            let start_page = new_page(bookmark.pdf_page(page_shift))
+                .or(lessons.first().map(|lesson| lesson.start_page))?;
+            Some(Bookmark {
+                title: bookmark.title.clone(),
+                start_page,
+                lessons,
+            })
+        })
+        .collect()
+}
+
+impl Book {
+    /// The book with only the pages in `ranges`, as a book of its own so it is
+    /// downloaded next to the whole book rather than into it.
+    pub fn select_pages(&self, ranges: &PageRanges) -> Book {
+        let kept: Vec<u32> = (1..=self.page_urls.len() as u32)
+            .filter(|page| ranges.contains(*page))
+            .collect();
+        let new_page = |page: u32| {
+            kept.iter()
+                .position(|kept| *kept == page)
+                .map(|i| i as u32 + 1)
+        };
+        Book {
+            title: format!("{} (pages {})", self.title, ranges),
+            page_shift: 0,
+            // the publisher's PDF is of the whole book
+            native_downloadable: false,
+            page_urls: kept
+                .iter()
+                .map(|page| self.page_urls[*page as usize - 1].clone())
+                .collect(),
+            bookmarks: select_bookmarks(&self.bookmarks, self.page_shift, &new_page),
+            original_pages: kept.clone(),
+            original_page_shift: self.page_shift,
+            ..self.clone()
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TeachingTool {
+    pub id: u64,
+    /// The book, or the first part of a collection of several
+    #[serde(default)]
+    pub book: Book,
+    /// The rest of the parts of a collection of several
+    #[serde(default)]
+    pub other_parts: Vec<Book>,
+    /// Of the package the teaching tool was downloaded with, empty otherwise
+    #[serde(default)]
+    pub authors: String,
+    #[serde(default)]
+    pub publishing_house: String,
+}
+
+impl TeachingTool {
+    pub fn new(id: u64) -> TeachingTool {
+        TeachingTool {
+            id,
+            book: Default::default(),
+            other_parts: vec![],
+            authors: String::new(),
+            publishing_house: String::new(),
+        }
+    }
+
+    pub fn books(&self) -> impl Iterator<Item = &Book> {
+        std::iter::once(&self.book).chain(&self.other_parts)
+    }
+
+    /// Every part as a teaching tool of its own, which is how parts are
+    /// downloaded and prepared, each into its own directory.
+    pub fn parts(&self) -> Vec<TeachingTool> {
+        self.books()
+            .map(|book| TeachingTool {
+                id: self.id,
+                book: book.clone(),
+                other_parts: vec![],
+                authors: self.authors.clone(),
+                publishing_house: self.publishing_house.clone(),
+            })
+            .collect()
+    }
+}
+
+/// A teaching tool found by `EdukaClient::search`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SearchHit {
+    pub id: u64,
+    #[serde(default)]
+    pub title: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Package {
+    pub id: u64,
+    #[serde(default)]
+    pub authors: String,
+    #[serde(default)]
+    pub publishing_house: String,
+    pub teaching_tools: Vec<TeachingTool>,
+}
@@ -0,0 +1,209 @@
+use crate::EdukaError;
+use lopdf::content::Operation;
+use lopdf::{dictionary, Object, ObjectId};
+use std::path::Path;
+
+/// Maps every two byte code of the text layer straight to the UTF-16 code unit it
+/// was made from, so the text can be searched and copied out of the PDF.
+const TO_UNICODE_CMAP: &str = "/CIDInit /ProcSet findresource begin
+12 dict begin
+begincmap
+/CIDSystemInfo << /Registry (Adobe) /Ordering (UCS) /Supplement 0 >> def
+/CMapName /Adobe-Identity-UCS def
+/CMapType 2 def
+1 begincodespacerange
+<0000> <FFFF>
+endcodespacerange
+1 beginbfrange
+<0000> <FFFF> <0000>
+endbfrange
+endcmap
+CMapName currentdict /CMap defineresource pop
+end
+end
+";
+
+/// Width of every glyph of the text layer font, in thousandths of the font size.
+const GLYPH_WIDTH: f32 = 500.0;
+
+/// A word tesseract recognized, with its bounding box in image pixels as left,
+/// top, right and bottom.
+struct Word {
+    text: String,
+    bbox: [f32; 4],
+}
+
+/// Tesseract, run in process on the pages while they are assembled.
+pub(crate) struct Ocr {
+    tesseract: leptess::LepTess,
+}
+
+/// What was recognized on a page.
+pub(crate) struct PageText {
+    pub(crate) hocr: String,
+    pub(crate) text: String,
+}
+
+impl Ocr {
+    /// `langs` are tesseract language codes separated by commas, like `lit,eng`.
+    pub(crate) fn new(langs: &str) -> Result<Ocr, EdukaError> {
+        let langs = langs
+            .split(',')
+            .map(str::trim)
+            .collect::<Vec<_>>()
+            .join("+");
+        Ok(Ocr {
+            tesseract: leptess::LepTess::new(None, &langs).map_err(|_| EdukaError::OCRFailed)?,
+        })
+    }
+
+    pub(crate) fn recognize(&mut self, page: &Path) -> Result<PageText, EdukaError> {
+        self.tesseract
+            .set_image(page)
+            .map_err(|_| EdukaError::OCRFailed)?;
+        let hocr = self
+            .tesseract
+            .get_hocr_text(0)
+            .map_err(|_| EdukaError::OCRFailed)?;
+        let text = self
+            .tesseract
+            .get_utf8_text()
+            .map_err(|_| EdukaError::OCRFailed)?;
+        Ok(PageText { hocr, text })
+    }
+}
+
+fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Words of a page from its hOCR, which tesseract writes one `ocrx_word` span
+/// per word with the bounding box in its title.
+fn hocr_words(hocr: &str) -> Vec<Word> {
+    let mut words = vec![];
+    for span in hocr.split("class='ocrx_word'").skip(1) {
+        let Some(bbox_start) = span.find("bbox ") else {
+            continue;
+        };
+        let bbox: Vec<f32> = span[bbox_start + 5..]
+            .split([';', '\'', '"'])
+            .next()
+            .unwrap_or_default()
+            .split_whitespace()
+            .filter_map(|n| n.parse().ok())
+            .collect();
+        let (Some(start), Some(end)) = (span.find('>'), span.find("</span>")) else {
+            continue;
+        };
+        if bbox.len() != 4 || end < start {
+            continue;
+        }
+        // words can be wrapped in <strong> or <em>
+        let mut text = String::new();
+        let mut in_tag = false;
+        for c in span[start + 1..end].chars() {
+            match c {
+                '<' => in_tag = true,
+                '>' => in_tag = false,
+                c if !in_tag => text.push(c),
+                _ => {}
+            }
+        }
+        let text = unescape(text.trim());
+        if !text.is_empty() {
+            words.push(Word {
+                text,
+                bbox: [bbox[0], bbox[1], bbox[2], bbox[3]],
+            });
+        }
+    }
+    words
+}
+
+/// Add the font of the text layer: it has no glyphs, only a fixed width and a
+/// mapping back to unicode, as the text is never drawn.
+pub(crate) fn add_text_layer_font(doc: &mut lopdf::Document) -> ObjectId {
+    let to_unicode = doc.add_object(lopdf::Stream::new(
+        dictionary! {},
+        TO_UNICODE_CMAP.as_bytes().to_vec(),
+    ));
+    let descriptor = doc.add_object(dictionary! {
+        "Type" => "FontDescriptor",
+        "FontName" => "GlyphLessFont",
+        "Flags" => 5,
+        "FontBBox" => vec![0.into(), 0.into(), (GLYPH_WIDTH as i64).into(), 1000.into()],
+        "ItalicAngle" => 0,
+        "Ascent" => 1000,
+        "Descent" => 0,
+        "CapHeight" => 1000,
+        "StemV" => 80,
+    });
+    let cid_font = doc.add_object(dictionary! {
+        "Type" => "Font",
+        "Subtype" => "CIDFontType2",
+        "BaseFont" => "GlyphLessFont",
+        "CIDSystemInfo" => dictionary! {
+            "Registry" => Object::string_literal("Adobe"),
+            "Ordering" => Object::string_literal("Identity"),
+            "Supplement" => 0,
+        },
+        "FontDescriptor" => descriptor,
+        "DW" => GLYPH_WIDTH as i64,
+        "CIDToGIDMap" => "Identity",
+    });
+    doc.add_object(dictionary! {
+        "Type" => "Font",
+        "Subtype" => "Type0",
+        "BaseFont" => "GlyphLessFont",
+        "Encoding" => "Identity-H",
+        "DescendantFonts" => vec![cid_font.into()],
+        "ToUnicode" => to_unicode,
+    })
+}
+
+/// Invisible text drawing every word of `hocr` over where it is in the page
+/// image, stretched to the width of the word. `scale` converts image pixels to
+/// points and `height` is the height of the page in points.
+pub(crate) fn text_layer(hocr: &str, font: &str, scale: (f32, f32), height: f32) -> Vec<Operation> {
+    let mut operations = vec![
+        Operation::new("BT", vec![]),
+        // neither filled nor stroked
+        Operation::new("Tr", vec![3.into()]),
+    ];
+    for word in hocr_words(hocr) {
+        let units: Vec<u16> = word.text.encode_utf16().collect();
+        let [left, top, right, bottom] = word.bbox;
+        let font_size = (bottom - top) * scale.1;
+        let width = (right - left) * scale.0;
+        if font_size <= 0.0 || width <= 0.0 {
+            continue;
+        }
+        let natural_width = units.len() as f32 * font_size * GLYPH_WIDTH / 1000.0;
+        let bytes = units.iter().flat_map(|unit| unit.to_be_bytes()).collect();
+        operations.extend([
+            Operation::new("Tf", vec![font.into(), font_size.into()]),
+            Operation::new("Tz", vec![(width / natural_width * 100.0).into()]),
+            Operation::new(
+                "Tm",
+                vec![
+                    1.into(),
+                    0.into(),
+                    0.into(),
+                    1.into(),
+                    (left * scale.0).into(),
+                    (height - bottom * scale.1).into(),
+                ],
+            ),
+            Operation::new(
+                "Tj",
+                vec![Object::String(bytes, lopdf::StringFormat::Hexadecimal)],
+            ),
+        ]);
+    }
+    operations.push(Operation::new("ET", vec![]));
+    operations
+}
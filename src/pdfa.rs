@@ -0,0 +1,171 @@
+use crate::archive::xml_escape;
+use crate::models::TeachingTool;
+use crate::prepare::civil_date;
+use crate::EdukaError;
+use lopdf::{dictionary, Object, StringFormat};
+use sha2::{Digest, Sha256};
+
+/// Description of the color profile the pages are declared to be in.
+const PROFILE_DESCRIPTION: &str = "sRGB";
+
+/// D50 white the profile connection space of ICC profiles is relative to.
+const D50: [f64; 3] = [0.9642, 1.0, 0.8249];
+
+/// sRGB primaries adapted to D50, the columns of the profile's matrix.
+const SRGB_PRIMARIES: [[f64; 3]; 3] = [
+    [0.4361, 0.2225, 0.0139],
+    [0.3851, 0.7169, 0.0971],
+    [0.1431, 0.0606, 0.7141],
+];
+
+fn s15_fixed16(value: f64) -> [u8; 4] {
+    ((value * 65536.0).round() as i32).to_be_bytes()
+}
+
+fn xyz_tag(xyz: [f64; 3]) -> Vec<u8> {
+    let mut tag = b"XYZ \0\0\0\0".to_vec();
+    for value in xyz {
+        tag.extend(s15_fixed16(value));
+    }
+    tag
+}
+
+/// ICC v2 display profile of sRGB, with a plain 2.2 gamma for the tone curve,
+/// that the page images are declared in.
+fn srgb_profile() -> Vec<u8> {
+    let mut description = b"desc\0\0\0\0".to_vec();
+    description.extend((PROFILE_DESCRIPTION.len() as u32 + 1).to_be_bytes());
+    description.extend(PROFILE_DESCRIPTION.as_bytes());
+    // the terminating nul, then no Unicode and no ScriptCode description
+    description.extend([0; 1 + 4 + 4 + 2 + 1 + 67]);
+    let mut copyright = b"text\0\0\0\0No copyright, use freely".to_vec();
+    copyright.push(0);
+    // gamma 2.2 as u8Fixed8, padded to four bytes
+    let curve = b"curv\0\0\0\0\0\0\0\x01\x02\x33\0\0".to_vec();
+    let tags: [(&[u8; 4], Vec<u8>); 9] = [
+        (b"desc", description),
+        (b"cprt", copyright),
+        (b"wtpt", xyz_tag(D50)),
+        (b"rXYZ", xyz_tag(SRGB_PRIMARIES[0])),
+        (b"gXYZ", xyz_tag(SRGB_PRIMARIES[1])),
+        (b"bXYZ", xyz_tag(SRGB_PRIMARIES[2])),
+        (b"rTRC", curve.clone()),
+        (b"gTRC", curve.clone()),
+        (b"bTRC", curve),
+    ];
+
+    let mut table = (tags.len() as u32).to_be_bytes().to_vec();
+    let mut data = vec![];
+    let data_start = 128 + 4 + 12 * tags.len();
+    for (signature, tag) in &tags {
+        table.extend(*signature);
+        table.extend(((data_start + data.len()) as u32).to_be_bytes());
+        table.extend((tag.len() as u32).to_be_bytes());
+        data.extend(tag);
+        // every tag starts on a four byte boundary
+        data.resize(data.len().next_multiple_of(4), 0);
+    }
+
+    let size = data_start + data.len();
+    let mut header = vec![];
+    header.extend((size as u32).to_be_bytes());
+    header.extend([0; 4]);
+    header.extend([2, 0x10, 0, 0]);
+    header.extend(b"mntrRGB XYZ ");
+    // 2000-01-01 00:00:00
+    for field in [2000u16, 1, 1, 0, 0, 0] {
+        header.extend(field.to_be_bytes());
+    }
+    header.extend(b"acsp");
+    // platform, flags, manufacturer, model, attributes and rendering intent
+    header.extend([0; 4 + 4 + 4 + 4 + 8 + 4]);
+    for value in D50 {
+        header.extend(s15_fixed16(value));
+    }
+    header.resize(128, 0);
+    [header, table, data].concat()
+}
+
+/// `YYYY-MM-DDThh:mm:ssZ` of a unix time, the date format of XMP.
+fn xmp_date(unix_time: u64) -> String {
+    let (year, month, day) = civil_date(unix_time);
+    let seconds = unix_time % 86400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+/// XMP metadata identifying the PDF as PDF/A-2b, repeating what the document
+/// info dictionary has.
+fn xmp_metadata(teaching_tool: &TeachingTool, producer: &str, unix_time: u64) -> String {
+    let creator = match teaching_tool.authors.is_empty() {
+        true => String::new(),
+        false => format!(
+            "<dc:creator><rdf:Seq><rdf:li>{}</rdf:li></rdf:Seq></dc:creator>",
+            xml_escape(&teaching_tool.authors)
+        ),
+    };
+    format!(
+        r#"<?xpacket begin="{bom}" id="W5M0MpCehiHzreSzNTczkc9d"?>
+<x:xmpmeta xmlns:x="adobe:ns:meta/">
+<rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+<rdf:Description rdf:about="" xmlns:pdfaid="http://www.aiim.org/pdfa/ns/id/" xmlns:dc="http://purl.org/dc/elements/1.1/" xmlns:xmp="http://ns.adobe.com/xap/1.0/" xmlns:pdf="http://ns.adobe.com/pdf/1.3/">
+<pdfaid:part>2</pdfaid:part>
+<pdfaid:conformance>B</pdfaid:conformance>
+<dc:format>application/pdf</dc:format>
+<dc:title><rdf:Alt><rdf:li xml:lang="x-default">{title}</rdf:li></rdf:Alt></dc:title>
+{creator}
+<xmp:CreateDate>{date}</xmp:CreateDate>
+<pdf:Producer>{producer}</pdf:Producer>
+</rdf:Description>
+</rdf:RDF>
+</x:xmpmeta>
+<?xpacket end="w"?>"#,
+        bom = '\u{feff}',
+        title = xml_escape(&teaching_tool.book.title),
+        creator = creator,
+        date = xmp_date(unix_time),
+        producer = xml_escape(producer),
+    )
+}
+
+/// Make `doc` PDF/A-2b: declare the pages sRGB with an embedded profile, add XMP
+/// metadata matching the document info written at `unix_time` and give it a
+/// file identifier. The document info has to be set already.
+pub(crate) fn make_pdfa(
+    doc: &mut lopdf::Document,
+    teaching_tool: &TeachingTool,
+    producer: &str,
+    unix_time: u64,
+) -> Result<(), EdukaError> {
+    doc.version = String::from("1.7");
+    let profile = doc.add_object(lopdf::Stream::new(dictionary! { "N" => 3 }, srgb_profile()));
+    let output_intent = dictionary! {
+        "Type" => "OutputIntent",
+        "S" => "GTS_PDFA1",
+        "OutputConditionIdentifier" => Object::string_literal(PROFILE_DESCRIPTION),
+        "Info" => Object::string_literal(PROFILE_DESCRIPTION),
+        "DestOutputProfile" => profile,
+    };
+    // left uncompressed, so archiving tools can read it without parsing the PDF
+    let metadata = doc.add_object(
+        lopdf::Stream::new(
+            dictionary! { "Type" => "Metadata", "Subtype" => "XML" },
+            xmp_metadata(teaching_tool, producer, unix_time).into_bytes(),
+        )
+        .with_compression(false),
+    );
+    let catalog = doc.catalog_mut()?;
+    catalog.set("OutputIntents", vec![output_intent.into()]);
+    catalog.set("Metadata", metadata);
+    let id = Sha256::digest(format!("{} {}", teaching_tool.book.id, unix_time));
+    let id = Object::String(id[..16].to_vec(), StringFormat::Hexadecimal);
+    doc.trailer.set("ID", vec![id.clone(), id]);
+    Ok(())
+}
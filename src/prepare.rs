@@ -0,0 +1,1989 @@
+use crate::archive::{
+    cbz_page_count, epub_page_count, images_page_count, write_cbz, write_epub, write_images,
+};
+use crate::download::{page_hash, record_output_hash, write_metadata, METADATA_FILE, NATIVE_PDF};
+use crate::encrypt::encrypt_pdf;
+use crate::models::{resolve_toc, Book, Bookmark, Package, TeachingTool, TocEntry};
+use crate::ocr::{add_text_layer_font, text_layer, Ocr, PageText};
+use crate::pdfa::make_pdfa;
+use crate::state::StateDb;
+use crate::EdukaError;
+use image::{ImageDecoder, ImageEncoder};
+use lopdf::dictionary;
+use std::collections::HashMap;
+use std::io::Write;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use std::{fs, io};
+use tracing::{info, warn};
+
+/// How the PDF inside a book directory is named. Either way the id stays in the
+/// name so a book can be found again from its file alone.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum PdfName {
+    /// `{id}.pdf`
+    #[default]
+    Id,
+    /// Named after the name template, like the book directory
+    Title,
+}
+
+/// What the pages of a book are assembled into.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// A PDF with bookmarks and, unless turned off, a text layer
+    #[default]
+    Pdf,
+    /// A comic book archive of the page images, with a ComicInfo.xml
+    Cbz,
+    /// A fixed-layout EPUB of the page images, with the chapters to navigate
+    Epub,
+    /// Just the page images in order and the book's metadata.json, archived
+    /// like `--images-archive` says
+    Images,
+}
+
+/// Archive the page images of `--format images` go into.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ImageArchive {
+    #[default]
+    Zip,
+    TarZst,
+}
+
+/// How book directories were always named, kept as the default so earlier
+/// downloads are still found and resumed.
+pub const DEFAULT_NAME_TEMPLATE: &str = "{title} ;;; {id}";
+
+/// Left in a book directory whose page images were deleted after its PDF was
+/// assembled, so the missing pages aren't downloaded again.
+const IMAGES_DELETED: &str = ".images-deleted";
+
+/// What assembled the PDFs, in their document info.
+const PRODUCER: &str = concat!("eduka-downloader ", env!("CARGO_PKG_VERSION"));
+
+impl Book {
+    /// Name of the directory the book is downloaded into, from a template where
+    /// `{title}` and `{id}` stand for the book's.
+    pub fn dir_name(&self, name_template: &str) -> String {
+        // a blank title would leave the name starting with the separator, which
+        // is trimmed off so the id can't be read back from it
+        let title = match self.title.trim().is_empty() {
+            true => "_",
+            false => &self.title,
+        };
+        file_name_safe(
+            &name_template
+                .replace("{id}", &self.id.to_string())
+                .replace("{title}", title),
+        )
+    }
+
+    pub fn pdf_file_name(&self, pdf_name: PdfName, name_template: &str) -> String {
+        self.output_file_name(pdf_name, name_template, "pdf")
+    }
+
+    /// Name of the file the book is assembled into, named like the PDF.
+    pub fn output_file_name(
+        &self,
+        pdf_name: PdfName,
+        name_template: &str,
+        extension: &str,
+    ) -> String {
+        match pdf_name {
+            PdfName::Id => format!("{}.{}", self.id, extension),
+            PdfName::Title => format!("{}.{}", self.dir_name(name_template), extension),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PrepareOptions {
+    /// Also write word-level hOCR for every page into the book's `hocr` directory
+    pub hocr: bool,
+    /// Write the OCR text of the whole book next to the PDF, named like it with a
+    /// `.txt` extension
+    pub text_sidecar: bool,
+    /// Write the resolved bookmark tree to `{id}.toc.json` next to the PDF
+    pub toc_json: bool,
+    /// Give the PDF an outline from the book's chapters
+    pub bookmarks: bool,
+    /// Write the resolved bookmark tree to this file, as JSON if it ends in
+    /// `.json` and as indented plain text otherwise, `{id}` standing for the
+    /// book's id
+    pub export_toc: Option<PathBuf>,
+    /// Build the outline from this bookmark tree file instead of eduka's
+    /// chapters, in either format of `export_toc`
+    pub toc: Option<PathBuf>,
+    /// Build the outline from both eduka's chapters and the `toc` file
+    pub toc_merge: bool,
+    /// Convert pages to this format before assembling the PDF
+    pub image_format: Option<PageFormat>,
+    /// Quality of pages converted to JPEG
+    pub jpeg_quality: u8,
+    /// Carry embedded ICC color profiles over to re-encoded pages and into the
+    /// PDF
+    pub keep_icc_profiles: bool,
+    /// Resolution assumed for every page when sizing PDF pages. Without it each
+    /// image's own DPI metadata is used, falling back to 96 DPI
+    pub dpi: Option<u32>,
+    /// Write the OCR text of every top-level chapter to `chapters/NN - title.txt`
+    pub chapter_text: bool,
+    /// Also write every top-level chapter as its own PDF, to
+    /// `chapters/NN - title.pdf`
+    pub split_by_chapter: bool,
+    /// Write only the PDFs of the chapters, without the one of the whole book
+    pub chapters_only: bool,
+    /// How the assembled PDF is named
+    pub pdf_name: PdfName,
+    /// Name of book directories, and of PDFs named after the title, with
+    /// `{title}` and `{id}` placeholders
+    pub name_template: String,
+    /// Directory the book directories were downloaded into
+    pub output_dir: PathBuf,
+    /// Assemble the parts of a collection into one PDF instead of one per part
+    pub merge_parts: bool,
+    /// Make the PDF conform to PDF/A-2b, for archiving
+    pub pdfa: bool,
+    /// Encrypt the PDF so it only opens with this password
+    pub user_password: Option<String>,
+    /// Password that lifts the restrictions of an encrypted PDF. Without it
+    /// nobody can
+    pub owner_password: Option<String>,
+    /// Add a searchable text layer. Without it the PDF only has the page images
+    /// and bookmarks, but is done much sooner
+    pub ocr: bool,
+    /// Languages of the text, as tesseract language codes separated by commas
+    pub ocr_lang: String,
+    /// Page shift used instead of the one eduka reports, for every book
+    pub page_shift_override: Option<i64>,
+    /// Page shifts used instead of eduka's for single books, by book id
+    pub page_shifts: HashMap<u64, i64>,
+    /// Added to the page shift of every book, after any override
+    pub page_shift_adjust: i64,
+    /// Delete the page images once the PDF has been read back with every page
+    pub delete_images: bool,
+    /// What the pages are assembled into
+    pub format: OutputFormat,
+    /// Archive of `OutputFormat::Images`
+    pub images_archive: ImageArchive,
+    /// Colors the pages of the PDF are stored in
+    pub color: ColorMode,
+    /// Trim uniform margins off the pages before assembling them
+    pub autocrop: bool,
+    /// Straighten pages scanned at an angle before assembling them
+    pub deskew: bool,
+    /// Embed the image of pages that are exact duplicates of an earlier one only
+    /// once, showing it on every such page
+    pub dedupe_pages: bool,
+}
+
+impl Default for PrepareOptions {
+    fn default() -> Self {
+        PrepareOptions {
+            hocr: false,
+            text_sidecar: true,
+            toc_json: false,
+            bookmarks: true,
+            export_toc: None,
+            toc: None,
+            toc_merge: false,
+            image_format: None,
+            jpeg_quality: 85,
+            keep_icc_profiles: true,
+            dpi: None,
+            chapter_text: false,
+            split_by_chapter: false,
+            chapters_only: false,
+            pdfa: false,
+            user_password: None,
+            owner_password: None,
+            pdf_name: PdfName::Id,
+            name_template: String::from(DEFAULT_NAME_TEMPLATE),
+            output_dir: PathBuf::from("."),
+            merge_parts: false,
+            ocr: true,
+            ocr_lang: String::from("lit"),
+            page_shift_override: None,
+            page_shifts: HashMap::new(),
+            page_shift_adjust: 0,
+            delete_images: false,
+            format: OutputFormat::Pdf,
+            images_archive: ImageArchive::Zip,
+            color: ColorMode::Color,
+            autocrop: false,
+            deskew: false,
+            dedupe_pages: false,
+        }
+    }
+}
+
+impl PrepareOptions {
+    /// Extension of the file books are assembled into.
+    pub fn output_extension(&self) -> &'static str {
+        match (self.format, self.images_archive) {
+            (OutputFormat::Pdf, _) => "pdf",
+            (OutputFormat::Cbz, _) => "cbz",
+            (OutputFormat::Epub, _) => "epub",
+            (OutputFormat::Images, ImageArchive::Zip) => "zip",
+            (OutputFormat::Images, ImageArchive::TarZst) => "tar.zst",
+        }
+    }
+
+    /// Page shift to prepare `book` with, for books eduka reports a wrong one of.
+    pub fn page_shift(&self, book: &Book) -> i64 {
+        self.page_shift_override
+            .or(self.page_shifts.get(&book.id).copied())
+            .unwrap_or(book.page_shift)
+            + self.page_shift_adjust
+    }
+}
+
+/// Downloaded page images of a book directory, in page order.
+fn page_images(book_dir: &Path) -> Result<Vec<PathBuf>, EdukaError> {
+    let mut pages = vec![];
+    for entry in fs::read_dir(book_dir)? {
+        let path = entry?.path();
+        if path.extension().map(|e| e == "png").unwrap_or(false) {
+            if let Some(n) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse::<u64>().ok())
+            {
+                pages.push((n, path));
+            }
+        }
+    }
+    pages.sort_by_key(|(n, _)| *n);
+    Ok(pages.into_iter().map(|(_, path)| path).collect())
+}
+
+/// Write the hOCR recognized on every page to `hocr/N.hocr` next to the PDF.
+/// Unlike the plain text layer this keeps word bounding boxes, which search and
+/// highlighting tools need. It is what tesseract recognized in the `--ocr-lang`
+/// languages while assembling, as ocrmypdf's `--sidecar` only gives plain text.
+fn write_hocr(book_dir: &Path, texts: &[PageText]) -> Result<(), EdukaError> {
+    let hocr_dir = book_dir.join("hocr");
+    fs::create_dir_all(&hocr_dir)?;
+    for (n, text) in texts.iter().enumerate() {
+        fs::write(hocr_dir.join(format!("{}.hocr", n)), &text.hocr)?;
+    }
+    Ok(())
+}
+
+/// Image format pages can be normalized to before assembly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum PageFormat {
+    Png,
+    Jpeg,
+    Webp,
+}
+
+impl PageFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            PageFormat::Png => "png",
+            PageFormat::Jpeg => "jpg",
+            PageFormat::Webp => "webp",
+        }
+    }
+
+    fn image_format(self) -> image::ImageFormat {
+        match self {
+            PageFormat::Png => image::ImageFormat::Png,
+            PageFormat::Jpeg => image::ImageFormat::Jpeg,
+            PageFormat::Webp => image::ImageFormat::WebP,
+        }
+    }
+}
+
+fn write_with_profile<E: ImageEncoder>(
+    mut encoder: E,
+    image: &image::DynamicImage,
+    icc_profile: Option<Vec<u8>>,
+    page: &Path,
+) -> Result<(), EdukaError> {
+    if let Some(icc_profile) = icc_profile {
+        if encoder.set_icc_profile(icc_profile).is_err() {
+            warn!("dropping the color profile of {:?}", page);
+        }
+    }
+    image.write_with_encoder(encoder)?;
+    Ok(())
+}
+
+/// Directory in a book directory the pages are cropped into.
+const CROPPED_DIR: &str = "cropped";
+
+/// Directory in a book directory the pages are straightened into.
+const DESKEWED_DIR: &str = "deskewed";
+
+/// Largest skew looked for, in degrees either way, and how finely.
+const MAX_SKEW: f32 = 5.0;
+const SKEW_STEP: f32 = 0.1;
+
+/// Pages skewed less than this many degrees are left alone, straightening them
+/// would only blur them.
+const MIN_SKEW: f32 = 0.15;
+
+/// Width pages are scaled down to while looking for their skew.
+const SKEW_SAMPLE_WIDTH: u32 = 1000;
+
+/// Angle in degrees the lines of `image` run down to the right at, found as the
+/// angle whose projection of the dark pixels onto the vertical is the most
+/// uneven, which is where text lines fall into the fewest rows.
+fn skew_angle(image: &image::GrayImage) -> Option<f32> {
+    let threshold = otsu_threshold(image.as_raw());
+    let dark: Vec<(f32, f32)> = image
+        .enumerate_pixels()
+        .filter(|(_, _, pixel)| pixel.0[0] < threshold)
+        .map(|(x, y, _)| (x as f32, y as f32))
+        .collect();
+    // too little on the page to tell
+    if dark.len() < 100 {
+        return None;
+    }
+    let reach = (image.width() as f32 * MAX_SKEW.to_radians().tan()).ceil();
+    let mut rows = vec![0u64; image.height() as usize + 2 * reach as usize + 1];
+    let steps = (MAX_SKEW / SKEW_STEP).round() as i32;
+    let mut best = (0, 0.0);
+    for step in -steps..=steps {
+        let angle = step as f32 * SKEW_STEP;
+        let tan = angle.to_radians().tan();
+        rows.iter_mut().for_each(|row| *row = 0);
+        for &(x, y) in &dark {
+            rows[(y - x * tan + reach) as usize] += 1;
+        }
+        let score: u64 = rows.iter().map(|row| row * row).sum();
+        if score > best.0 {
+            best = (score, angle);
+        }
+    }
+    Some(best.1)
+}
+
+/// `image` rotated back by `angle` degrees around its center, the corners left
+/// uncovered filled with the color of its top left one.
+fn rotate(image: &image::RgbImage, angle: f32) -> image::RgbImage {
+    let (width, height) = image.dimensions();
+    let fill = *image.get_pixel(0, 0);
+    let (sin, cos) = angle.to_radians().sin_cos();
+    let (center_x, center_y) = (width as f32 / 2.0, height as f32 / 2.0);
+    image::RgbImage::from_fn(width, height, |x, y| {
+        let (dx, dy) = (x as f32 - center_x, y as f32 - center_y);
+        let source_x = center_x + dx * cos - dy * sin;
+        let source_y = center_y + dx * sin + dy * cos;
+        if source_x < 0.0
+            || source_y < 0.0
+            || source_x >= (width - 1) as f32
+            || source_y >= (height - 1) as f32
+        {
+            return fill;
+        }
+        // bilinear, so text edges stay smooth
+        let (x0, y0) = (source_x as u32, source_y as u32);
+        let (fx, fy) = (source_x.fract(), source_y.fract());
+        let pixel = |x, y| image.get_pixel(x, y).0.map(|channel| channel as f32);
+        let (top_left, top_right) = (pixel(x0, y0), pixel(x0 + 1, y0));
+        let (bottom_left, bottom_right) = (pixel(x0, y0 + 1), pixel(x0 + 1, y0 + 1));
+        image::Rgb(std::array::from_fn(|c| {
+            let top = top_left[c] + (top_right[c] - top_left[c]) * fx;
+            let bottom = bottom_left[c] + (bottom_right[c] - bottom_left[c]) * fx;
+            (top + (bottom - top) * fy).round() as u8
+        }))
+    })
+}
+
+/// Straighten every page scanned at an angle into the `deskewed` directory.
+fn deskew_pages(book_dir: &Path, pages: &[PathBuf]) -> Result<Vec<PathBuf>, EdukaError> {
+    process_pages(book_dir, pages, DESKEWED_DIR, "straightened", |image| {
+        let sample = image.thumbnail(SKEW_SAMPLE_WIDTH, u32::MAX).to_luma8();
+        let angle = skew_angle(&sample)?;
+        if angle.abs() < MIN_SKEW {
+            return None;
+        }
+        Some(image::DynamicImage::ImageRgb8(rotate(
+            &image.to_rgb8(),
+            angle,
+        )))
+    })
+}
+
+/// How far apart in any channel a pixel can be from the margin color and still
+/// count as margin, for the noise of scans and JPEG.
+const MARGIN_TOLERANCE: u8 = 24;
+
+/// Rows or columns that make up more than this share of a page aren't cropped
+/// off, as on a mostly blank page the content could be taken for the margin.
+const MAX_CROP_SHARE: f32 = 0.5;
+
+/// Bounds `(x, y, width, height)` of `image` without its margins of the color of
+/// its top left corner, `None` if there are none or they are too large to trust.
+fn content_bounds(image: &image::RgbImage) -> Option<(u32, u32, u32, u32)> {
+    let (width, height) = image.dimensions();
+    let margin = image.get_pixel(0, 0).0;
+    let is_margin = |x: u32, y: u32| {
+        image
+            .get_pixel(x, y)
+            .0
+            .iter()
+            .zip(margin)
+            .all(|(channel, margin)| channel.abs_diff(margin) <= MARGIN_TOLERANCE)
+    };
+    let row_is_margin = |y: u32| (0..width).all(|x| is_margin(x, y));
+    let column_is_margin = |x: u32| (0..height).all(|y| is_margin(x, y));
+    let top = (0..height).find(|&y| !row_is_margin(y))?;
+    let bottom = (top..height).rev().find(|&y| !row_is_margin(y))? + 1;
+    let left = (0..width).find(|&x| !column_is_margin(x))?;
+    let right = (left..width).rev().find(|&x| !column_is_margin(x))? + 1;
+    let (content_width, content_height) = (right - left, bottom - top);
+    if content_width == width && content_height == height {
+        return None;
+    }
+    if (content_width as f32) < width as f32 * (1.0 - MAX_CROP_SHARE)
+        || (content_height as f32) < height as f32 * (1.0 - MAX_CROP_SHARE)
+    {
+        return None;
+    }
+    Some((left, top, content_width, content_height))
+}
+
+/// Run `process` over every page into the directory `dir` of `book_dir`,
+/// leaving the downloaded pages untouched, and return the pages in the same
+/// order. Pages `process` leaves as they are are copied, the rest saved as PNG.
+/// `action` describes what was done in the summary.
+fn process_pages(
+    book_dir: &Path,
+    pages: &[PathBuf],
+    dir: &str,
+    action: &str,
+    process: impl Fn(&image::DynamicImage) -> Option<image::DynamicImage>,
+) -> Result<Vec<PathBuf>, EdukaError> {
+    let processed_dir = book_dir.join(dir);
+    fs::create_dir_all(&processed_dir)?;
+    let mut processed = 0;
+    let mut processed_pages = vec![];
+    for page in pages {
+        let file_name = page.file_name().ok_or(EdukaError::Unknown)?;
+        let processed_path = processed_dir.join(file_name);
+        let image = image::ImageReader::open(page)?
+            .with_guessed_format()?
+            .decode()?;
+        match process(&image) {
+            Some(image) => {
+                image.save_with_format(&processed_path, image::ImageFormat::Png)?;
+                processed += 1;
+            }
+            None => {
+                fs::copy(page, &processed_path)?;
+            }
+        }
+        processed_pages.push(processed_path);
+    }
+    info!("{} {} of {} pages", action, processed, pages.len());
+    Ok(processed_pages)
+}
+
+/// Trim the uniform margins off every page into the `cropped` directory. Pages
+/// without margins, or with too much of them, are kept as they are.
+fn crop_pages(book_dir: &Path, pages: &[PathBuf]) -> Result<Vec<PathBuf>, EdukaError> {
+    process_pages(
+        book_dir,
+        pages,
+        CROPPED_DIR,
+        "cropped the margins of",
+        |image| {
+            let (x, y, width, height) = content_bounds(&image.to_rgb8())?;
+            Some(image.crop_imm(x, y, width, height))
+        },
+    )
+}
+
+/// Convert every page to `format` into a directory named after it, leaving the
+/// downloaded pages untouched, and return the converted pages in the same order.
+/// Pages eduka already served in a lossless target format are copied as is.
+fn convert_pages(
+    book_dir: &Path,
+    pages: &[PathBuf],
+    format: PageFormat,
+    jpeg_quality: u8,
+    keep_icc_profiles: bool,
+) -> Result<Vec<PathBuf>, EdukaError> {
+    let format_dir = book_dir.join(format.extension());
+    fs::create_dir_all(&format_dir)?;
+    let mut original_size = 0;
+    let mut converted_size = 0;
+    let mut conversions = 0;
+    let mut converted_pages = vec![];
+    for page in pages {
+        let stem = page.file_stem().ok_or(EdukaError::Unknown)?;
+        let converted_path = format_dir.join(stem).with_extension(format.extension());
+        let reader = image::ImageReader::open(page)?.with_guessed_format()?;
+        if reader.format() == Some(format.image_format()) && format != PageFormat::Jpeg {
+            fs::copy(page, &converted_path)?;
+        } else {
+            let mut decoder = reader.into_decoder()?;
+            let icc_profile = decoder.icc_profile()?.filter(|_| keep_icc_profiles);
+            let image = image::DynamicImage::from_decoder(decoder)?;
+            let mut writer = io::BufWriter::new(fs::File::create(&converted_path)?);
+            match format {
+                PageFormat::Png => write_with_profile(
+                    image::codecs::png::PngEncoder::new(&mut writer),
+                    &image,
+                    icc_profile,
+                    page,
+                )?,
+                // JPEG has no alpha channel
+                PageFormat::Jpeg => write_with_profile(
+                    image::codecs::jpeg::JpegEncoder::new_with_quality(&mut writer, jpeg_quality),
+                    &image::DynamicImage::ImageRgb8(image.to_rgb8()),
+                    icc_profile,
+                    page,
+                )?,
+                PageFormat::Webp => write_with_profile(
+                    image::codecs::webp::WebPEncoder::new_lossless(&mut writer),
+                    &image::DynamicImage::ImageRgba8(image.to_rgba8()),
+                    icc_profile,
+                    page,
+                )?,
+            }
+            writer.flush()?;
+            conversions += 1;
+        }
+        original_size += fs::metadata(page)?.len();
+        converted_size += fs::metadata(&converted_path)?.len();
+        converted_pages.push(converted_path);
+    }
+    info!(
+        "converted {} of {} pages to {}, {} bytes before and {} bytes after",
+        conversions,
+        pages.len(),
+        format.extension(),
+        original_size,
+        converted_size
+    );
+    Ok(converted_pages)
+}
+
+/// Resolution stored in a PNG `pHYs` chunk or a JPEG JFIF header, which is what
+/// pages are sized by when no DPI is given.
+fn stored_dpi(bytes: &[u8]) -> Option<(f32, f32)> {
+    let be_u32 = |b: &[u8]| u32::from_be_bytes([b[0], b[1], b[2], b[3]]);
+    let be_u16 = |b: &[u8]| u16::from_be_bytes([b[0], b[1]]);
+    match image::guess_format(bytes).ok()? {
+        image::ImageFormat::Png => {
+            // chunks follow the 8 byte signature as length, type, data and crc
+            let mut offset = 8;
+            while offset + 8 <= bytes.len() {
+                let length = be_u32(&bytes[offset..]) as usize;
+                let data = offset + 8;
+                match &bytes[offset + 4..data] {
+                    b"pHYs" if data + 9 <= bytes.len() && bytes[data + 8] == 1 => {
+                        // pixels per meter
+                        return Some((
+                            be_u32(&bytes[data..]) as f32 * 0.0254,
+                            be_u32(&bytes[data + 4..]) as f32 * 0.0254,
+                        ));
+                    }
+                    b"IDAT" | b"IEND" => return None,
+                    _ => offset = data + length + 4,
+                }
+            }
+            None
+        }
+        image::ImageFormat::Jpeg => {
+            if bytes.len() < 18 || &bytes[6..11] != b"JFIF\0" {
+                return None;
+            }
+            let density = (be_u16(&bytes[14..]) as f32, be_u16(&bytes[16..]) as f32);
+            match bytes[13] {
+                1 => Some(density),
+                2 => Some((density.0 * 2.54, density.1 * 2.54)),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+    .filter(|(x, y)| *x > 0.0 && *y > 0.0)
+}
+
+/// Colors the pages of a PDF are stored in.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ColorMode {
+    /// As eduka served them
+    #[default]
+    Color,
+    /// Shades of gray, a third of the size of color pages
+    Grayscale,
+    /// Only black and white at 1 bit per pixel, for text and line drawings
+    Bilevel,
+}
+
+/// Gray level splitting `pixels` into black and white with the least variance
+/// within each, by Otsu's method.
+fn otsu_threshold(pixels: &[u8]) -> u8 {
+    let mut histogram = [0u64; 256];
+    for &pixel in pixels {
+        histogram[pixel as usize] += 1;
+    }
+    let total = pixels.len() as f64;
+    let sum: f64 = (0..256)
+        .map(|level| level as f64 * histogram[level] as f64)
+        .sum();
+    let (mut black_sum, mut black_count) = (0.0, 0.0);
+    let (mut best_threshold, mut best_variance) = (128, 0.0);
+    for (level, &count) in histogram.iter().enumerate() {
+        black_count += count as f64;
+        if black_count == 0.0 {
+            continue;
+        }
+        let white_count = total - black_count;
+        if white_count == 0.0 {
+            break;
+        }
+        black_sum += level as f64 * count as f64;
+        let black_mean = black_sum / black_count;
+        let white_mean = (sum - black_sum) / white_count;
+        let variance = black_count * white_count * (black_mean - white_mean).powi(2);
+        if variance > best_variance {
+            best_variance = variance;
+            best_threshold = level as u8;
+        }
+    }
+    best_threshold
+}
+
+/// Pack a grayscale image into rows of 1 bit pixels, set for white, each row
+/// padded to whole bytes.
+fn bilevel_rows(image: &image::GrayImage) -> Vec<u8> {
+    let threshold = otsu_threshold(image.as_raw());
+    let row_len = (image.width() as usize).div_ceil(8);
+    let mut bits = vec![0u8; row_len * image.height() as usize];
+    for (x, y, pixel) in image.enumerate_pixels() {
+        if pixel.0[0] > threshold {
+            bits[y as usize * row_len + x as usize / 8] |= 0x80 >> (x % 8);
+        }
+    }
+    bits
+}
+
+/// Image XObject of a page and its size in pixels. JPEGs are embedded as they
+/// are, anything else is decoded and stored losslessly. Bilevel pages are
+/// stored with Flate like the rest, as lopdf has no CCITT or JBIG2 encoder, which
+/// still makes them a fraction of the size of gray ones.
+fn pdf_image(bytes: &[u8], color: ColorMode) -> Result<(lopdf::Stream, u32, u32), EdukaError> {
+    let reader = image::ImageReader::new(io::Cursor::new(bytes)).with_guessed_format()?;
+    let format = reader.format();
+    let decoder = reader.into_decoder()?;
+    let (width, height) = decoder.dimensions();
+    let color_type = decoder.color_type();
+    let image_dictionary = |color_space: &str| {
+        lopdf::dictionary! {
+            "Type" => "XObject",
+            "Subtype" => "Image",
+            "Width" => width,
+            "Height" => height,
+            "ColorSpace" => color_space,
+            "BitsPerComponent" => 8,
+        }
+    };
+    if format == Some(image::ImageFormat::Jpeg) {
+        let color_space = match (color_type, color) {
+            (image::ColorType::L8, ColorMode::Color | ColorMode::Grayscale) => Some("DeviceGray"),
+            (image::ColorType::Rgb8, ColorMode::Color) => Some("DeviceRGB"),
+            _ => None,
+        };
+        if let Some(color_space) = color_space {
+            let mut dictionary = image_dictionary(color_space);
+            dictionary.set("Filter", "DCTDecode");
+            let stream = lopdf::Stream::new(dictionary, bytes.to_vec()).with_compression(false);
+            return Ok((stream, width, height));
+        }
+    }
+    let image = image::DynamicImage::from_decoder(decoder)?;
+    // alpha has no place on a printed page
+    let stream = match (color_type, color) {
+        (_, ColorMode::Bilevel) => {
+            let mut dictionary = image_dictionary("DeviceGray");
+            dictionary.set("BitsPerComponent", 1);
+            lopdf::Stream::new(dictionary, bilevel_rows(&image.to_luma8()))
+        }
+        (image::ColorType::L8 | image::ColorType::L16, _) | (_, ColorMode::Grayscale) => {
+            lopdf::Stream::new(image_dictionary("DeviceGray"), image.to_luma8().into_raw())
+        }
+        _ => lopdf::Stream::new(image_dictionary("DeviceRGB"), image.to_rgb8().into_raw()),
+    };
+    Ok((stream, width, height))
+}
+
+/// Declare the colors of `image` in the ICC profile embedded in the page it was
+/// made of, as an `ICCBased` color space. Profiles are embedded once, however
+/// many pages have them. A profile of other colors than the image is stored in
+/// is dropped, with a warning unless the page was turned gray on purpose.
+fn embed_icc_profile(
+    doc: &mut lopdf::Document,
+    profiles: &mut HashMap<String, lopdf::ObjectId>,
+    image: &mut lopdf::Stream,
+    bytes: &[u8],
+    color: ColorMode,
+    page: &Path,
+) -> Result<(), EdukaError> {
+    let mut decoder = image::ImageReader::new(io::Cursor::new(bytes))
+        .with_guessed_format()?
+        .into_decoder()?;
+    let Some(profile) = decoder.icc_profile()? else {
+        return Ok(());
+    };
+    // the data color space of the profile
+    let components = match (profile.get(16..20), image.dict.get(b"ColorSpace")) {
+        (Some(b"RGB "), Ok(lopdf::Object::Name(name))) if name == b"DeviceRGB" => 3,
+        (Some(b"GRAY"), Ok(lopdf::Object::Name(name))) if name == b"DeviceGray" => 1,
+        _ => {
+            if color == ColorMode::Color {
+                warn!("dropping the color profile of {:?}", page);
+            }
+            return Ok(());
+        }
+    };
+    let profile_id = *profiles.entry(page_hash(&profile)).or_insert_with(|| {
+        doc.add_object(lopdf::Stream::new(
+            lopdf::dictionary! { "N" => components },
+            profile,
+        ))
+    });
+    image.dict.set(
+        "ColorSpace",
+        vec![lopdf::Object::from("ICCBased"), profile_id.into()],
+    );
+    Ok(())
+}
+
+/// Build a PDF with one page per image, each page sized to its image at `dpi`,
+/// the DPI stored in the image, or 96 DPI. With `keep_icc_profiles` the color
+/// profiles of the images are embedded with them. With `ocr` the recognized text
+/// is laid invisibly over every page and returned.
+fn assemble_pdf(
+    pages: &[PathBuf],
+    dpi: Option<u32>,
+    color: ColorMode,
+    dedupe: bool,
+    keep_icc_profiles: bool,
+    mut ocr: Option<&mut Ocr>,
+    pdf_path: &Path,
+) -> Result<Vec<PageText>, EdukaError> {
+    let mut doc = lopdf::Document::with_version("1.5");
+    let pages_id = doc.new_object_id();
+    let font_id = ocr.is_some().then(|| add_text_layer_font(&mut doc));
+    let mut kids: Vec<lopdf::Object> = vec![];
+    let mut texts = vec![];
+    // image of every page embedded so far by its hash, with its size in pixels
+    let mut embedded: HashMap<String, (lopdf::ObjectId, u32, u32)> = HashMap::new();
+    let mut profiles = HashMap::new();
+    for page in pages {
+        let bytes = fs::read(page)?;
+        let hash = page_hash(&bytes);
+        let (image_id, width, height) = match embedded.get(&hash) {
+            Some(&image) if dedupe => image,
+            _ => {
+                let (mut image, width, height) = pdf_image(&bytes, color)?;
+                if keep_icc_profiles {
+                    embed_icc_profile(&mut doc, &mut profiles, &mut image, &bytes, color, page)?;
+                }
+                let image = (doc.add_object(image), width, height);
+                embedded.insert(hash, image);
+                image
+            }
+        };
+        let (dpi_x, dpi_y) = dpi
+            .map(|dpi| (dpi as f32, dpi as f32))
+            .or_else(|| stored_dpi(&bytes))
+            .unwrap_or((96.0, 96.0));
+        let width = width as f32 * 72.0 / dpi_x;
+        let height = height as f32 * 72.0 / dpi_y;
+        let mut content = lopdf::content::Content {
+            operations: vec![
+                lopdf::content::Operation::new("q", vec![]),
+                lopdf::content::Operation::new(
+                    "cm",
+                    vec![
+                        width.into(),
+                        0.into(),
+                        0.into(),
+                        height.into(),
+                        0.into(),
+                        0.into(),
+                    ],
+                ),
+                lopdf::content::Operation::new("Do", vec!["Im0".into()]),
+                lopdf::content::Operation::new("Q", vec![]),
+            ],
+        };
+        if let Some(ocr) = ocr.as_deref_mut() {
+            let text = ocr.recognize(page)?;
+            content.operations.extend(text_layer(
+                &text.hocr,
+                "F0",
+                (72.0 / dpi_x, 72.0 / dpi_y),
+                height,
+            ));
+            texts.push(text);
+        }
+        let mut resources = lopdf::dictionary! {
+            "XObject" => lopdf::dictionary! {
+                "Im0" => image_id,
+            },
+        };
+        if let Some(font_id) = font_id {
+            resources.set("Font", lopdf::dictionary! { "F0" => font_id });
+        }
+        let content_id =
+            doc.add_object(lopdf::Stream::new(lopdf::dictionary! {}, content.encode()?));
+        let page_id = doc.add_object(lopdf::dictionary! {
+            "Type" => "Page",
+            "Parent" => pages_id,
+            "MediaBox" => vec![0.into(), 0.into(), width.into(), height.into()],
+            "Contents" => content_id,
+            "Resources" => resources,
+        });
+        kids.push(page_id.into());
+    }
+    doc.objects.insert(
+        pages_id,
+        lopdf::Object::Dictionary(lopdf::dictionary! {
+            "Type" => "Pages",
+            "Count" => kids.len() as u32,
+            "Kids" => kids,
+        }),
+    );
+    let catalog_id = doc.add_object(lopdf::dictionary! {
+        "Type" => "Catalog",
+        "Pages" => pages_id,
+    });
+    doc.trailer.set("Root", catalog_id);
+    doc.compress();
+    doc.save(pdf_path)?;
+    Ok(texts)
+}
+
+/// A blank title would make an invisible bookmark.
+fn bookmark_title(title: &str) -> String {
+    if title.trim().is_empty() {
+        String::from("Untitled section")
+    } else {
+        String::from(title)
+    }
+}
+
+/// PDF text string in UTF-16BE with a byte order mark, which unlike the default
+/// PDFDocEncoding can hold ą, č, ė, š, ž and the rest of the alphabet.
+fn pdf_text_string(text: &str) -> lopdf::Object {
+    let mut bytes = vec![0xFE, 0xFF];
+    for unit in text.encode_utf16() {
+        bytes.extend(unit.to_be_bytes());
+    }
+    lopdf::Object::String(bytes, lopdf::StringFormat::Hexadecimal)
+}
+
+/// lopdf writes outline titles as the raw UTF-8 of the string, which readers
+/// take for PDFDocEncoding, so re-encode the titles of the outline items
+/// starting at `first` and everything below them.
+fn encode_outline_titles(
+    doc: &mut lopdf::Document,
+    first: lopdf::ObjectId,
+) -> Result<(), EdukaError> {
+    let mut next = Some(first);
+    while let Some(id) = next {
+        let item = doc.get_object_mut(id)?.as_dict_mut()?;
+        if let Ok(title) = item.get(b"Title").and_then(lopdf::Object::as_str) {
+            let title = String::from_utf8_lossy(title).into_owned();
+            item.set("Title", pdf_text_string(&title));
+        }
+        let child = item
+            .get(b"First")
+            .and_then(lopdf::Object::as_reference)
+            .ok();
+        next = item.get(b"Next").and_then(lopdf::Object::as_reference).ok();
+        if let Some(child) = child {
+            encode_outline_titles(doc, child)?;
+        }
+    }
+    Ok(())
+}
+
+fn add_bookmarks(
+    doc: &mut lopdf::Document,
+    toc: &[TocEntry],
+    parent_id: Option<u32>,
+) -> Result<(), EdukaError> {
+    let pages = doc.get_pages();
+    let last_page = *pages.keys().last().ok_or(EdukaError::PositionOffsetError)?;
+    for entry in toc {
+        let page_num = entry.page;
+        // a wrong page shift shouldn't cost the rest of the outline
+        let page_id = match pages.get(&page_num) {
+            Some(page_id) => *page_id,
+            None => {
+                // pages before the first wrap around in `pdf_page`
+                let clamped = if page_num as i32 <= 0 { 1 } else { last_page };
+                warn!(
+                    "{:?} points at page {} of {}, moved to page {}",
+                    &entry.title, page_num as i32, last_page, clamped
+                );
+                pages[&clamped]
+            }
+        };
+
+        let title = bookmark_title(&entry.title);
+        let lo_bookmark = lopdf::Bookmark::new(title, [1.0; 3], 0, page_id);
+        let bookmark_id = doc.add_bookmark(lo_bookmark, parent_id);
+        add_bookmarks(doc, &entry.children, Some(bookmark_id))?;
+    }
+    Ok(())
+}
+
+/// Write the outline and document information of an assembled PDF, replacing
+/// any it had before.
+fn finish_pdf(
+    pdf_path: &Path,
+    teaching_tool: &TeachingTool,
+    options: &PrepareOptions,
+) -> Result<(), EdukaError> {
+    let mut doc = lopdf::Document::load(pdf_path)?;
+    if options.bookmarks {
+        add_outline(&mut doc, &outline_toc(&teaching_tool.book, options)?)?;
+    } else {
+        doc.catalog_mut()?.remove(b"Outlines");
+    }
+    set_page_labels(&mut doc, &teaching_tool.book)?;
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    set_document_info(&mut doc, teaching_tool, now);
+    if options.pdfa {
+        make_pdfa(&mut doc, teaching_tool, PRODUCER, now)?;
+    }
+    // drop the objects of what was just replaced
+    doc.prune_objects();
+    doc.save(pdf_path)?;
+    Ok(())
+}
+
+/// Page labels numbering the pages like the printed book, as ranges of the
+/// 0-based page they start at, their style and first number. Printed page `n`
+/// is page `n - page_shift` of the whole book, so a negative shift is that many
+/// pages of front matter, labelled in roman numerals. `pages` are the pages of
+/// the whole book there are, a new range starting wherever the numbering jumps.
+fn page_label_ranges(pages: &[u32], page_shift: i64) -> Vec<(u32, &'static str, i64)> {
+    let mut ranges: Vec<(u32, &'static str, i64)> = vec![];
+    let mut previous: Option<(&str, i64)> = None;
+    for (index, &page) in pages.iter().enumerate() {
+        let printed = page as i64 + page_shift;
+        let label = match printed < 1 {
+            true => ("r", page as i64),
+            false => ("D", printed),
+        };
+        if previous != Some((label.0, label.1 - 1)) {
+            ranges.push((index as u32, label.0, label.1));
+        }
+        previous = Some(label);
+    }
+    ranges
+}
+
+fn set_page_labels(doc: &mut lopdf::Document, book: &Book) -> Result<(), EdukaError> {
+    let ranges = match book.original_pages.is_empty() {
+        true => {
+            let pages: Vec<u32> = (1..=doc.get_pages().len() as u32).collect();
+            page_label_ranges(&pages, book.page_shift)
+        }
+        false => page_label_ranges(&book.original_pages, book.original_page_shift),
+    };
+    let nums: Vec<lopdf::Object> = ranges
+        .into_iter()
+        .flat_map(|(index, style, start)| {
+            [
+                index.into(),
+                lopdf::dictionary! { "S" => style, "St" => start }.into(),
+            ]
+        })
+        .collect();
+    doc.catalog_mut()?
+        .set("PageLabels", lopdf::dictionary! { "Nums" => nums });
+    Ok(())
+}
+
+/// `D:YYYYMMDDHHmmSSZ` date of a unix time.
+fn pdf_date(unix_time: u64) -> String {
+    let (year, month, day) = civil_date(unix_time);
+    let seconds = unix_time % 86400;
+    format!(
+        "D:{:04}{:02}{:02}{:02}{:02}{:02}Z",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+/// Year, month and day of a unix time, in UTC.
+pub(crate) fn civil_date(unix_time: u64) -> (i64, i64, i64) {
+    // days to the civil date, from Howard Hinnant's date algorithms
+    let days = (unix_time / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Title, authors and publisher of the book in the document information
+/// dictionary, which is what viewers and library tools show.
+fn set_document_info(doc: &mut lopdf::Document, teaching_tool: &TeachingTool, now: u64) {
+    let mut info = lopdf::dictionary! {
+        "Title" => pdf_text_string(&teaching_tool.book.title),
+        "Producer" => pdf_text_string(PRODUCER),
+        "CreationDate" => lopdf::Object::string_literal(pdf_date(now)),
+    };
+    if !teaching_tool.authors.is_empty() {
+        info.set("Author", pdf_text_string(&teaching_tool.authors));
+    }
+    if !teaching_tool.publishing_house.is_empty() {
+        // not a standard key, but kept by viewers that show custom properties
+        info.set(
+            "Publisher",
+            pdf_text_string(&teaching_tool.publishing_house),
+        );
+    }
+    let info = doc.add_object(info);
+    doc.trailer.set("Info", info);
+}
+
+/// Build the outline of `doc` from a bookmark tree.
+fn add_outline(doc: &mut lopdf::Document, toc: &[TocEntry]) -> Result<(), EdukaError> {
+    add_bookmarks(doc, toc, None)?;
+    if let Some(n) = doc.build_outline() {
+        doc.catalog_mut()?
+            .set("Outlines", lopdf::Object::Reference(n));
+        let first = doc
+            .get_object(n)?
+            .as_dict()?
+            .get(b"First")
+            .and_then(lopdf::Object::as_reference);
+        if let Ok(first) = first {
+            encode_outline_titles(doc, first)?;
+        }
+    }
+    Ok(())
+}
+
+/// Redo only the outline of an already prepared book from the `metadata.json`
+/// saved in its directory, without downloading or running OCR again.
+pub fn rebookmark(book_dir: &Path, options: &PrepareOptions) -> Result<(), EdukaError> {
+    let mut teaching_tool = read_metadata(book_dir)?;
+    teaching_tool.book.page_shift = options.page_shift(&teaching_tool.book);
+    let pdf_path = [PdfName::Id, PdfName::Title]
+        .into_iter()
+        .map(|pdf_name| {
+            book_dir.join(
+                teaching_tool
+                    .book
+                    .pdf_file_name(pdf_name, &options.name_template),
+            )
+        })
+        .find(|pdf_path| pdf_path.is_file())
+        .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
+    export_toc(&teaching_tool.book, options)?;
+    finish_pdf(&pdf_path, &teaching_tool, options)
+}
+
+/// The bookmark tree as one `title<TAB>page` line per bookmark, indented two
+/// spaces a level.
+fn toc_text(toc: &[TocEntry], level: usize, text: &mut String) {
+    for entry in toc {
+        text.push_str(&format!(
+            "{}{}\t{}\n",
+            "  ".repeat(level),
+            &entry.title,
+            entry.page as i32
+        ));
+        toc_text(&entry.children, level + 1, text);
+    }
+}
+
+/// Read back a bookmark tree in the text format of `toc_text`. Lines indented
+/// deeper than the line before them are its children.
+fn parse_toc_text(text: &str) -> Result<Vec<TocEntry>, EdukaError> {
+    let mut entries = vec![];
+    for (n, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let title = line.trim_start_matches(' ');
+        let level = (line.len() - title.len()) / 2;
+        let (title, page) = title
+            .rsplit_once('\t')
+            .and_then(|(title, page)| Some((title, page.trim().parse().ok()?)))
+            .ok_or_else(|| {
+                EdukaError::InvalidToc(format!("line {} isn't `title<TAB>page`", n + 1))
+            })?;
+        entries.push((
+            level,
+            TocEntry {
+                title: String::from(title),
+                page,
+                children: vec![],
+            },
+        ));
+    }
+    Ok(nest_toc(&mut entries.into_iter().peekable(), 0))
+}
+
+fn nest_toc(
+    entries: &mut std::iter::Peekable<impl Iterator<Item = (usize, TocEntry)>>,
+    level: usize,
+) -> Vec<TocEntry> {
+    let mut toc = vec![];
+    while let Some((_, mut entry)) = entries.next_if(|(entry_level, _)| *entry_level >= level) {
+        entry.children = nest_toc(entries, level + 1);
+        toc.push(entry);
+    }
+    toc
+}
+
+/// A `--export-toc` or `--toc` path with `{id}` replaced by the book's id.
+fn toc_path(path: &Path, book: &Book) -> PathBuf {
+    PathBuf::from(path.to_string_lossy().replace("{id}", &book.id.to_string()))
+}
+
+fn is_json(path: &Path) -> bool {
+    path.extension().map(|e| e == "json").unwrap_or(false)
+}
+
+/// The bookmark tree the outline of `book` is built from: eduka's chapters,
+/// the `--toc` file instead, or both with `--toc-merge`.
+fn outline_toc(book: &Book, options: &PrepareOptions) -> Result<Vec<TocEntry>, EdukaError> {
+    let mut toc = resolve_toc(&book.bookmarks, book.page_shift);
+    let Some(path) = &options.toc else {
+        return Ok(toc);
+    };
+    let path = toc_path(path, book);
+    let text = fs::read_to_string(&path)?;
+    let imported = if is_json(&path) {
+        serde_json::from_str(&text)?
+    } else {
+        parse_toc_text(&text)?
+    };
+    if options.toc_merge {
+        toc.extend(imported);
+        // stable, so eduka's chapter stays first where both start on a page
+        toc.sort_by_key(|entry| entry.page as i32);
+    } else {
+        toc = imported;
+    }
+    Ok(toc)
+}
+
+/// Write the resolved bookmarks of `book` to where `--export-toc` asks, to be
+/// edited by hand.
+fn export_toc(book: &Book, options: &PrepareOptions) -> Result<(), EdukaError> {
+    let Some(path) = &options.export_toc else {
+        return Ok(());
+    };
+    let path = toc_path(path, book);
+    let toc = resolve_toc(&book.bookmarks, book.page_shift);
+    if is_json(&path) {
+        fs::write(&path, serde_json::to_string_pretty(&toc)?)?;
+    } else {
+        let mut text = String::new();
+        toc_text(&toc, 0, &mut text);
+        fs::write(&path, text)?;
+    }
+    info!("exported the bookmarks of {} to {:?}", &book.title, &path);
+    Ok(())
+}
+
+/// Replace characters that aren't allowed in file names, and drop the trailing
+/// dots and spaces Windows doesn't allow either.
+pub(crate) fn file_name_safe(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    let name = name.trim_end_matches(['.', ' ']).trim_start();
+    if name.is_empty() {
+        String::from("_")
+    } else {
+        String::from(name)
+    }
+}
+
+/// Split the OCR text into one text file per top-level chapter, covering the
+/// pages from where the chapter starts up to where the next one does.
+fn write_chapter_texts(book_dir: &Path, book: &Book, texts: &[PageText]) -> Result<(), EdukaError> {
+    let pages: Vec<&str> = texts.iter().map(|text| text.text.as_str()).collect();
+    let chapters_dir = book_dir.join(CHAPTERS_DIR);
+    fs::create_dir_all(&chapters_dir)?;
+    for (n, (chapter, pdf_pages)) in chapter_pages(book, pages.len()).into_iter().enumerate() {
+        let chapter_text = pages
+            .get(pdf_pages.start - 1..pdf_pages.end - 1)
+            .unwrap_or_default()
+            .join("\n");
+        fs::write(
+            chapters_dir.join(chapter_file_name(n, chapter, "txt")),
+            chapter_text,
+        )?;
+    }
+    Ok(())
+}
+
+const CHAPTERS_DIR: &str = "chapters";
+
+/// `NN - title.extension` of the `n`th top-level chapter, counted from 0.
+fn chapter_file_name(n: usize, chapter: &Bookmark, extension: &str) -> String {
+    format!(
+        "{:02} - {}.{}",
+        n + 1,
+        file_name_safe(&chapter.title),
+        extension
+    )
+}
+
+/// Every top-level chapter of `book` with the PDF pages it spans, counted from 1,
+/// up to the next chapter or the end of its `page_count` pages.
+pub(crate) fn chapter_pages(book: &Book, page_count: usize) -> Vec<(&Bookmark, Range<usize>)> {
+    let starts: Vec<usize> = book
+        .bookmarks
+        .iter()
+        .map(|chapter| chapter.pdf_page(book.page_shift).max(1) as usize)
+        .collect();
+    book.bookmarks
+        .iter()
+        .enumerate()
+        .map(|(n, chapter)| {
+            let start = starts[n];
+            let end = starts
+                .get(n + 1)
+                .copied()
+                .unwrap_or(page_count + 1)
+                .min(page_count + 1)
+                .max(start);
+            (chapter, start..end)
+        })
+        .collect()
+}
+
+/// Write every top-level chapter of the PDF at `pdf_path` as its own PDF, to
+/// `chapters/NN - title.pdf`, numbered like the printed pages it has.
+fn write_chapter_pdfs(
+    book_dir: &Path,
+    book: &Book,
+    pdf_path: &Path,
+    options: &PrepareOptions,
+) -> Result<(), EdukaError> {
+    let doc = lopdf::Document::load(pdf_path)?;
+    let page_count = doc.get_pages().len();
+    let chapters_dir = book_dir.join(CHAPTERS_DIR);
+    fs::create_dir_all(&chapters_dir)?;
+    for (n, (chapter, pdf_pages)) in chapter_pages(book, page_count).into_iter().enumerate() {
+        if pdf_pages.is_empty() {
+            warn!("skipping {}, it has no pages in the PDF", &chapter.title);
+            continue;
+        }
+        let mut chapter_doc = doc.clone();
+        let other_pages: Vec<u32> = (1..=page_count)
+            .filter(|page| !pdf_pages.contains(page))
+            .map(|page| page as u32)
+            .collect();
+        chapter_doc.delete_pages(&other_pages);
+        // the outline points into the whole book
+        chapter_doc.catalog_mut()?.remove(b"Outlines");
+        let first_printed = pdf_pages.start as i64 + book.page_shift;
+        if first_printed >= 1 {
+            chapter_doc.catalog_mut()?.set(
+                "PageLabels",
+                lopdf::dictionary! {
+                    "Nums" => vec![
+                        0.into(),
+                        lopdf::dictionary! { "S" => "D", "St" => first_printed }.into(),
+                    ]
+                },
+            );
+        } else {
+            chapter_doc.catalog_mut()?.remove(b"PageLabels");
+        }
+        chapter_doc.prune_objects();
+        if let Some(user_password) = &options.user_password {
+            encrypt_pdf(
+                &mut chapter_doc,
+                user_password,
+                options.owner_password.as_deref(),
+            )?;
+        }
+        chapter_doc.save(chapters_dir.join(chapter_file_name(n, chapter, "pdf")))?;
+    }
+    info!(
+        "split {} into {} chapters",
+        &book.title,
+        book.bookmarks.len()
+    );
+    Ok(())
+}
+
+/// The `metadata.json` a book directory is downloaded with.
+pub fn read_metadata(book_dir: &Path) -> Result<TeachingTool, EdukaError> {
+    Ok(serde_json::from_str(&fs::read_to_string(
+        book_dir.join(METADATA_FILE),
+    )?)?)
+}
+
+/// Keep a corrected page shift in the `metadata.json` of a book directory, so
+/// the book is prepared with it from then on.
+pub fn save_page_shift(book_dir: &Path, page_shift: i64) -> Result<(), EdukaError> {
+    let mut teaching_tool = read_metadata(book_dir)?;
+    teaching_tool.book.page_shift = page_shift;
+    write_metadata(book_dir, &teaching_tool)
+}
+
+pub fn prepare_teaching_tool(
+    teaching_tool: &TeachingTool,
+    options: &PrepareOptions,
+) -> Result<(), EdukaError> {
+    if options.merge_parts && !teaching_tool.other_parts.is_empty() {
+        return prepare_merged_parts(teaching_tool, options);
+    }
+    for mut part in teaching_tool.parts() {
+        part.book.page_shift = options.page_shift(&part.book);
+        prepare_book(
+            &options
+                .output_dir
+                .join(part.book.dir_name(&options.name_template)),
+            &part,
+            options,
+        )?;
+    }
+    Ok(())
+}
+
+/// `bookmarks` of a part with their pages moved to where the part starts in a
+/// merged PDF, with a page shift of 0. Pages before the part's first are moved
+/// to it, so they stay within the part.
+fn shift_bookmarks(bookmarks: &[Bookmark], page_shift: i64, offset: u32) -> Vec<Bookmark> {
+    bookmarks
+        .iter()
+        .map(|bookmark| Bookmark {
+            title: bookmark.title.clone(),
+            start_page: match (bookmark.start_page, bookmark.lessons.is_empty()) {
+                // chapters without a start page still start where their first lesson does
+                (0, false) => 0,
+                (0, true) => offset + 1,
+                (start_page, _) => (start_page as i64 - page_shift).max(1) as u32 + offset,
+            },
+            lessons: shift_bookmarks(&bookmark.lessons, page_shift, offset),
+        })
+        .collect()
+}
+
+/// Assemble all parts of a collection into one PDF in a directory named after
+/// the collection, with a top-level bookmark for every part holding its
+/// chapters.
+fn prepare_merged_parts(
+    teaching_tool: &TeachingTool,
+    options: &PrepareOptions,
+) -> Result<(), EdukaError> {
+    let mut pages = vec![];
+    let mut bookmarks = vec![];
+    let mut book_dirs = vec![];
+    for book in teaching_tool.books() {
+        let offset = pages.len() as u32;
+        bookmarks.push(Bookmark {
+            title: book.title.clone(),
+            start_page: offset + 1,
+            lessons: shift_bookmarks(&book.bookmarks, options.page_shift(book), offset),
+        });
+        let book_dir = options
+            .output_dir
+            .join(book.dir_name(&options.name_template));
+        if images_deleted(&book_dir) {
+            return Err(EdukaError::ImagesDeleted);
+        }
+        pages.extend(book_pages(&book_dir, options)?);
+        book_dirs.push(book_dir);
+    }
+    let merged = Book {
+        id: teaching_tool.id,
+        page_shift: 0,
+        title: teaching_tool.book.collection_title.clone(),
+        collection_title: teaching_tool.book.collection_title.clone(),
+        parts: teaching_tool.book.parts.clone(),
+        bookmarks,
+        ..Default::default()
+    };
+    let merged_dir = options
+        .output_dir
+        .join(merged.dir_name(&options.name_template));
+    fs::create_dir_all(&merged_dir)?;
+    let merged = TeachingTool {
+        id: teaching_tool.id,
+        book: merged,
+        other_parts: vec![],
+        authors: teaching_tool.authors.clone(),
+        publishing_house: teaching_tool.publishing_house.clone(),
+    };
+    // lets `prepare --bookmarks-only` redo the outline of the merged PDF
+    write_metadata(&merged_dir, &merged)?;
+    let output_path = merged_dir.join(merged.book.output_file_name(
+        options.pdf_name,
+        &options.name_template,
+        options.output_extension(),
+    ));
+    write_output(&merged_dir, &output_path, &merged, &pages, options)?;
+    if options.delete_images {
+        delete_images_after(&book_dirs, &output_path, options, pages.len())?;
+    }
+    Ok(())
+}
+
+/// Assemble the PDF of an already downloaded book directory again, reading the
+/// book from its `metadata.json` instead of asking eduka. Directories from
+/// before there was one are prepared without bookmarks, knowing only what their
+/// `{title} ;;; {id}` name tells.
+pub fn prepare_book_dir(book_dir: &Path, options: &PrepareOptions) -> Result<(), EdukaError> {
+    let mut teaching_tool = match read_metadata(book_dir) {
+        Ok(teaching_tool) => teaching_tool,
+        Err(EdukaError::IOError(e)) if e.kind() == io::ErrorKind::NotFound => {
+            let name = book_dir
+                .file_name()
+                .and_then(|name| name.to_str())
+                .unwrap_or_default();
+            let Some((title, id)) = name.rsplit_once(" ;;; ") else {
+                return Err(EdukaError::IOError(e));
+            };
+            warn!(
+                "{:?} has no {}, preparing it without bookmarks",
+                book_dir, METADATA_FILE
+            );
+            let id = id.parse().map_err(|_| EdukaError::IOError(e))?;
+            TeachingTool {
+                book: Book {
+                    id,
+                    title: String::from(title),
+                    ..Default::default()
+                },
+                ..TeachingTool::new(id)
+            }
+        }
+        Err(e) => return Err(e),
+    };
+    teaching_tool.book.page_shift = options.page_shift(&teaching_tool.book);
+    prepare_book(book_dir, &teaching_tool, options)
+}
+
+/// Prepare the book of a teaching tool of a single part.
+fn prepare_book(
+    book_dir: &Path,
+    teaching_tool: &TeachingTool,
+    options: &PrepareOptions,
+) -> Result<(), EdukaError> {
+    let book = &teaching_tool.book;
+    let native_pdf = book_dir.join(NATIVE_PDF);
+    if native_pdf.is_file() {
+        // it already has the publisher's text and outline
+        let pdf_path = book_dir.join(book.pdf_file_name(options.pdf_name, &options.name_template));
+        fs::copy(&native_pdf, &pdf_path)?;
+        record_output_hash(book_dir, &pdf_path)?;
+        info!("using the publisher's PDF of {}", &book.title);
+        if options.pdfa || options.user_password.is_some() {
+            warn!(
+                "the publisher's PDF of {} is kept as it is, not as PDF/A or encrypted",
+                &book.title
+            );
+        }
+        return Ok(());
+    }
+    let output_path = book_dir.join(book.output_file_name(
+        options.pdf_name,
+        &options.name_template,
+        options.output_extension(),
+    ));
+    if images_deleted(book_dir) {
+        if output_path.is_file() {
+            info!("{} is prepared and its page images deleted", &book.title);
+            return Ok(());
+        }
+        return Err(EdukaError::ImagesDeleted);
+    }
+    let pages = book_pages(book_dir, options)?;
+    write_output(book_dir, &output_path, teaching_tool, &pages, options)?;
+    record_prepared(&options.output_dir, book_dir, &output_path);
+    if options.delete_images {
+        delete_images_after(
+            &[book_dir.to_path_buf()],
+            &output_path,
+            options,
+            pages.len(),
+        )?;
+    }
+    Ok(())
+}
+
+/// Record in the state database of `output_dir` that the book in `book_dir`
+/// was prepared into `output_path`, only warning if it can't be.
+fn record_prepared(output_dir: &Path, book_dir: &Path, output_path: &Path) {
+    let (Some(dir), Some(name), true) = (
+        book_dir.file_name(),
+        output_path.file_name(),
+        output_path.is_file(),
+    ) else {
+        return;
+    };
+    let recorded = fs::read(output_path)
+        .map_err(EdukaError::from)
+        .and_then(|bytes| {
+            StateDb::open(output_dir)?.record_output(
+                &dir.to_string_lossy(),
+                &name.to_string_lossy(),
+                &page_hash(&bytes),
+            )
+        });
+    if let Err(e) = recorded {
+        warn!("recording the prepared book failed {}", &e);
+    }
+}
+
+/// Assemble `pages` into the output of `options.format` at `output_path`.
+fn write_output(
+    book_dir: &Path,
+    output_path: &Path,
+    teaching_tool: &TeachingTool,
+    pages: &[PathBuf],
+    options: &PrepareOptions,
+) -> Result<(), EdukaError> {
+    match options.format {
+        OutputFormat::Pdf => write_pdf(book_dir, teaching_tool, pages, options)?,
+        OutputFormat::Cbz | OutputFormat::Epub => {
+            let toc = match options.bookmarks {
+                true => outline_toc(&teaching_tool.book, options)?,
+                false => vec![],
+            };
+            export_toc(&teaching_tool.book, options)?;
+            match options.format {
+                OutputFormat::Epub => write_epub(output_path, teaching_tool, pages, &toc)?,
+                _ => write_cbz(output_path, teaching_tool, pages, &toc)?,
+            }
+        }
+        OutputFormat::Images => {
+            export_toc(&teaching_tool.book, options)?;
+            write_images(output_path, teaching_tool, pages, options.images_archive)?
+        }
+    }
+    record_output_hash(book_dir, output_path)
+}
+
+/// Pages of a book directory as they go into the PDF, converted if asked to.
+fn book_pages(book_dir: &Path, options: &PrepareOptions) -> Result<Vec<PathBuf>, EdukaError> {
+    let book_path = fs::canonicalize(book_dir)?;
+    let mut pages = page_images(&book_path)?;
+    // straightened first, so the corners it uncovers are cropped off with the margins
+    if options.deskew {
+        pages = deskew_pages(&book_path, &pages)?;
+    }
+    if options.autocrop {
+        pages = crop_pages(&book_path, &pages)?;
+    }
+    match options.image_format {
+        Some(format) => convert_pages(
+            &book_path,
+            &pages,
+            format,
+            options.jpeg_quality,
+            options.keep_icc_profiles,
+        ),
+        None => Ok(pages),
+    }
+}
+
+/// Whether the page images of `book_dir` were deleted after preparing it.
+pub(crate) fn images_deleted(book_dir: &Path) -> bool {
+    book_dir.join(IMAGES_DELETED).exists()
+}
+
+/// Delete the page images of `book_dir`, and the converted ones, marking the
+/// directory so they aren't downloaded again.
+pub(crate) fn delete_page_images(book_dir: &Path) -> Result<(), EdukaError> {
+    for page in page_images(book_dir)? {
+        fs::remove_file(page)?;
+    }
+    let format_dirs = <PageFormat as clap::ValueEnum>::value_variants()
+        .iter()
+        .map(|format| format.extension());
+    for dir in format_dirs.chain([CROPPED_DIR, DESKEWED_DIR]) {
+        let dir = book_dir.join(dir);
+        if dir.is_dir() {
+            fs::remove_dir_all(dir)?;
+        }
+    }
+    fs::write(book_dir.join(IMAGES_DELETED), "")?;
+    Ok(())
+}
+
+/// Delete the page images of `book_dirs` once the output at `output_path` reads
+/// back with all `page_count` pages, keeping them if it doesn't.
+fn delete_images_after(
+    book_dirs: &[PathBuf],
+    output_path: &Path,
+    options: &PrepareOptions,
+    page_count: usize,
+) -> Result<(), EdukaError> {
+    let output_pages = match options.format {
+        OutputFormat::Pdf => lopdf::Document::load(output_path)?.get_pages().len(),
+        OutputFormat::Cbz => cbz_page_count(output_path)?,
+        OutputFormat::Epub => epub_page_count(output_path)?,
+        OutputFormat::Images => images_page_count(output_path, options.images_archive)?,
+    };
+    if output_pages != page_count {
+        warn!(
+            "keeping the page images, {:?} has {} of {} pages",
+            output_path, output_pages, page_count
+        );
+        return Ok(());
+    }
+    for book_dir in book_dirs {
+        delete_page_images(book_dir)?;
+    }
+    Ok(())
+}
+
+/// Assemble `pages` into the PDF of the book of `teaching_tool` in `book_dir`,
+/// with everything that is written alongside it.
+fn write_pdf(
+    book_dir: &Path,
+    teaching_tool: &TeachingTool,
+    pages: &[PathBuf],
+    options: &PrepareOptions,
+) -> Result<(), EdukaError> {
+    let book = &teaching_tool.book;
+    let pdf_file_name = book.pdf_file_name(options.pdf_name, &options.name_template);
+    let pdf_path = book_dir.join(&pdf_file_name);
+    let spinner = indicatif::ProgressBar::new_spinner().with_message(format!(
+        "assembling {}{}",
+        &book.title,
+        if options.ocr { " and running OCR" } else { "" }
+    ));
+    spinner.enable_steady_tick(Duration::from_millis(100));
+    let assembled = if options.ocr {
+        Ocr::new(&options.ocr_lang).and_then(|mut ocr| {
+            assemble_pdf(
+                pages,
+                options.dpi,
+                options.color,
+                options.dedupe_pages,
+                options.keep_icc_profiles,
+                Some(&mut ocr),
+                &pdf_path,
+            )
+        })
+    } else {
+        assemble_pdf(
+            pages,
+            options.dpi,
+            options.color,
+            options.dedupe_pages,
+            options.keep_icc_profiles,
+            None,
+            &pdf_path,
+        )
+    };
+    spinner.finish_and_clear();
+    let texts = assembled?;
+    // both are made from the recognized text
+    let chapter_text = options.chapter_text && options.ocr;
+    let hocr = options.hocr && options.ocr;
+    if (options.chapter_text || options.hocr) && !options.ocr {
+        info!("skipping chapter text and hOCR as OCR is turned off");
+    }
+
+    // named like the PDF, so it is found next to it
+    if options.ocr && options.text_sidecar {
+        let text: Vec<&str> = texts.iter().map(|text| text.text.as_str()).collect();
+        // pages are separated by form feeds
+        fs::write(pdf_path.with_extension("txt"), text.join("\x0c"))?;
+    }
+
+    // the resolved pages, to compare with the PDF when the shift is off
+    if options.toc_json {
+        let toc = resolve_toc(&book.bookmarks, book.page_shift);
+        fs::write(
+            book_dir.join(format!("{}.toc.json", &book.id)),
+            serde_json::to_string_pretty(&toc)?,
+        )?;
+    }
+    export_toc(book, options)?;
+    finish_pdf(&pdf_path, teaching_tool, options)?;
+    if options.split_by_chapter || options.chapters_only {
+        write_chapter_pdfs(book_dir, book, &pdf_path, options)?;
+    }
+    if options.chapters_only {
+        fs::remove_file(&pdf_path)?;
+    } else if let Some(user_password) = &options.user_password {
+        let mut doc = lopdf::Document::load(&pdf_path)?;
+        encrypt_pdf(&mut doc, user_password, options.owner_password.as_deref())?;
+        doc.save(&pdf_path)?;
+    }
+    if chapter_text {
+        write_chapter_texts(book_dir, book, &texts)?;
+    }
+    if hocr {
+        write_hocr(book_dir, &texts)?;
+    }
+    Ok(())
+}
+
+pub fn prepare_package(package: Package, options: &PrepareOptions) -> Result<(), EdukaError> {
+    for teaching_tool in &package.teaching_tools {
+        prepare_teaching_tool(teaching_tool, options)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::codecs::jpeg::{JpegEncoder, PixelDensity};
+
+    /// An empty directory of its own for a test.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("eduka-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Width and height in points of every page of the PDF at `pdf_path`.
+    fn page_sizes(pdf_path: &Path) -> Vec<(f32, f32)> {
+        let doc = lopdf::Document::load(pdf_path).unwrap();
+        doc.get_pages()
+            .values()
+            .map(|&id| {
+                let media_box = doc
+                    .get_dictionary(id)
+                    .unwrap()
+                    .get(b"MediaBox")
+                    .and_then(lopdf::Object::as_array)
+                    .unwrap();
+                (
+                    media_box[2].as_float().unwrap(),
+                    media_box[3].as_float().unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn picked_pages_are_labelled_as_printed() {
+        // two pages of front matter, then printed page 1 on PDF page 3
+        assert_eq!(
+            page_label_ranges(&[1, 2, 3, 4, 5], -2),
+            [(0, "r", 1), (2, "D", 1)]
+        );
+        assert_eq!(page_label_ranges(&[1, 2, 3], 4), [(0, "D", 5)]);
+        let book = Book {
+            page_urls: vec![crate::PageSource::Url(String::new()); 200],
+            page_shift: -2,
+            ..Default::default()
+        }
+        .select_pages(&"2,50-52,120".parse().unwrap());
+        assert_eq!(book.page_shift, 0);
+        assert_eq!(
+            page_label_ranges(&book.original_pages, book.original_page_shift),
+            [(0, "r", 2), (1, "D", 48), (4, "D", 118)]
+        );
+    }
+
+    #[test]
+    fn bookmarks_of_a_part_stay_within_it() {
+        let bookmark = |title: &str, start_page, lessons| Bookmark {
+            title: String::from(title),
+            start_page,
+            lessons,
+        };
+        let bookmarks = [
+            bookmark("Turinys", 0, vec![]),
+            bookmark("Įvadas", 1, vec![]),
+            bookmark("1 skyrius", 0, vec![bookmark("1.1", 5, vec![])]),
+        ];
+        let pages: Vec<u32> = shift_bookmarks(&bookmarks, 2, 100)
+            .iter()
+            .map(|bookmark| bookmark.pdf_page(0))
+            .collect();
+        assert_eq!(pages, [101, 101, 103]);
+    }
+
+    #[test]
+    fn pages_are_sized_by_dpi() {
+        let dir = test_dir("dpi");
+        let image = image::RgbImage::new(600, 300);
+        // 150 DPI in the JFIF header
+        let mut jpeg = vec![];
+        let mut encoder = JpegEncoder::new(&mut jpeg);
+        encoder.set_pixel_density(PixelDensity::dpi(150));
+        encoder.encode_image(&image).unwrap();
+        fs::write(dir.join("0.jpg"), jpeg).unwrap();
+        // no pHYs chunk
+        image.save(dir.join("1.png")).unwrap();
+        let pages = [dir.join("0.jpg"), dir.join("1.png")];
+        let pdf_path = dir.join("book.pdf");
+
+        assemble_pdf(&pages, None, ColorMode::Color, false, true, None, &pdf_path).unwrap();
+        // 600 pixels at 150 DPI are 4 inches, at the 96 DPI fallback 6.25 inches
+        assert_eq!(page_sizes(&pdf_path), [(288.0, 144.0), (450.0, 225.0)]);
+
+        assemble_pdf(
+            &pages,
+            Some(300),
+            ColorMode::Color,
+            false,
+            true,
+            None,
+            &pdf_path,
+        )
+        .unwrap();
+        assert_eq!(page_sizes(&pdf_path), [(144.0, 72.0), (144.0, 72.0)]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn color_profiles_are_embedded() {
+        let dir = test_dir("icc");
+        // only the data color space of the profile is looked at
+        let mut profile = vec![0; 128];
+        profile[16..20].copy_from_slice(b"RGB ");
+        let mut png = vec![];
+        let mut encoder = image::codecs::png::PngEncoder::new(&mut png);
+        encoder.set_icc_profile(profile.clone()).unwrap();
+        image::DynamicImage::ImageRgb8(image::RgbImage::new(8, 8))
+            .write_with_encoder(encoder)
+            .unwrap();
+        fs::write(dir.join("0.png"), &png).unwrap();
+        fs::write(dir.join("1.png"), &png).unwrap();
+        let pages = [dir.join("0.png"), dir.join("1.png")];
+        let pdf_path = dir.join("book.pdf");
+
+        assemble_pdf(&pages, None, ColorMode::Color, false, true, None, &pdf_path).unwrap();
+        let doc = lopdf::Document::load(&pdf_path).unwrap();
+        let color_spaces: Vec<&lopdf::Object> = doc
+            .objects
+            .values()
+            .filter_map(|object| object.as_stream().ok())
+            .filter_map(|stream| stream.dict.get(b"ColorSpace").ok())
+            .collect();
+        assert_eq!(color_spaces.len(), 2);
+        let profile_ids: std::collections::HashSet<_> = color_spaces
+            .iter()
+            .map(|color_space| {
+                let color_space = color_space.as_array().unwrap();
+                assert_eq!(color_space[0].as_name().unwrap(), b"ICCBased");
+                color_space[1].as_reference().unwrap()
+            })
+            .collect();
+        // embedded once for both pages
+        assert_eq!(profile_ids.len(), 1);
+        let profile_id = *profile_ids.iter().next().unwrap();
+        let embedded = doc.get_object(profile_id).unwrap().as_stream().unwrap();
+        assert_eq!(embedded.decompressed_content().unwrap(), profile);
+
+        assemble_pdf(
+            &pages,
+            None,
+            ColorMode::Color,
+            false,
+            false,
+            None,
+            &pdf_path,
+        )
+        .unwrap();
+        let doc = lopdf::Document::load(&pdf_path).unwrap();
+        assert!(doc
+            .objects
+            .values()
+            .filter_map(|object| object.as_stream().ok())
+            .filter_map(|stream| stream.dict.get(b"ColorSpace").ok())
+            .all(|color_space| color_space.as_name().ok() == Some(&b"DeviceRGB"[..])));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn titles_with_nothing_to_show_get_a_placeholder() {
+        // kept as they are in UTF-16, where unidecode made them empty
+        assert_eq!(bookmark_title("🧪🔬"), "🧪🔬");
+        assert_eq!(bookmark_title("Ąžuolas"), "Ąžuolas");
+        assert_eq!(bookmark_title(""), "Untitled section");
+        assert_eq!(bookmark_title(" \t "), "Untitled section");
+
+        let book = Book {
+            id: 7,
+            title: String::from("..."),
+            ..Default::default()
+        };
+        assert_eq!(book.dir_name("{title}"), "_");
+        assert_eq!(book.dir_name(DEFAULT_NAME_TEMPLATE), "... ;;; 7");
+        assert_eq!(file_name_safe(" ?*. "), "__");
+    }
+
+    #[test]
+    fn names_of_a_batch_are_unique_and_parse_back() {
+        let titles = [
+            "Fizika 7",
+            "Fizika 7",
+            "Matematika: 5/6 klasei",
+            "Kas ;;; tai",
+            "",
+            "Biologija...",
+        ];
+        let books: Vec<Book> = titles
+            .iter()
+            .enumerate()
+            .map(|(n, title)| Book {
+                id: 1000 + n as u64,
+                title: String::from(*title),
+                ..Default::default()
+            })
+            .collect();
+        let root = test_dir("names");
+        let mut dir_names = std::collections::HashSet::new();
+        let mut pdf_names = std::collections::HashSet::new();
+        for book in &books {
+            let dir_name = book.dir_name(DEFAULT_NAME_TEMPLATE);
+            assert!(dir_names.insert(dir_name.clone()), "{} twice", dir_name);
+            let pdf_name = book.pdf_file_name(PdfName::Title, DEFAULT_NAME_TEMPLATE);
+            assert!(pdf_names.insert(pdf_name.clone()), "{} twice", pdf_name);
+            assert_eq!(pdf_name, format!("{}.pdf", dir_name));
+            fs::create_dir(root.join(dir_name)).unwrap();
+        }
+
+        // without a metadata.json the id is read back from the name alone
+        let ids: Vec<u64> = crate::local_books(&root)
+            .unwrap()
+            .iter()
+            .map(|book| book.id)
+            .collect();
+        assert_eq!(ids, books.iter().map(|book| book.id).collect::<Vec<_>>());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,237 @@
+use crate::models::Book;
+use crate::EdukaError;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Database in the output directory, next to the book directories.
+const STATE_FILE: &str = ".eduka.sqlite3";
+
+/// Version of `SCHEMA`, kept in the database's `user_version`.
+const SCHEMA_VERSION: i32 = 2;
+
+/// Books are kept by their directory, as every part of a collection has the
+/// teaching tool's id.
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS books (
+    dir TEXT PRIMARY KEY,
+    id INTEGER NOT NULL,
+    title TEXT NOT NULL,
+    page_count INTEGER NOT NULL,
+    downloaded_at INTEGER,
+    prepared_at INTEGER
+);
+CREATE TABLE IF NOT EXISTS pages (
+    dir TEXT NOT NULL,
+    page INTEGER NOT NULL,
+    hash TEXT NOT NULL,
+    PRIMARY KEY (dir, page)
+);
+CREATE TABLE IF NOT EXISTS outputs (
+    dir TEXT NOT NULL,
+    file TEXT NOT NULL,
+    hash TEXT NOT NULL,
+    PRIMARY KEY (dir, file)
+);
+CREATE TABLE IF NOT EXISTS explored (
+    id INTEGER PRIMARY KEY,
+    found INTEGER NOT NULL,
+    wanted INTEGER NOT NULL,
+    pending INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS settings (
+    key TEXT PRIMARY KEY,
+    value INTEGER NOT NULL
+);
+";
+
+/// How long a write waits for another instance writing at the same time.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Teaching tool id exploration went through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Explored {
+    /// There is no teaching tool with the id
+    Missing,
+    /// Asked whether to download it, `pending` until it is downloaded
+    Answered { wanted: bool, pending: bool },
+}
+
+/// What was downloaded, prepared and explored, kept between runs so books are
+/// skipped and resumed by what was recorded rather than by looking at every
+/// page on disk again.
+pub struct StateDb {
+    connection: Mutex<Connection>,
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+impl StateDb {
+    /// The database of `output_dir`, made on first use.
+    pub fn open(output_dir: &Path) -> Result<StateDb, EdukaError> {
+        fs::create_dir_all(output_dir)?;
+        let connection = Connection::open(output_dir.join(STATE_FILE))?;
+        connection.busy_timeout(BUSY_TIMEOUT)?;
+        let version: i32 = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        if version < SCHEMA_VERSION {
+            // kept books by id, they are recorded again from their manifests
+            connection.execute_batch(
+                "DROP TABLE IF EXISTS books;
+                 DROP TABLE IF EXISTS pages;
+                 DROP TABLE IF EXISTS outputs;",
+            )?;
+            connection.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+        }
+        connection.execute_batch(SCHEMA)?;
+        Ok(StateDb {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    /// Start recording the download of `book` into the directory `dir`. A book
+    /// whose page count changed since is downloaded anew.
+    pub(crate) fn start_book(&self, book: &Book, dir: &str) -> Result<(), EdukaError> {
+        let connection = self.connection.lock().unwrap();
+        let page_count: Option<i64> = connection
+            .query_row(
+                "SELECT page_count FROM books WHERE dir = ?1",
+                params![dir],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if page_count.is_some_and(|count| count != book.page_urls.len() as i64) {
+            connection.execute("DELETE FROM pages WHERE dir = ?1", params![dir])?;
+        }
+        connection.execute(
+            "INSERT INTO books (dir, id, title, page_count) VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (dir) DO UPDATE SET id = ?2, title = ?3, page_count = ?4,
+             downloaded_at = NULL",
+            params![dir, book.id, &book.title, book.page_urls.len()],
+        )?;
+        Ok(())
+    }
+
+    pub(crate) fn record_page(&self, dir: &str, page: usize, hash: &str) -> Result<(), EdukaError> {
+        self.connection.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO pages (dir, page, hash) VALUES (?1, ?2, ?3)",
+            params![dir, page, hash],
+        )?;
+        Ok(())
+    }
+
+    pub(crate) fn finish_book(&self, dir: &str) -> Result<(), EdukaError> {
+        self.connection.lock().unwrap().execute(
+            "UPDATE books SET downloaded_at = ?2 WHERE dir = ?1",
+            params![dir, now()],
+        )?;
+        Ok(())
+    }
+
+    /// Have the book in `dir` downloaded again, checking its pages one by one.
+    pub(crate) fn reopen_book(&self, dir: &str) -> Result<(), EdukaError> {
+        self.connection.lock().unwrap().execute(
+            "UPDATE books SET downloaded_at = NULL WHERE dir = ?1",
+            params![dir],
+        )?;
+        Ok(())
+    }
+
+    /// Whether `book` was downloaded completely into `dir`, with as many pages as
+    /// it has now.
+    pub(crate) fn is_downloaded(&self, book: &Book, dir: &str) -> Result<bool, EdukaError> {
+        let downloaded = self
+            .connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT 1 FROM books WHERE dir = ?1 AND id = ?2 AND page_count = ?3
+                 AND downloaded_at IS NOT NULL",
+                params![dir, book.id, book.page_urls.len()],
+                |_| Ok(()),
+            )
+            .optional()?;
+        Ok(downloaded.is_some())
+    }
+
+    /// Record that `file` was assembled of the pages of the book in `dir`, with
+    /// its SHA-256.
+    pub(crate) fn record_output(
+        &self,
+        dir: &str,
+        file: &str,
+        hash: &str,
+    ) -> Result<(), EdukaError> {
+        let connection = self.connection.lock().unwrap();
+        connection.execute(
+            "INSERT OR REPLACE INTO outputs (dir, file, hash) VALUES (?1, ?2, ?3)",
+            params![dir, file, hash],
+        )?;
+        connection.execute(
+            "UPDATE books SET prepared_at = ?2 WHERE dir = ?1",
+            params![dir, now()],
+        )?;
+        Ok(())
+    }
+
+    /// Id exploration goes on from, and every id it went through.
+    pub fn exploration(&self) -> Result<(u64, BTreeMap<u64, Explored>), EdukaError> {
+        let connection = self.connection.lock().unwrap();
+        let next_id: Option<u64> = connection
+            .query_row(
+                "SELECT value FROM settings WHERE key = 'next_id'",
+                [],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let mut statement =
+            connection.prepare("SELECT id, found, wanted, pending FROM explored")?;
+        let explored = statement
+            .query_map([], |row| {
+                let explored = match row.get(1)? {
+                    false => Explored::Missing,
+                    true => Explored::Answered {
+                        wanted: row.get(2)?,
+                        pending: row.get(3)?,
+                    },
+                };
+                Ok((row.get(0)?, explored))
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok((next_id.unwrap_or(0), explored))
+    }
+
+    /// Replace what exploration went through with `explored`.
+    pub fn save_exploration(
+        &self,
+        next_id: u64,
+        explored: &BTreeMap<u64, Explored>,
+    ) -> Result<(), EdukaError> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        transaction.execute(
+            "INSERT OR REPLACE INTO settings (key, value) VALUES ('next_id', ?1)",
+            params![next_id],
+        )?;
+        transaction.execute("DELETE FROM explored", [])?;
+        for (id, explored) in explored {
+            let (found, wanted, pending) = match *explored {
+                Explored::Missing => (false, false, false),
+                Explored::Answered { wanted, pending } => (true, wanted, pending),
+            };
+            transaction.execute(
+                "INSERT INTO explored (id, found, wanted, pending) VALUES (?1, ?2, ?3, ?4)",
+                params![id, found, wanted, pending],
+            )?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+}
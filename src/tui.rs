@@ -0,0 +1,201 @@
+//! Terminal UI for exploration: teaching tools are listed as their metadata
+//! streams in and can be ticked off before the batch download starts.
+
+use eduka::{EdukaClient, EdukaError, TeachingTool};
+use ratatui::backend::CrosstermBackend;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::crossterm::terminal::{
+    disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
+};
+use ratatui::crossterm::ExecutableCommand;
+use ratatui::layout::{Constraint, Direction, Layout};
+use ratatui::style::{Modifier, Style};
+use ratatui::widgets::{Block, Borders, List, ListItem, ListState, Paragraph};
+use ratatui::Terminal;
+use std::collections::HashMap;
+use std::io;
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
+
+/// Result of probing a single teaching tool id.
+struct Probe {
+    id: u64,
+    teaching_tool: Option<TeachingTool>,
+}
+
+/// Leaves raw mode and the alternate screen however the browser exits.
+struct TerminalGuard;
+
+impl TerminalGuard {
+    fn enter() -> io::Result<TerminalGuard> {
+        enable_raw_mode()?;
+        io::stdout().execute(EnterAlternateScreen)?;
+        Ok(TerminalGuard)
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        disable_raw_mode().ok();
+        io::stdout().execute(LeaveAlternateScreen).ok();
+    }
+}
+
+/// Probe ids from `start` upwards in the background and let the user pick which
+/// of the found teaching tools to download. Cancelling selects nothing.
+pub async fn select_teaching_tools(
+    client: Arc<EdukaClient>,
+    start: u64,
+) -> Result<Vec<TeachingTool>, EdukaError> {
+    let (sender, receiver) = mpsc::channel();
+    let prober = tokio::spawn(async move {
+        // eduka only tells the authors and publisher of a package, so they are
+        // known for the teaching tools of the account's packages alone
+        let mut packages = HashMap::new();
+        for package in client.my_packages().await.unwrap_or_default() {
+            for teaching_tool in package.teaching_tools {
+                packages.insert(
+                    teaching_tool.id,
+                    (package.authors.clone(), package.publishing_house.clone()),
+                );
+            }
+        }
+        let mut id = start;
+        loop {
+            let mut teaching_tool = TeachingTool::new(id);
+            if let Some((authors, publishing_house)) = packages.get(&id) {
+                teaching_tool.authors = authors.clone();
+                teaching_tool.publishing_house = publishing_house.clone();
+            }
+            let found = client
+                .fill_teaching_tool_metadata(&mut teaching_tool)
+                .await
+                .is_ok();
+            let probe = Probe {
+                id,
+                teaching_tool: found.then_some(teaching_tool),
+            };
+            // the browser was closed
+            if sender.send(probe).is_err() {
+                break;
+            }
+            id += 1;
+        }
+    });
+    let selected = tokio::task::spawn_blocking(move || browse(receiver))
+        .await
+        .unwrap();
+    prober.abort();
+    selected
+}
+
+fn browse(receiver: mpsc::Receiver<Probe>) -> Result<Vec<TeachingTool>, EdukaError> {
+    let _guard = TerminalGuard::enter()?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(io::stdout()))?;
+    let mut teaching_tools: Vec<(TeachingTool, bool)> = vec![];
+    let mut list_state = ListState::default();
+    let mut last_probed = None;
+
+    loop {
+        while let Ok(probe) = receiver.try_recv() {
+            last_probed = Some(probe.id);
+            if let Some(teaching_tool) = probe.teaching_tool {
+                teaching_tools.push((teaching_tool, false));
+                if list_state.selected().is_none() {
+                    list_state.select(Some(0));
+                }
+            }
+        }
+
+        terminal.draw(|frame| {
+            let chunks = Layout::default()
+                .direction(Direction::Vertical)
+                .constraints([
+                    Constraint::Length(1),
+                    Constraint::Min(0),
+                    Constraint::Length(1),
+                ])
+                .split(frame.area());
+            let status = match last_probed {
+                Some(id) => format!(
+                    "probed up to teaching tool {}, found {}",
+                    id,
+                    teaching_tools.len()
+                ),
+                None => String::from("probing teaching tools..."),
+            };
+            frame.render_widget(Paragraph::new(status), chunks[0]);
+            let items: Vec<ListItem> = teaching_tools
+                .iter()
+                .map(|(teaching_tool, checked)| {
+                    let book = &teaching_tool.book;
+                    let publisher = match teaching_tool.publishing_house.is_empty() {
+                        true => "unknown publisher",
+                        false => teaching_tool.publishing_house.as_str(),
+                    };
+                    ListItem::new(format!(
+                        "[{}] {} ({}, {} pages){}",
+                        if *checked { "x" } else { " " },
+                        &book.title,
+                        publisher,
+                        book.page_urls.len(),
+                        if book.native_downloadable {
+                            " [NATIVE DOWNLOADABLE]"
+                        } else {
+                            ""
+                        }
+                    ))
+                })
+                .collect();
+            let list = List::new(items)
+                .block(
+                    Block::default()
+                        .borders(Borders::ALL)
+                        .title("Teaching tools"),
+                )
+                .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+            frame.render_stateful_widget(list, chunks[1], &mut list_state);
+            frame.render_widget(
+                Paragraph::new("up/down move, space select, enter download, q cancel"),
+                chunks[2],
+            );
+        })?;
+
+        if !event::poll(Duration::from_millis(100))? {
+            continue;
+        }
+        let Event::Key(key) = event::read()? else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+        let current = list_state.selected();
+        match key.code {
+            KeyCode::Up | KeyCode::Char('k') => {
+                if let Some(i) = current {
+                    list_state.select(Some(i.saturating_sub(1)));
+                }
+            }
+            KeyCode::Down | KeyCode::Char('j') => {
+                if let Some(i) = current {
+                    list_state.select(Some((i + 1).min(teaching_tools.len() - 1)));
+                }
+            }
+            KeyCode::Char(' ') => {
+                if let Some(i) = current {
+                    teaching_tools[i].1 = !teaching_tools[i].1;
+                }
+            }
+            KeyCode::Enter => {
+                return Ok(teaching_tools
+                    .into_iter()
+                    .filter(|(_, checked)| *checked)
+                    .map(|(teaching_tool, _)| teaching_tool)
+                    .collect());
+            }
+            KeyCode::Char('q') | KeyCode::Esc => return Ok(vec![]),
+            _ => {}
+        }
+    }
+}
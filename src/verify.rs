@@ -0,0 +1,143 @@
+use crate::download::{checksum_mismatches, is_complete_page, NATIVE_PDF};
+use crate::prepare::{images_deleted, read_metadata};
+use crate::{EdukaClient, EdukaError, LocalBook, TeachingTool};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// What is wrong with a downloaded book directory, against what eduka has for
+/// the book now.
+#[derive(Debug)]
+pub struct Verification {
+    pub book: LocalBook,
+    /// The book as eduka has it now, `None` for directories eduka has no single
+    /// book for, like collections merged with `--merge-parts`
+    pub teaching_tool: Option<TeachingTool>,
+    /// Pages eduka has that aren't in the directory
+    pub missing_pages: Vec<usize>,
+    /// Zero byte pages
+    pub empty_pages: Vec<usize>,
+    /// Pages that are cut off or not an image
+    pub corrupted_pages: Vec<usize>,
+    /// PDFs that can't be read back
+    pub corrupted_pdfs: Vec<PathBuf>,
+    /// Pages that changed since they were downloaded, when checksums are checked
+    pub changed_pages: Vec<usize>,
+    /// PDFs and other assembled files that changed since they were written, when
+    /// checksums are checked
+    pub changed_outputs: Vec<PathBuf>,
+}
+
+impl Verification {
+    pub fn is_ok(&self) -> bool {
+        self.missing_pages.is_empty()
+            && self.empty_pages.is_empty()
+            && self.corrupted_pages.is_empty()
+            && self.corrupted_pdfs.is_empty()
+            && self.changed_pages.is_empty()
+            && self.changed_outputs.is_empty()
+    }
+}
+
+impl EdukaClient {
+    /// Check a downloaded book directory page by page, with the page count asked
+    /// from eduka again rather than taken from the directory. With `checksums`
+    /// every page and assembled file is also compared with the checksum recorded
+    /// in the manifest, which catches bit-rot that leaves a file readable.
+    pub async fn verify(
+        &self,
+        book: &LocalBook,
+        checksums: bool,
+    ) -> Result<Verification, EdukaError> {
+        let title = match read_metadata(&book.path) {
+            Ok(teaching_tool) => teaching_tool.book.title,
+            Err(_) => book.title.clone(),
+        };
+        let mut fresh = TeachingTool::new(book.id);
+        self.fill_teaching_tool_metadata(&mut fresh).await?;
+        let mut parts = fresh.parts();
+        let teaching_tool = match parts.iter().position(|part| part.book.title == title) {
+            Some(position) => Some(parts.swap_remove(position)),
+            None if parts.len() == 1 => parts.pop(),
+            None => None,
+        };
+        let mut verification = Verification {
+            book: book.clone(),
+            teaching_tool,
+            missing_pages: vec![],
+            empty_pages: vec![],
+            corrupted_pages: vec![],
+            corrupted_pdfs: vec![],
+            changed_pages: vec![],
+            changed_outputs: vec![],
+        };
+        if checksums {
+            (verification.changed_pages, verification.changed_outputs) =
+                checksum_mismatches(&book.path);
+        }
+        for entry in fs::read_dir(&book.path)? {
+            let path = entry?.path();
+            if path.extension().map(|e| e == "pdf").unwrap_or(false)
+                && lopdf::Document::load(&path).is_err()
+            {
+                verification.corrupted_pdfs.push(path);
+            }
+        }
+        let page_count = match &verification.teaching_tool {
+            // the publisher's PDF takes the place of the pages
+            Some(_) if book.path.join(NATIVE_PDF).is_file() => 0,
+            Some(_) if images_deleted(&book.path) => 0,
+            Some(teaching_tool) => teaching_tool.book.page_urls.len(),
+            None => 0,
+        };
+        for i in 0..page_count {
+            let path = book.path.join(format!("{}.png", i));
+            match fs::metadata(&path) {
+                Err(_) => verification.missing_pages.push(i),
+                Ok(metadata) if metadata.len() == 0 => verification.empty_pages.push(i),
+                Ok(_) if !is_complete_page(&path) => verification.corrupted_pages.push(i),
+                Ok(_) => {}
+            }
+        }
+        Ok(verification)
+    }
+
+    /// Remove the broken pages and PDFs `verification` found and download what
+    /// is missing then, keeping every page that is fine.
+    pub async fn repair(self: &Arc<Self>, verification: &Verification) -> Result<(), EdukaError> {
+        let Some(teaching_tool) = &verification.teaching_tool else {
+            return Err(EdukaError::NotRepairable(
+                "eduka has no book it was downloaded from",
+            ));
+        };
+        let book_dir = self
+            .output_dir
+            .join(teaching_tool.book.dir_name(&self.name_template));
+        if book_dir != verification.book.path {
+            return Err(EdukaError::NotRepairable(
+                "it is named after another --name-template",
+            ));
+        }
+        let broken_pages = verification
+            .empty_pages
+            .iter()
+            .chain(&verification.corrupted_pages)
+            .chain(&verification.changed_pages)
+            .collect::<BTreeSet<_>>();
+        for &i in broken_pages {
+            fs::remove_file(verification.book.path.join(format!("{}.png", i)))?;
+        }
+        let broken_outputs = verification
+            .corrupted_pdfs
+            .iter()
+            .chain(&verification.changed_outputs)
+            .collect::<BTreeSet<_>>();
+        for output in broken_outputs {
+            fs::remove_file(output)?;
+        }
+        let dir_name = teaching_tool.book.dir_name(&self.name_template);
+        self.record_state(|state| state.reopen_book(&dir_name));
+        self.download_part(teaching_tool).await
+    }
+}